@@ -0,0 +1,236 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use druid::im::Vector;
+use epub::doc::EpubDoc;
+use crate::css::{font_faces, Stylesheet};
+use crate::data::{ImageOfPage, Page, Section};
+use crate::loader::{image_sources, resolve_href, LoadError};
+use crate::markup::{blocks_text, parse_blocks, Block};
+
+/*
+The number of decoded pages kept in memory: the pages displayed and the ones around them.
+*/
+const PAGE_CACHE_SIZE: usize = 12;
+
+/*
+A page of the Ebook ready to be displayed: its HTML parsed into blocks with the stylesheets of its
+file, and its images decoded and indexed by their src.
+*/
+pub struct DecodedPage {
+    pub blocks: Vec<Block>,
+    pub images: HashMap<String, ImageOfPage>,
+}
+
+/*
+The Ebook opened by the user. The archive is kept open, so that the pages are parsed and their
+images decoded only when they are about to be displayed; the decoded pages are kept in a bounded
+cache, dropping the least recently used one when it is full. The key identifies the book in the
+saved data, whatever the name of its file. The pages around the one displayed are decoded in advance
+by a single worker thread, started when it is first needed and stopped with the Book. The fonts are
+the ones embedded in the Ebook, with their family name and the path of their file, which is only
+read when the font is used. The plain text of the sections is kept once it has been
+extracted for a search, since the sections don't change while the Ebook is open.
+*/
+pub struct Book {
+    pub key: String,
+    pub fonts: Vec<(String, String)>,
+    archive: Mutex<EpubDoc<File>>,
+    pages: Mutex<VecDeque<((String, usize, usize), Arc<DecodedPage>)>>,
+    prefetcher: Mutex<Option<Sender<Vec<(Page, Arc<Stylesheet>)>>>>,
+    texts: Mutex<HashMap<String, Arc<String>>>,
+}
+
+impl Book {
+    pub fn new(mut archive: EpubDoc<File>, key: String) -> Self {
+        Self {
+            key,
+            fonts: embedded_fonts(&mut archive),
+            archive: Mutex::new(archive),
+            pages: Mutex::new(VecDeque::new()),
+            prefetcher: Mutex::new(None),
+            texts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /*
+    This function gives access to the archive of the Ebook, locking it until the guard is dropped.
+    */
+    pub fn archive(&self) -> MutexGuard<'_, EpubDoc<File>> {
+        self.archive.lock().unwrap()
+    }
+
+    /*
+    This function returns the decoded version of a page of the Ebook, taking it from the cache or
+    decoding it with the stylesheet of the file of the page.
+    */
+    pub fn page(&self, page: &Page, stylesheet: &Stylesheet) -> Arc<DecodedPage> {
+        let key = (page.source.clone(), page.start, page.text.len());
+
+        if let Some(decoded) = self.cached(&key) {
+            return decoded;
+        }
+
+        let decoded = Arc::new(self.decode(page, stylesheet));
+        let mut pages = self.pages.lock().unwrap();
+        if !pages.iter().any(|(k, _)| *k == key) {
+            pages.push_front((key, decoded.clone()));
+            pages.truncate(PAGE_CACHE_SIZE);
+        }
+
+        decoded
+    }
+
+    /*
+    This function asks the worker thread to decode the given pages, so that they are already in the
+    cache when the user turns the page. A new request replaces the ones the worker hasn't started.
+    */
+    pub fn prefetch(book: &Arc<Book>, pages: Vec<(Page, Arc<Stylesheet>)>) {
+        let mut prefetcher = book.prefetcher.lock().unwrap();
+        let sender = prefetcher.get_or_insert_with(|| {
+            let (sender, receiver) = channel();
+            let book = Arc::downgrade(book);
+            std::thread::spawn(move || prefetch_worker(book, receiver));
+            sender
+        });
+
+        sender.send(pages).ok();
+    }
+
+    /*
+    This function reads the file of an embedded font, given its family name. None is returned if the
+    Ebook doesn't embed the font or if its file is missing.
+    */
+    pub fn font_bytes(&self, family: &str) -> Option<Vec<u8>> {
+        let (_, path) = self.fonts.iter().find(|(other, _)| other == family)?;
+        let bytes = self.archive().get_resource_by_path(path);
+
+        match bytes {
+            Ok(bytes) => Some(bytes),
+            Err(_) => {
+                println!("{}", LoadError::MissingEntry(path.clone()));
+                None
+            }
+        }
+    }
+
+    /*
+    This function returns the text of a section as it is displayed, extracting it the first time
+    outside the lock of the cache.
+    */
+    pub fn section_text(&self, section: &Section) -> Arc<String> {
+        if let Some(text) = self.texts.lock().unwrap().get(&section.source) {
+            return text.clone();
+        }
+
+        let text = Arc::new(blocks_text(&parse_blocks(&section.content, &section.stylesheet)));
+        self.texts.lock().unwrap().insert(section.source.clone(), text.clone());
+
+        text
+    }
+
+    /*
+    This function looks for a page in the cache, moving it to the front as the most recently used.
+    */
+    fn cached(&self, key: &(String, usize, usize)) -> Option<Arc<DecodedPage>> {
+        let mut pages = self.pages.lock().unwrap();
+        let pos = pages.iter().position(|(k, _)| k == key)?;
+        let entry = pages.remove(pos)?;
+        let decoded = entry.1.clone();
+        pages.push_front(entry);
+
+        Some(decoded)
+    }
+
+    fn is_cached(&self, page: &Page) -> bool {
+        let key = (page.source.clone(), page.start, page.text.len());
+        self.pages.lock().unwrap().iter().any(|(k, _)| *k == key)
+    }
+
+    fn decode(&self, page: &Page, stylesheet: &Stylesheet) -> DecodedPage {
+        let blocks = parse_blocks(&(page.context.clone() + page.text.as_str()), stylesheet);
+        let mut images = HashMap::new();
+
+        for src in image_sources(&page.text) {
+            if images.contains_key(&src) {
+                continue;
+            }
+
+            let image_path = resolve_href(&page.source, &src);
+            let bytes = self.archive().get_resource_by_path(&image_path);
+            match bytes {
+                Ok(bytes) => match decode_image(&bytes) {
+                    Some(image) => {
+                        images.insert(src, image);
+                    }
+                    None => println!("{}", LoadError::UnsupportedImage(image_path)),
+                },
+                Err(_) => println!("{}", LoadError::MissingEntry(image_path)),
+            }
+        }
+
+        DecodedPage { blocks, images }
+    }
+}
+
+/*
+This function is the worker decoding the pages requested by prefetch, skipping the ones already in
+the cache. Only the last request is followed, and the worker stops when the Book is dropped.
+*/
+fn prefetch_worker(book: Weak<Book>, receiver: Receiver<Vec<(Page, Arc<Stylesheet>)>>) {
+    while let Ok(mut pages) = receiver.recv() {
+        while let Ok(newer) = receiver.try_recv() {
+            pages = newer;
+        }
+        let book = match book.upgrade() {
+            Some(book) => book,
+            None => return,
+        };
+
+        for (page, stylesheet) in pages {
+            if !book.is_cached(&page) {
+                book.page(&page, &stylesheet);
+            }
+        }
+    }
+}
+
+/*
+This function lists the fonts embedded in the Ebook, declared by the @font-face rules of its
+stylesheets, with the path of their file in the archive. Only the stylesheets are read here.
+*/
+fn embedded_fonts(archive: &mut EpubDoc<File>) -> Vec<(String, String)> {
+    let stylesheets: Vec<String> = archive.resources.values()
+        .filter(|(_, mime)| mime == "text/css")
+        .map(|(path, _)| path.to_string_lossy().to_string())
+        .collect();
+    let mut fonts: Vec<(String, String)> = Vec::new();
+
+    for stylesheet in stylesheets {
+        let css = match archive.get_resource_str_by_path(&stylesheet) {
+            Ok(css) => css,
+            Err(_) => continue,
+        };
+        for (family, url) in font_faces(&css) {
+            if fonts.iter().any(|(other, _)| *other == family) {
+                continue;
+            }
+            fonts.push((family, resolve_href(&stylesheet, &url)));
+        }
+    }
+
+    fonts
+}
+
+/*
+This function decodes the bytes of an image stored in the archive, guessing its format from the
+content, and converts it into RGBA pixels.
+*/
+fn decode_image(bytes: &[u8]) -> Option<ImageOfPage> {
+    let decoded = image::load_from_memory(bytes).ok()?;
+    let rgba = decoded.to_rgba8();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+
+    Some(ImageOfPage::from(Vector::from(rgba.into_raw()), width, height))
+}
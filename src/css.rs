@@ -0,0 +1,453 @@
+use crate::markup::Element;
+
+/*
+This module reads the stylesheets of the Ebook. Only a subset of CSS is supported: selectors made of
+type, class and id selectors joined by descendant or child combinators, and the properties text-align,
+font-weight, font-style, font-variant, margin, text-indent, display and page-break-before. Rules
+with other selectors and unknown properties are ignored.
+*/
+
+/*
+The default style of the elements, applied before the stylesheets of the Ebook.
+*/
+const DEFAULT_STYLESHEET: &str = "
+    b, strong, th, h1, h2, h3, h4, h5, h6 { font-weight: bold }
+    i, em, cite, dfn, var, address { font-style: italic }
+    center { text-align: center }
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextAlign {
+    Left,
+    Right,
+    Center,
+    Justify,
+}
+
+/*
+The style of an element once the stylesheet has been applied. Lengths are expressed in em, so that
+they follow the font size chosen by the user; None means that the property isn't set and the
+default of the element is used.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Style {
+    pub text_align: Option<TextAlign>,
+    pub bold: Option<bool>,
+    pub italic: Option<bool>,
+    pub small_caps: Option<bool>,
+    pub margin_top: Option<f64>,
+    pub margin_right: Option<f64>,
+    pub margin_bottom: Option<f64>,
+    pub margin_left: Option<f64>,
+    pub text_indent: Option<f64>,
+    pub hidden: bool,
+    pub page_break_before: bool,
+}
+
+impl Style {
+    /*
+    This function returns the style an element inherits from its parent: only the properties that
+    CSS defines as inherited are kept.
+    */
+    pub fn inherit(parent: &Style) -> Self {
+        Self {
+            text_align: parent.text_align,
+            bold: parent.bold,
+            italic: parent.italic,
+            small_caps: parent.small_caps,
+            text_indent: parent.text_indent,
+            ..Style::default()
+        }
+    }
+
+    fn apply(&mut self, property: &str, value: &str) {
+        let value = value.trim().to_ascii_lowercase();
+        let value = value.trim_end_matches("!important").trim();
+
+        match property {
+            "text-align" => {
+                self.text_align = match value {
+                    "left" | "start" => Some(TextAlign::Left),
+                    "right" | "end" => Some(TextAlign::Right),
+                    "center" => Some(TextAlign::Center),
+                    "justify" => Some(TextAlign::Justify),
+                    _ => self.text_align,
+                }
+            }
+            "font-weight" => {
+                self.bold = match value {
+                    "bold" | "bolder" => Some(true),
+                    "normal" | "lighter" => Some(false),
+                    _ => value.parse::<u32>().ok().map(|weight| weight >= 600).or(self.bold),
+                }
+            }
+            "font-style" => self.italic = Some(value == "italic" || value == "oblique"),
+            "font-variant" | "font-variant-caps" => self.small_caps = Some(value.contains("small-caps")),
+            "margin" => {
+                let values: Vec<Option<f64>> = value.split_whitespace().map(parse_length).collect();
+                let (top, right, bottom, left) = match values.len() {
+                    1 => (values[0], values[0], values[0], values[0]),
+                    2 => (values[0], values[1], values[0], values[1]),
+                    3 => (values[0], values[1], values[2], values[1]),
+                    4 => (values[0], values[1], values[2], values[3]),
+                    _ => return,
+                };
+                self.margin_top = top;
+                self.margin_right = right;
+                self.margin_bottom = bottom;
+                self.margin_left = left;
+            }
+            "margin-top" => self.margin_top = parse_length(value),
+            "margin-right" => self.margin_right = parse_length(value),
+            "margin-bottom" => self.margin_bottom = parse_length(value),
+            "margin-left" => self.margin_left = parse_length(value),
+            "text-indent" => self.text_indent = parse_length(value),
+            "display" => self.hidden = value == "none",
+            "page-break-before" | "break-before" => {
+                self.page_break_before = ["always", "page", "left", "right", "recto", "verso"].contains(&value)
+            }
+            _ => {}
+        }
+    }
+}
+
+/*
+This function converts a CSS length into em. Pixels and points are converted with the usual 16px
+and 12pt font, percentages are relative to a column about 40em wide. None is returned for auto and
+for values that can't be parsed.
+*/
+fn parse_length(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let units = [("rem", 1.), ("em", 1.), ("ex", 0.5), ("px", 1. / 16.), ("pt", 1. / 12.), ("%", 0.4)];
+
+    for (unit, factor) in units {
+        if let Some(number) = value.strip_suffix(unit) {
+            return number.trim().parse::<f64>().ok().map(|n| n * factor);
+        }
+    }
+
+    value.parse::<f64>().ok().filter(|n| *n == 0.)
+}
+
+/*
+The part of a selector matching a single element: an optional type, the ids and the classes.
+*/
+#[derive(Clone, Debug)]
+struct Compound {
+    name: Option<String>,
+    ids: Vec<String>,
+    classes: Vec<String>,
+}
+
+impl Compound {
+    fn matches(&self, element: &ElementInfo) -> bool {
+        self.name.as_ref().map(|name| *name == element.name).unwrap_or(true)
+            && self.ids.iter().all(|id| *id == element.id)
+            && self.classes.iter().all(|class| element.classes.contains(class))
+    }
+}
+
+/*
+A selector is a list of compounds from the outermost to the subject of the rule; the boolean says
+if the compound is a direct child of the previous one.
+*/
+#[derive(Clone, Debug)]
+struct Rule {
+    selector: Vec<(Compound, bool)>,
+    specificity: (usize, usize, usize),
+    declarations: Vec<(String, String)>,
+}
+
+/*
+The information about an element needed to match the selectors.
+*/
+#[derive(Clone, Debug)]
+pub struct ElementInfo {
+    pub name: String,
+    pub id: String,
+    pub classes: Vec<String>,
+}
+
+impl ElementInfo {
+    pub fn of(element: &Element) -> Self {
+        Self {
+            name: element.name.clone(),
+            id: element.attribute("id").unwrap_or("").to_string(),
+            classes: element.attribute("class").unwrap_or("").split_whitespace().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+/*
+The rules of the stylesheets used by a file of the Ebook, in the order they are written.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct Stylesheet {
+    rules: Vec<Rule>,
+}
+
+impl Stylesheet {
+    /*
+    This function creates the stylesheet of a file of the Ebook from the text of its stylesheets,
+    preceded by the default style of the elements.
+    */
+    pub fn new(css: &str) -> Self {
+        Self::parse(&format!("{}\n{}", DEFAULT_STYLESHEET, css))
+    }
+
+    /*
+    This function parses the text of one or more stylesheets. At-rules (as @media and @font-face)
+    are skipped with their content.
+    */
+    fn parse(css: &str) -> Self {
+        let css = remove_comments(css);
+        let mut rules = Vec::new();
+        let mut rest = css.as_str();
+
+        while let Some(open) = rest.find(|c: char| c == '{' || c == ';') {
+            let prelude = rest[..open].trim();
+
+            if prelude.starts_with('@') {
+                rest = if rest[open..].starts_with('{') { skip_block(&rest[open..]) } else { &rest[open + 1..] };
+                continue;
+            }
+            if rest[open..].starts_with(';') {
+                rest = &rest[open + 1..];
+                continue;
+            }
+
+            let close = rest[open..].find('}').map(|close| open + close).unwrap_or(rest.len());
+            let declarations = parse_declarations(&rest[open + 1..close]);
+
+            for selector in prelude.split(',') {
+                if let Some((selector, specificity)) = parse_selector(selector) {
+                    rules.push(Rule {
+                        selector,
+                        specificity,
+                        declarations: declarations.clone(),
+                    });
+                }
+            }
+            rest = &rest[(close + 1).min(rest.len())..];
+        }
+
+        Self { rules }
+    }
+
+    /*
+    This function computes the style of an element, given its ancestors (from the outermost) and the
+    style of its parent. The matching rules are applied in order of specificity, then the style
+    attribute of the element is applied.
+    */
+    pub fn compute(&self, element: &Element, ancestors: &[ElementInfo], parent: &Style) -> Style {
+        let info = ElementInfo::of(element);
+        let mut style = Style::inherit(parent);
+        let mut matched: Vec<(usize, &Rule)> = self.rules.iter()
+            .enumerate()
+            .filter(|(_, rule)| matches(&rule.selector, &info, ancestors))
+            .collect();
+
+        matched.sort_by_key(|(order, rule)| (rule.specificity, *order));
+
+        for (_, rule) in matched {
+            for (property, value) in rule.declarations.iter() {
+                style.apply(property, value);
+            }
+        }
+
+        if let Some(inline) = element.attribute("style") {
+            for (property, value) in parse_declarations(inline) {
+                style.apply(&property, &value);
+            }
+        }
+
+        style
+    }
+}
+
+/*
+This function checks if a selector matches an element: the last compound has to match the element,
+the others its ancestors in the same order.
+*/
+fn matches(selector: &Vec<(Compound, bool)>, element: &ElementInfo, ancestors: &[ElementInfo]) -> bool {
+    let (subject, child) = match selector.last() {
+        Some(last) => last,
+        None => return false,
+    };
+    if !subject.matches(element) {
+        return false;
+    }
+
+    let mut rest = &selector[..selector.len() - 1];
+    let mut ancestors = ancestors;
+    let mut direct = *child;
+
+    while let Some((compound, child)) = rest.last() {
+        let parent = match ancestors.last() {
+            Some(parent) => parent,
+            None => return false,
+        };
+        ancestors = &ancestors[..ancestors.len() - 1];
+
+        if compound.matches(parent) {
+            rest = &rest[..rest.len() - 1];
+            direct = *child;
+        } else if direct {
+            return false;
+        }
+    }
+
+    true
+}
+
+/*
+This function parses a selector returning its compounds and its specificity. None is returned for
+the selectors that aren't supported, as pseudo-classes, attribute selectors and sibling combinators.
+*/
+fn parse_selector(selector: &str) -> Option<(Vec<(Compound, bool)>, (usize, usize, usize))> {
+    let selector = selector.trim();
+    if selector.len() == 0 || selector.contains(|c: char| c == ':' || c == '[' || c == '+' || c == '~') {
+        return None;
+    }
+
+    let mut compounds = Vec::new();
+    let mut specificity = (0, 0, 0);
+    let mut child = false;
+
+    for token in selector.replace('>', " > ").split_whitespace() {
+        if token == ">" {
+            child = true;
+            continue;
+        }
+
+        let mut compound = Compound {
+            name: None,
+            ids: Vec::new(),
+            classes: Vec::new(),
+        };
+        let mut parts = token.split_inclusive(|c: char| c == '.' || c == '#').peekable();
+        let mut prefix = ' ';
+        let mut first = true;
+
+        while let Some(part) = parts.next() {
+            let (name, next) = match part.chars().last() {
+                Some(c) if (c == '.' || c == '#') => (&part[..part.len() - 1], c),
+                _ => (part, ' '),
+            };
+
+            match prefix {
+                '.' if name.len() > 0 => {
+                    compound.classes.push(name.to_string());
+                    specificity.1 += 1;
+                }
+                '#' if name.len() > 0 => {
+                    compound.ids.push(name.to_string());
+                    specificity.0 += 1;
+                }
+                ' ' if first && name.len() > 0 && name != "*" => {
+                    compound.name = Some(name.rsplit('|').next().unwrap_or(name).to_ascii_lowercase());
+                    specificity.2 += 1;
+                }
+                _ => {}
+            }
+            prefix = next;
+            first = false;
+        }
+
+        compounds.push((compound, child));
+        child = false;
+    }
+
+    if compounds.len() == 0 {
+        return None;
+    }
+
+    Some((compounds, specificity))
+}
+
+/*
+This function returns the fonts declared by the @font-face rules of a stylesheet, as their family
+name and the url of their first source.
+*/
+pub fn font_faces(css: &str) -> Vec<(String, String)> {
+    let css = remove_comments(css);
+    let mut faces = Vec::new();
+    let mut rest = css.as_str();
+
+    while let Some(start) = rest.find("@font-face") {
+        rest = &rest[start + 10..];
+        let (open, close) = match (rest.find('{'), rest.find('}')) {
+            (Some(open), Some(close)) if open < close => (open, close),
+            _ => break,
+        };
+        let declarations = parse_declarations(&rest[open + 1..close]);
+        let value = |name: &str| declarations.iter().find(|(property, _)| property == name).map(|(_, value)| value.clone());
+
+        let family = value("font-family").map(|family| family.trim_matches(|c| c == '"' || c == '\'').trim().to_string());
+        let url = value("src").and_then(|src| {
+            let start = src.find("url(")? + 4;
+            let end = start + src[start..].find(')')?;
+            Some(src[start..end].trim().trim_matches(|c| c == '"' || c == '\'').to_string())
+        });
+        if let (Some(family), Some(url)) = (family, url) {
+            if family.len() > 0 && !faces.iter().any(|(other, _)| *other == family) {
+                faces.push((family, url));
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+
+    faces
+}
+
+/*
+This function parses a list of declarations separated by semicolons, as the body of a rule or the
+style attribute of an element.
+*/
+fn parse_declarations(text: &str) -> Vec<(String, String)> {
+    text.split(';')
+        .filter_map(|declaration| {
+            let (property, value) = declaration.split_once(':')?;
+            Some((property.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+fn remove_comments(css: &str) -> String {
+    let mut result = String::new();
+    let mut rest = css;
+
+    while let Some(start) = rest.find("/*") {
+        result.push_str(&rest[..start]);
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    result.push_str(rest);
+
+    result
+}
+
+/*
+This function skips a block delimited by braces, including the nested blocks, returning the text
+after it.
+*/
+fn skip_block(text: &str) -> &str {
+    let mut depth = 0;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return &text[i + 1..];
+                }
+            }
+            _ => {}
+        }
+    }
+
+    ""
+}
@@ -3,27 +3,54 @@
 #![allow(unused_variables)]
 
 use std::{fs, io};
-use druid::{Data, Lens, EventCtx, Env, commands, AppDelegate, DelegateCtx, Target, Command, Handled, Widget, WidgetExt, Event, LifeCycleCtx, LifeCycle, UpdateCtx, LayoutCtx, BoxConstraints, Size, PaintCtx, WidgetId, Selector, WindowDesc, WindowId, FileDialogOptions, FileSpec};
+use druid::{Color, Data, Lens, EventCtx, Env, commands, AppDelegate, DelegateCtx, ExtEventSink, Target, Command, Handled, Widget, WidgetExt, Event, LifeCycleCtx, LifeCycle, UpdateCtx, LayoutCtx, BoxConstraints, Size, PaintCtx, WidgetId, Selector, WindowDesc, WindowId, WindowState, FileDialogOptions, FileInfo, FileSpec, KeyOrValue};
 use std::fs::{File};
-use std::io::{BufReader, Read, Seek, Write};
+use std::io::{Read, Seek, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use druid::im::Vector;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use druid::im::{HashMap, Vector};
 use druid::widget::{SizedBox};
-use epub::archive::EpubArchive;
-use imagesize::{blob_size};
 use native_dialog::{MessageDialog, MessageType};
 use voca_rs::Voca;
-use crate::view::{build_ui_edit_mode, build_widget};
+use regex::Regex;
+use crate::view::{build_preferences_window, build_ui_edit_mode, build_widget};
+use crate::book::{Book, DecodedPage};
+use crate::export::{export_annotations, ExportFormat};
+use crate::highlight::{Highlight, HighlightColor, Selection};
+use crate::index::{index_book, index_epub_file, index_library, search_library, LibraryHit};
+use crate::library::{scan_library, LibraryBook, LibraryLayout, LibrarySettings, LibrarySort};
+use crate::recent::{add_recent, check_recent, write_recent, RecentBook};
+use crate::theme::{self, ImageFilter, Theme};
+use crate::typography::{forget_fonts, Typography};
+use crate::preferences::{Preferences, FONT_SIZE_RANGE};
+use crate::locator::{locator_at, offset_of, Locator};
+use crate::loader::{book_key, load_epub, resolve_href, LoadError, LoadProgress};
+use crate::css::Stylesheet;
+use crate::markup::{footnote_text, text_positions};
+use crate::search::{build_pattern, search_sections, SearchHit, SearchOptions, MAX_HITS};
+use crate::storage::{backup_file, legacy_metadata_file, metadata_file};
+use crate::util::clamp;
+use crate::paginator::{locate_chapters, page_at_offset, page_of_target, paginate, paginate_after, PageLayout};
 use serde::Serialize;
 use serde::Deserialize;
-use voca_rs::strip::strip_tags;
+use epub::doc::EpubDoc;
 use zip::{CompressionMethod};
 use zip::result::ZipError;
 use zip::write::FileOptions;
 use walkdir::{WalkDir, DirEntry as OtherDirEntry};
 
 
-const SIZE_FONT: f64 = 20.0;
+pub const SIZE_FONT: f64 = 20.0;
+
+/*
+The space left above and below the pages in the reading area.
+*/
+pub const PAGE_PADDING: f64 = 10.0;
+
+const SEARCH_HIT_COLOR: Color = Color::rgb8(0xff, 0xd5, 0x99);
+const CURRENT_HIT_COLOR: Color = Color::rgb8(0xff, 0x98, 0x00);
 
 #[derive(Clone, Data, Lens, Serialize, Deserialize)]
 pub struct ImageOfPage {
@@ -49,6 +76,8 @@ on the current state of the AppState struct. This is done through the function r
 pub struct Rebuilder {
     inner: Box<dyn Widget<AppState>>,
     window_size: f64,
+    window_height: f64,
+    reading_height: f64,
 }
 
 impl Rebuilder {
@@ -56,6 +85,8 @@ impl Rebuilder {
         Rebuilder {
             inner: SizedBox::empty().boxed(),
             window_size: 1100.,
+            window_height: 600.,
+            reading_height: 450.,
         }
     }
 
@@ -67,13 +98,21 @@ impl Rebuilder {
 /*
 We have created a Widget of AppState for the Rebuilder struct manually managing the main function of
 Widget: event, lifecycle, update, layout, paint and id. In particular, our focus is on the update
-function in which we manage the navigation through the ebook pages.
+function in which we manage the navigation through the ebook pages and ask for a new pagination
+when the font or the size of the window change. The layout records the height given to the reading
+area, which changes with the size of the window and with the rows shown above and below it.
 */
 impl Widget<AppState> for Rebuilder {
     fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
         if data.window_size != self.window_size {
             data.window_size = self.window_size;
         }
+        if data.window_height != self.window_height {
+            data.window_height = self.window_height;
+        }
+        if data.reading_height != self.reading_height {
+            data.reading_height = self.reading_height;
+        }
         self.inner.event(ctx, event, data, env)
     }
 
@@ -86,6 +125,22 @@ impl Widget<AppState> for Rebuilder {
 
     fn update(&mut self, ctx: &mut UpdateCtx, old_data: &AppState, data: &AppState, _env: &Env) {
         if !old_data.same(data) {
+            if old_data.font_size != data.font_size
+                || old_data.window_size != data.window_size
+                || old_data.window_height != data.window_height
+                || old_data.reading_height != data.reading_height
+                || old_data.double_page != data.double_page
+                || old_data.display_menu != data.display_menu
+                || old_data.typography.line_height != data.typography.line_height
+                || old_data.typography.paragraph_spacing != data.typography.paragraph_spacing
+                || old_data.typography.margin != data.typography.margin {
+                ctx.submit_command(REPAGINATE);
+            }
+
+            if old_data.current_page != data.current_page || !old_data.ebook.same(&data.ebook) {
+                data.prefetch_pages();
+            }
+
             if data.ebook.len() > 0 {
                 if old_data.edit_current_page != data.edit_current_page {
                     if data.edit_current_page.parse::<usize>().is_ok() {
@@ -118,6 +173,10 @@ impl Widget<AppState> for Rebuilder {
         env: &Env,
     ) -> Size {
         self.window_size = ctx.window().get_size().width;
+        self.window_height = ctx.window().get_size().height;
+        if bc.max().height.is_finite() {
+            self.reading_height = bc.max().height;
+        }
         self.inner.layout(ctx, bc, data, env)
     }
 
@@ -130,25 +189,103 @@ impl Widget<AppState> for Rebuilder {
     }
 }
 
+/*
+A page of the Ebook as it is displayed: a piece of the content of a spine item, starting at the byte
+start of its file, and the label of the printed page (if the publisher inserted page markers). The
+context contains the start tags of the elements still open at the beginning of the page, so that the
+page can be displayed with its style. Pages are parsed and their images decoded by the Book only
+when they are displayed.
+*/
 #[derive(Clone, Data, Lens, Serialize, Deserialize)]
 pub struct Page {
     pub text: String,
-    pub images: Vector<ImageOfPage>,
+    pub source: String,
+    pub start: usize,
+    pub print_page: String,
+    pub context: String,
 }
 
 impl Page {
     pub fn new() -> Self {
         Self {
             text: String::new(),
-            images: Vector::<ImageOfPage>::new(),
+            source: String::new(),
+            start: 0,
+            print_page: String::new(),
+            context: String::new(),
+        }
+    }
+}
+
+/*
+The whole content of a spine item of the Ebook, with the width and height of its images indexed by
+the value of their src attribute, the stylesheet it uses (parsed once while the Ebook is loaded and
+shared by the sections using the same stylesheets) and the byte ranges of the elements it hides.
+Sections are split into pages by the paginator.
+*/
+#[derive(Clone, Data, Lens, Serialize, Deserialize)]
+pub struct Section {
+    pub source: String,
+    pub content: String,
+    pub image_sizes: HashMap<String, (usize, usize)>,
+    #[serde(skip)]
+    pub stylesheet: Arc<Stylesheet>,
+    pub hidden: Vector<(usize, usize)>,
+}
+
+impl Section {
+    pub fn new() -> Self {
+        Self {
+            source: String::new(),
+            content: String::new(),
+            image_sizes: HashMap::new(),
+            stylesheet: Arc::new(Stylesheet::new("")),
+            hidden: Vector::new(),
         }
     }
+
+    /*
+    This function returns the byte positions in the content of the characters of visible text with
+    the given numbers (in increasing order), counted from the byte from. Whitespace and the hidden
+    elements aren't counted, as in the text displayed.
+    */
+    pub fn text_positions(&self, from: usize, counts: &[usize]) -> Vec<usize> {
+        let (positions, _) = text_positions(&self.content[from..], &self.hidden_in(from..self.content.len()), counts);
+        positions.into_iter().map(|position| from + position).collect()
+    }
+
+    /*
+    This function returns the number of characters of visible text in a range of the content, counted
+    as text_positions does.
+    */
+    pub fn text_length(&self, range: Range<usize>) -> usize {
+        text_positions(&self.content[range.clone()], &self.hidden_in(range), &[]).1
+    }
+
+    /*
+    This function returns the hidden ranges intersecting a range of the content, relative to its
+    beginning.
+    */
+    fn hidden_in(&self, range: Range<usize>) -> Vec<(usize, usize)> {
+        self.hidden.iter()
+            .filter(|(start, end)| *start < range.end && *end > range.start)
+            .map(|(start, end)| (start.max(&range.start) - range.start, end.min(&range.end) - range.start))
+            .collect()
+    }
 }
 
+/*
+A chapter of the table of contents of the Ebook; chapters can contain other chapters following the
+hierarchy defined by the publisher. The target page is computed from source and fragment every time
+the Ebook is paginated.
+*/
 #[derive(Clone, Data, Serialize, Deserialize)]
 pub struct Chapter {
     pub title: String,
     pub target_page: usize,
+    pub source: String,
+    pub fragment: String,
+    pub children: Vector<Chapter>,
 }
 
 impl Chapter {
@@ -156,35 +293,196 @@ impl Chapter {
         Self {
             title: String::from(title),
             target_page: page,
+            source: String::new(),
+            fragment: String::new(),
+            children: Vector::new(),
+        }
+    }
+}
+
+/*
+This function returns the chapters of the table of contents in reading order, each one with its
+depth in the hierarchy, in order to display them in the menu section.
+*/
+pub fn flatten_chapters(chapters: &Vector<Chapter>) -> Vec<(usize, Chapter)> {
+    let mut list = Vec::new();
+    let mut stack: Vec<(usize, Chapter)> = chapters.iter().rev().map(|c| (0, c.clone())).collect();
+
+    while let Some((depth, chapter)) = stack.pop() {
+        for child in chapter.children.iter().rev() {
+            stack.push((depth + 1, child.clone()));
         }
+        list.push((depth, chapter));
     }
+
+    list
 }
 
+/*
+The version of the format of the json files saved for every Ebook. It has to be increased every
+time the format changes, adding to MIGRATIONS the function converting the files of the previous
+version.
+*/
+pub const SAVE_VERSION: u64 = 3;
+
+/*
+The functions converting a saved file from a version to the next one: the function at index i
+converts the files of version i + 1. They work on the json values, so that the files of every
+previous version can be read even when the fields of JsonStruct change, and fail if a file doesn't
+contain what its version saved.
+*/
+const MIGRATIONS: [fn(&mut serde_json::Value) -> Result<(), SaveError>; 2] = [
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+];
+
+/*
+The data saved for every Ebook: the bookmarks, the highlights and the last position read, as
+locators so that they don't depend on the pagination, and the fraction of the Ebook read, shown in
+the library. The fields missing from a file take their default value, so that
+adding a field doesn't prevent reading the existing files.
+*/
 #[derive(Clone, Data, Serialize, Deserialize)]
 pub struct JsonStruct {
-    pub bookmarks: Vector<(String, usize)>,
-    pub last_page: usize,
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub bookmarks: Vector<(String, Locator)>,
+    #[serde(default)]
+    pub position: Locator,
+    #[serde(default)]
+    pub highlights: Vector<Highlight>,
+    #[serde(default)]
+    pub progress: f64,
 }
 
 impl JsonStruct {
     pub fn new() -> Self {
         Self {
+            version: SAVE_VERSION,
             bookmarks: Vector::new(),
-            last_page: 0,
+            position: Locator::default(),
+            highlights: Vector::new(),
+            progress: 0.,
+        }
+    }
+
+    /*
+    This function reads the content of a saved file, applying the migrations needed to bring it to
+    the current version. An error is returned if the file isn't valid json, if it doesn't contain
+    the data of an Ebook or if it was saved by a newer version of the application.
+    */
+    pub fn from_json(text: &str) -> Result<Self, SaveError> {
+        let mut value: serde_json::Value = serde_json::from_str(text).map_err(|e| SaveError::Invalid(e.to_string()))?;
+        if !value.is_object() {
+            return Err(SaveError::Invalid(String::from("the file doesn't contain an object")));
+        }
+
+        let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(1);
+        if version > SAVE_VERSION {
+            return Err(SaveError::Newer(version));
+        }
+        if version == 0 {
+            return Err(SaveError::Invalid(format!("unknown version {}", version)));
+        }
+
+        for migration in MIGRATIONS.iter().skip(version as usize - 1) {
+            migration(&mut value)?;
         }
+
+        let mut saves: JsonStruct = serde_json::from_value(value).map_err(|e| SaveError::Invalid(e.to_string()))?;
+        saves.version = SAVE_VERSION;
+
+        Ok(saves)
+    }
+}
+
+/*
+The reasons why a saved file can't be read: an invalid file is backed up and replaced, while a
+file saved by a newer version of the application is left untouched, so that the newer version can
+still read it.
+*/
+pub enum SaveError {
+    Invalid(String),
+    Newer(u64),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Invalid(error) => write!(f, "{}", error),
+            SaveError::Newer(version) => write!(f, "saved by a newer version of the application (format {})", version),
+        }
+    }
+}
+
+/*
+The first version had no version field.
+*/
+fn migrate_v1_to_v2(value: &mut serde_json::Value) -> Result<(), SaveError> {
+    value["version"] = serde_json::Value::from(2);
+    Ok(())
+}
+
+/*
+The version 2 saved the index of the pages, which are kept as locators without file until the
+Ebook has been paginated. The bookmarks that aren't a pair of name and page number are dropped,
+while a last page that isn't a page number makes the file invalid.
+*/
+fn migrate_v2_to_v3(value: &mut serde_json::Value) -> Result<(), SaveError> {
+    let page = |page: u64| serde_json::to_value(Locator::from_page(page as usize)).unwrap();
+
+    match value.get("last_page") {
+        None => {}
+        Some(last_page) => match last_page.as_u64() {
+            Some(last_page) => value["position"] = page(last_page),
+            None => return Err(SaveError::Invalid(format!("the last page {} isn't a page number", last_page))),
+        },
     }
+    if let Some(bookmarks) = value.get_mut("bookmarks").and_then(|b| b.as_array_mut()) {
+        bookmarks.retain(|bookmark| match bookmark.as_array() {
+            Some(pair) => pair.len() == 2 && pair[0].is_string() && pair[1].as_u64().is_some(),
+            None => false,
+        });
+        for bookmark in bookmarks.iter_mut() {
+            bookmark[1] = page(bookmark[1].as_u64().unwrap());
+        }
+    }
+    value["version"] = serde_json::Value::from(3);
+    Ok(())
+}
+
+/*
+An Ebook being loaded by a worker thread: the path of the file, the number identifying the loading
+(the messages of a cancelled loading are ignored), the flag asking the worker to stop, whether the
+Ebook has already replaced the previous one in the view and whether the user has been moved to the
+last position read. The target, if any, replaces the last position read: it is the file and the
+number of characters of visible text without whitespace preceding the position to open the Ebook at.
+*/
+#[derive(Clone)]
+pub struct LoadTask {
+    pub path: String,
+    pub id: usize,
+    pub cancel: Arc<AtomicBool>,
+    pub opened: bool,
+    pub resumed: bool,
+    pub target: Option<(String, usize)>,
 }
 
 /*
 This is the main struct used by the application managing the state of the application and the
-current opened Ebook.
+current opened Ebook. The archive of the Ebook is shared with the Book, which isn't part of the
+state compared by druid nor of the saved data.
 */
 #[derive(Clone, Data, Lens, Serialize, Deserialize)]
 pub struct AppState {
-    pub font_size: String,
+    pub font_size: f64,
+    pub sections: Vector<Section>,
     pub ebook: Vector<Page>,
     pub current_page: usize,
     pub window_size: f64,
+    pub window_height: f64,
+    pub reading_height: f64,
     pub double_page: bool,
     pub title: String,
     pub chapters: Vector<Chapter>,
@@ -197,15 +495,70 @@ pub struct AppState {
     pub file_info: String,
     pub scan_mode: bool,
     pub edit_current_page: String,
+    pub show_print_pages: bool,
+    pub history: Vector<(String, usize)>,
+    pub footnote: String,
+    pub footnote_popover: bool,
+    #[data(ignore)]
+    #[serde(skip)]
+    pub book: Option<Arc<Book>>,
+    pub loading: bool,
+    pub loading_progress: f64,
+    #[data(ignore)]
+    #[serde(skip)]
+    pub loading_task: Option<LoadTask>,
+    #[serde(skip)]
+    pub selection: Option<Selection>,
+    pub highlight_color: HighlightColor,
+    pub string_note: String,
+    pub display_search: bool,
+    pub search_query: String,
+    pub search_options: SearchOptions,
+    #[serde(skip)]
+    pub search_hits: Vector<SearchHit>,
+    pub search_current: usize,
+    #[serde(skip)]
+    pub search_partial: bool,
+    #[serde(skip)]
+    pub library_hits: Vector<LibraryHit>,
+    pub display_library: bool,
+    pub library_folders: Vector<String>,
+    #[serde(skip)]
+    pub library_books: Vector<LibraryBook>,
+    pub library_filter: String,
+    pub library_sort: LibrarySort,
+    pub library_layout: LibraryLayout,
+    pub library_scanning: bool,
+    pub recent_books: Vector<RecentBook>,
+    pub display_recent: bool,
+    pub distraction_free: bool,
+    pub wheel_turning: bool,
+    pub click_turning: bool,
+    pub theme: String,
+    #[serde(skip)]
+    pub themes: Vector<Theme>,
+    pub image_filter: ImageFilter,
+    pub typography: Typography,
+    pub display_typography: bool,
+    #[serde(skip)]
+    pub preferences: Preferences,
+    #[data(ignore)]
+    #[serde(skip)]
+    pub preferences_window: Option<WindowId>,
+    #[serde(skip)]
+    pub saves_read_only: bool,
 }
 
 impl AppState {
     pub fn new() -> Self {
         Self {
-            font_size: SIZE_FONT.to_string(),
+            font_size: SIZE_FONT,
+            sections: Vector::<Section>::new(),
             ebook: Vector::<Page>::new(),
             current_page: 0,
             window_size: 1100.,
+            window_height: 600.,
+            reading_height: 450.,
             double_page: false,
             title: String::new(),
             chapters: Vector::<Chapter>::new(),
@@ -218,34 +571,457 @@ impl AppState {
             file_info: String::new(),
             scan_mode: false,
             edit_current_page: String::from("0"),
+            show_print_pages: false,
+            history: Vector::new(),
+            footnote: String::new(),
+            footnote_popover: true,
+            book: None,
+            loading: false,
+            loading_progress: 0.,
+            loading_task: None,
+            selection: None,
+            highlight_color: HighlightColor::Yellow,
+            string_note: String::new(),
+            display_search: false,
+            search_query: String::new(),
+            search_options: SearchOptions::new(),
+            search_hits: Vector::new(),
+            search_current: 0,
+            search_partial: false,
+            library_hits: Vector::new(),
+            display_library: false,
+            library_folders: Vector::new(),
+            library_books: Vector::new(),
+            library_filter: String::new(),
+            library_sort: LibrarySort::Title,
+            library_layout: LibraryLayout::Grid,
+            library_scanning: false,
+            recent_books: Vector::new(),
+            display_recent: false,
+            distraction_free: false,
+            wheel_turning: true,
+            click_turning: true,
+            theme: Theme::default().name,
+            themes: Vector::from(vec![Theme::default()]),
+            image_filter: ImageFilter::Normal,
+            typography: Typography::default(),
+            display_typography: false,
+            preferences: Preferences::default(),
+            preferences_window: None,
+            saves_read_only: false,
+        }
+    }
+
+    /*
+    This function returns the width of the column containing the text of a page, which depends on the
+    size of the window, on the menu and on the number of pages displayed.
+    */
+    pub fn text_width(&self) -> f64 {
+        let mut width = self.window_size - self.typography.margin * 2.;
+        if self.display_menu {
+            width = width / 1.2 - 20.;
+        }
+        if self.double_page {
+            width = width / 2. - 30.;
+        }
+
+        width.max(1.)
+    }
+
+    /*
+    This function returns the page of the Ebook with the given index parsed and with its images
+    decoded, ready to be displayed.
+    */
+    pub fn decoded_page(&self, index: usize) -> Option<Arc<DecodedPage>> {
+        let page = self.ebook.get(index)?;
+        let book = self.book.as_ref()?;
+
+        Some(book.page(page, &self.section_stylesheet(&page.source)))
+    }
+
+    /*
+    This function decodes in the background the pages around the current one (the previous one and
+    the next ones, two by two in double page mode), so that turning the page doesn't wait for the
+    archive.
+    */
+    pub fn prefetch_pages(&self) {
+        let book = match &self.book {
+            Some(book) => book.clone(),
+            None => return,
+        };
+        let step = if self.double_page { 2 } else { 1 };
+        let first = self.current_page.saturating_sub(step);
+        let last = (self.current_page + 2 * step).min(self.ebook.len());
+
+        let pages = (first..last)
+            .map(|i| (self.ebook[i].clone(), self.section_stylesheet(&self.ebook[i].source)))
+            .collect();
+        Book::prefetch(&book, pages);
+    }
+
+    fn section_stylesheet(&self, source: &str) -> Arc<Stylesheet> {
+        self.sections.iter()
+            .find(|section| section.source == source)
+            .map(|section| section.stylesheet.clone())
+            .unwrap_or_else(|| Arc::new(Stylesheet::new("")))
+    }
+
+    /*
+    This function returns the locator of the beginning of the current page, which doesn't change
+    when the Ebook is paginated again.
+    */
+    pub fn current_locator(&self) -> Option<Locator> {
+        let page = self.ebook.get(self.current_page)?;
+        let section = self.sections.iter().find(|section| section.source == page.source)?;
+
+        Some(locator_at(&page.source, &section.content, page.start))
+    }
+
+    /*
+    This function returns the index of the page containing the position of a locator in the current
+    pagination, or None if its file hasn't been loaded yet. If the element of the locator doesn't
+    exist anymore, the first page of its file is returned.
+    */
+    pub fn page_of_locator(&self, locator: &Locator) -> Option<usize> {
+        if locator.is_page() {
+            return Some(locator.offset).filter(|page| *page < self.ebook.len());
+        }
+
+        let section = self.sections.iter().find(|section| section.source == locator.source)?;
+        let offset = offset_of(&section.content, locator).unwrap_or(0);
+
+        page_at_offset(&self.ebook, &locator.source, offset)
+    }
+
+    /*
+    This function saves the position of the current page as the last position read. The progress is
+    only updated once the whole Ebook is paginated.
+    */
+    pub fn save_position(&mut self) {
+        if let Some(locator) = self.current_locator() {
+            self.saves.position = locator;
+        }
+        if !self.loading && self.ebook.len() > 0 {
+            self.saves.progress = (self.current_page + 1) as f64 / self.ebook.len() as f64;
+        }
+        self.save_to_json();
+    }
+
+    /*
+    This function converts the positions saved as page indices by the previous versions of the
+    application into locators, using the current pagination.
+    */
+    pub fn resolve_page_locators(&mut self) {
+        let to_locator = |state: &Self, locator: &Locator| -> Locator {
+            match state.ebook.get(locator.offset) {
+                Some(page) if locator.is_page() => {
+                    let content = state.sections.iter()
+                        .find(|section| section.source == page.source)
+                        .map(|section| section.content.as_str())
+                        .unwrap_or("");
+                    locator_at(&page.source, content, page.start)
+                }
+                _ => locator.clone(),
+            }
+        };
+
+        let position = to_locator(self, &self.saves.position);
+        let bookmarks: Vector<(String, Locator)> = self.saves.bookmarks.iter()
+            .map(|(title, locator)| (title.clone(), to_locator(self, locator)))
+            .collect();
+
+        if position != self.saves.position || bookmarks != self.saves.bookmarks {
+            self.saves.position = position;
+            self.saves.bookmarks = bookmarks;
+            self.save_to_json();
+        }
+    }
+
+    /*
+    This function returns the marks painted on a page: its highlights, with their colour, the hits of
+    the search (the current one in a stronger colour) and the text selected by the user, as ranges of
+    the characters of the visible text of the page without whitespace. A highlight starting on a
+    previous page is painted from the beginning of the page.
+    */
+    pub fn page_marks(&self, index: usize) -> Vec<(Range<usize>, KeyOrValue<Color>)> {
+        let mut marks = Vec::new();
+        let page = match self.ebook.get(index) {
+            Some(page) => page,
+            None => return marks,
+        };
+        let end_of_page = page.start + page.text.len();
+
+        if let Some(section) = self.sections.iter().find(|section| section.source == page.source) {
+            for highlight in self.saves.highlights.iter().filter(|h| h.position.source == page.source) {
+                let start = match offset_of(&section.content, &highlight.position) {
+                    Some(start) if start < end_of_page => start,
+                    _ => continue,
+                };
+                let (first, length) = if start >= page.start {
+                    (section.text_length(page.start..start), highlight.length())
+                } else {
+                    (0, highlight.length().saturating_sub(section.text_length(start..page.start)))
+                };
+
+                if length > 0 {
+                    marks.push((first..first + length, highlight.color.color().into()));
+                }
+            }
+        }
+
+        for (i, hit) in self.search_hits.iter().enumerate().filter(|(_, hit)| hit.page == index) {
+            let color = if i == self.search_current { CURRENT_HIT_COLOR } else { SEARCH_HIT_COLOR };
+            marks.push((hit.start..hit.start + hit.length, color.into()));
+        }
+
+        if let Some(selection) = self.selection.as_ref().filter(|selection| selection.page == index) {
+            marks.push((selection.start..selection.end, KeyOrValue::Key(theme::SELECTION)));
+        }
+
+        marks
+    }
+
+    /*
+    This function finds the page of every hit of the search in the current pagination, and the
+    number of characters of the page preceding it, so that it can be highlighted.
+    */
+    fn locate_search_hits(&mut self) {
+        let mut bases: HashMap<usize, usize> = HashMap::new();
+        let mut hits = self.search_hits.clone();
+
+        for hit in hits.iter_mut() {
+            let page = match page_at_offset(&self.ebook, &hit.source, hit.offset) {
+                Some(page) => page,
+                None => continue,
+            };
+            let base = *bases.entry(page).or_insert_with(|| {
+                let start = self.ebook[page].start;
+                self.sections.iter()
+                    .find(|section| section.source == hit.source)
+                    .map(|section| section.text_length(0..start))
+                    .unwrap_or(0)
+            });
+
+            hit.page = page;
+            hit.start = hit.chars.saturating_sub(base);
+        }
+
+        self.search_hits = hits;
+    }
 
+    /*
+    This function returns the page containing the given position of a file of the Ebook, expressed
+    as the number of characters of visible text without whitespace preceding it, as the positions of
+    the results of the search in the library. None is returned if the file isn't loaded yet.
+    */
+    pub fn page_of_text(&self, source: &str, chars: usize) -> Option<usize> {
+        let section = self.sections.iter().find(|section| section.source == source)?;
+        let offset = section.text_positions(0, &[chars]).first().copied()?;
+
+        page_at_offset(&self.ebook, source, offset)
+    }
+
+    /*
+    This function moves to the hit of the search with the given index.
+    */
+    pub fn go_to_hit(&mut self, index: usize) {
+        if let Some(hit) = self.search_hits.get(index) {
+            self.search_current = index;
+            self.current_page = hit.page;
+            self.edit_current_page = hit.page.to_string();
+        }
+    }
+
+    /*
+    This function splits the sections of the Ebook into pages fitting the current window and font
+    size. The reading position is kept: after the pagination the current page is the one containing
+    the beginning of the page that was displayed before.
+    */
+    pub fn repaginate(&mut self) {
+        let layout = self.page_layout();
+
+        let position = self.ebook.get(self.current_page).map(|page| (page.source.clone(), page.start));
+        let selected = self.selection.as_ref().and_then(|selection| self.ebook.get(selection.page).cloned());
+
+        self.ebook = paginate(&self.sections, &layout);
+        locate_chapters(&mut self.chapters, &self.ebook, 0);
+        self.locate_search_hits();
+
+        if let Some((source, start)) = position {
+            self.current_page = page_at_offset(&self.ebook, &source, start).unwrap_or(0);
+        }
+        if self.current_page >= self.ebook.len() {
+            self.current_page = 0;
         }
+        self.edit_current_page = self.current_page.to_string();
+
+        let kept = match (&self.selection, &selected) {
+            (Some(selection), Some(page)) => self.ebook.get(selection.page)
+                .map(|new| new.source == page.source && new.start == page.start && new.text.len() == page.text.len())
+                .unwrap_or(false),
+            _ => false,
+        };
+        if !kept {
+            self.selection = None;
+        }
+    }
+
+    /*
+    This function adds the sections read by the loader to the Ebook, splitting only them into pages:
+    the pages of the sections already loaded don't change, nor does the current page.
+    */
+    fn append_sections(&mut self, sections: &Vector<Section>) {
+        self.sections.append(sections.clone());
+
+        let pages = paginate_after(&self.ebook, sections, &self.page_layout());
+        self.ebook.append(pages);
+    }
+
+    /*
+    This function returns the size of the pages and of their text for the current window, font size
+    and typography. The text of a page is displayed in the reading area measured by the Rebuilder,
+    below the number of the page (three lines of the font size) and padded above and below by
+    PAGE_PADDING.
+    */
+    fn page_layout(&self) -> PageLayout {
+        let font_size = self.font_size;
+        let number_height = font_size * 3.6;
+
+        PageLayout {
+            font_size,
+            line_height: self.typography.line_height,
+            paragraph_spacing: self.typography.paragraph_spacing,
+            width: self.text_width().max(font_size),
+            height: (self.reading_height - PAGE_PADDING * 2. - number_height).max(font_size * 1.2),
+        }
+    }
+
+    /*
+    This function updates the AppState variable show_print_pages in order to display or hide the
+    number of the printed page, when the publisher inserted page markers in the Ebook.
+    */
+    pub fn click_print_pages_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.show_print_pages = !data.show_print_pages;
+    }
+
+    /*
+    This function updates the AppState variable footnote_popover: when it is set, the links to the
+    footnotes display the text of the note over the page instead of jumping to it.
+    */
+    pub fn click_footnote_popover_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.footnote_popover = !data.footnote_popover;
+        data.footnote.clear();
+    }
+
+    /*
+    This function closes the footnote displayed over the page.
+    */
+    pub fn click_close_footnote_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.footnote.clear();
+    }
+
+    /*
+    This function stops the loading of the Ebook in progress.
+    */
+    pub fn click_cancel_loading_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.cancel_loading();
     }
 
     /*
-    This function updates the AppState variable font_size adding 1
+    This function asks the worker thread loading an Ebook (if any) to stop. If the Ebook being loaded
+    was already displayed it is closed, since only a part of it has been read.
     */
-    pub fn click_plus_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
-        data.plus();
+    pub fn cancel_loading(&mut self) {
+        if let Some(task) = self.loading_task.take() {
+            task.cancel.store(true, Ordering::Relaxed);
+            if task.opened {
+                self.close_book();
+            }
+        }
+        self.loading = false;
     }
-    fn plus(&mut self) {
-        let new_size = self.font_size.parse::<f64>().unwrap() + 1.;
-        self.font_size = new_size.to_string();
+
+    /*
+    This function removes the current Ebook from the AppState, going back to the welcome screen.
+    */
+    pub fn close_book(&mut self) {
+        forget_fonts();
+        self.ebook.clear();
+        self.sections.clear();
+        self.chapters.clear();
+        self.book = None;
+        self.title.clear();
+        self.file_info.clear();
+        self.current_page = 0;
+        self.edit_current_page = String::from("0");
+        self.saves = JsonStruct::new();
+        self.history.clear();
+        self.footnote.clear();
+        self.selection = None;
+        self.string_note.clear();
+        self.search_hits.clear();
+        self.search_current = 0;
+        self.search_partial = false;
     }
 
     /*
-    This function updates the AppState variable font_size subtracting 1
+    This function brings the user back to the position the last followed link was clicked from.
+    Positions are saved as file and offset, so they are still valid after a new pagination.
     */
-    pub fn click_min_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
-        data.min();
+    pub fn click_back_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        if let Some((source, start)) = data.history.pop_back() {
+            if let Some(page) = page_at_offset(&data.ebook, &source, start) {
+                data.current_page = page;
+                data.edit_current_page = page.to_string();
+            }
+        }
+        data.footnote.clear();
     }
-    fn min(&mut self) {
-        if self.font_size.parse::<f64>().unwrap() > 0. {
-            let new_size = self.font_size.parse::<f64>().unwrap() - 1.;
-            self.font_size = new_size.to_string();
+
+    /*
+    This function follows a link pointing inside the Ebook, given the file of the page containing it.
+    The target file and fragment are translated into a page; when footnote_popover is set and the
+    target is a footnote, its text is displayed over the page instead. The current position is saved
+    in the history, so that the Back button can return to it.
+    */
+    pub fn follow_link(&mut self, source: &str, href: &str, noteref: bool) {
+        let path = resolve_href(source, href);
+        let fragment = href.split('#').nth(1);
+
+        if self.footnote_popover {
+            if let Some(fragment) = fragment {
+                let note = self.sections.iter()
+                    .find(|section| section.source == path)
+                    .and_then(|section| footnote_text(&section.content, fragment, noteref));
+
+                if let Some(note) = note {
+                    self.footnote = note;
+                    return;
+                }
+            }
+        }
+
+        match page_of_target(&self.ebook, &path, fragment) {
+            Some(page) => {
+                if let Some(current) = self.ebook.get(self.current_page) {
+                    self.history.push_back((current.source.clone(), current.start));
+                }
+                self.current_page = page;
+                self.edit_current_page = page.to_string();
+                self.footnote.clear();
+            }
+            None => println!("Link target {} not found", href),
         }
     }
 
+    /*
+    This function sets the font size of the text, keeping it inside the sizes allowed by the
+    preferences.
+    */
+    pub fn set_font_size(&mut self, size: f64) {
+        self.font_size = clamp(size, &FONT_SIZE_RANGE);
+    }
+
     /*
     This function manages the opening of a new window to edit the Ebook current page.
     Before creating the new window, it checks if the constraints are met or it will open a new
@@ -375,14 +1151,25 @@ impl AppState {
     pub fn click_help_button(_ctx: &mut EventCtx, _: &mut Self, _env: &Env) {
         let help_description = String::from("Welcome to Ebook Reader application v1.0.0, this is a short guide for the application.
         \nThese are the functions that you can use:
-        \n- Open: use this button to open an epub file and navigate through its content
+        \n- Open: use this button to open an epub file and navigate through its content; the first pages can be read while the rest of the Ebook is loading, use the Cancel button next to the progress bar to stop the loading
         \n- Edit: use this button to edit the epub content of the current page. Be careful, inappropriate html changes can corrupt the new file, we suggest you to only edit the contents of the tags
         \n- Scan: use this button to select a jpg/png image of the physical book to navigate to the corresponding digital page. Be careful, the success of this function depends on the image quality!
-        \n- Previous/Next Page: use these buttons to navigate through the ebook digital pages, you can use the text field to directly navigate to the desired page
+        \n- Previous/Next Page: use these buttons to navigate through the ebook digital pages, you can use the text field to directly navigate to the desired page. Scrolling past the end of a page or clicking on the left or right third of the page turns it too, unless Wheel turns pages or Click zones are unchecked
         \n- Bookmark section: this section allows you to create a new bookmark at the current page assigning it a name
-        \n- Menu: use this button to toggle a new section in which you can find all the bookmarks you created and ebook chapters, you can interact with them (by clicking) to navigate to the corresponding page. To delete a bookmark click on the red 'x'
+        \n- Highlights: drag the mouse over the text of a page to select it, then choose a colour, optionally write a note and press Apply to highlight it
+        \n- Export: use this button to save the bookmarks, highlights and notes of the Ebook to a Markdown, JSON or CSV file, grouped by chapter
+        \n- Shortcuts: Arrows and PageUp/PageDown turn the pages, Home/End go to the first and last page, Ctrl+F shows the search bar, Ctrl+B bookmarks the page, Ctrl++/Ctrl+- change the font size and F11 switches to the distraction-free view, with only the page in a maximized window. Click on the page if they don't respond; the shortcuts can be changed in the keymap.json file of the configuration folder
+        \n- Recent: use this button to list the Ebooks opened recently and click on one to open it again where you left it; at launch, Continue reading reopens the last Ebook read
+        \n- Library: use this button to show the Ebooks of your folders with their cover and the progress of the reading; add the folders with Add folder, filter the Ebooks by title, author or language, choose their order, show them as a grid or a list and click on one to open it
+        \n- Find: use this button to show the search bar; the search ignores the case unless Match case is checked, Whole word skips the matches inside longer words and Regex searches a regular expression. The results are listed in the menu with their page and highlighted on the pages, use Previous and Next to move between them. With Whole library checked the words are searched in all the Ebooks opened so far and in the Ebooks of the library folders, listing the best results above the page: click on one to open its Ebook there
+        \n- Menu: use this button to toggle a new section in which you can find all the bookmarks, highlights and ebook chapters, you can interact with them (by clicking) to navigate to the corresponding page. To delete a bookmark or a highlight click on the red 'x'
+        \n- Print Pages: use this button to show the number of the printed page, when the Ebook contains it
+        \n- Links: click on a link to follow it, use the Back button to return to the page you were reading. Use Footnote Popover to choose if the footnotes are displayed over the page or reached as normal links
         \n- Single Page/Double Page: use these buttons to display one or two digital pages
-        \n- Font Section: use this section to adjust the text dimensions");
+        \n- Theme: use this button to switch between the light, dark, sepia and high contrast themes and the themes written in the themes.json file of the configuration folder; with a dark theme, Images chooses if the illustrations are displayed normally, dimmed or inverted
+        \n- Preferences: use this button to edit the font size, the layout of the pages, the page turning and the theme in their own window, then press Apply; the preferences and the size of the window are kept for the next launches in the preferences.json file of the configuration folder
+        \n- Font Section: use this section to adjust the text dimensions
+        \n- Typography: use this button to choose the font (the fonts of the Ebook, serif, sans-serif, monospace, the fonts embedded in the Ebook or the name of an installed font), the line height, the space after the paragraphs, the margins of the page and justified or ragged text; they are kept for the next launches");
 
         let dialog = MessageDialog::new()
             .set_type(MessageType::Info)
@@ -416,16 +1203,14 @@ impl AppState {
                     data.current_page -= 2;
                     let new_page = data.current_page.to_string();
                     data.edit_current_page = new_page;
-                    data.saves.last_page = data.current_page;
-                    data.save_to_json();
+                    data.save_position();
                 }
             } else {
                 if data.current_page > 0 {
                     data.current_page -= 1;
                     let new_page = data.current_page.to_string();
                     data.edit_current_page = new_page;
-                    data.saves.last_page = data.current_page;
-                    data.save_to_json();
+                    data.save_position();
                 }
             }
         }
@@ -455,21 +1240,89 @@ impl AppState {
                     data.current_page += 2;
                     let new_page = data.current_page.to_string();
                     data.edit_current_page = new_page;
-                    data.saves.last_page = data.current_page;
-                    data.save_to_json();
+                    data.save_position();
                 }
             } else {
                 if data.current_page < (data.ebook.len() - 1) {
                     data.current_page += 1;
                     let new_page = data.current_page.to_string();
                     data.edit_current_page = new_page;
-                    data.saves.last_page = data.current_page;
-                    data.save_to_json();
+                    data.save_position();
                 }
             }
         }
     }
 
+    /*
+    This function shows or hides the row of the typography settings.
+    */
+    pub fn click_typography_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.display_typography = !data.display_typography;
+    }
+
+    /*
+    This function switches to the next font family: the fonts of the Ebook, the generic families
+    and the fonts embedded in the Ebook.
+    */
+    pub fn click_font_family_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        let embedded: Vec<String> = data.book.as_ref()
+            .map(|book| book.fonts.iter().map(|(family, _)| family.clone()).collect())
+            .unwrap_or_default();
+        data.typography.font_family = data.typography.next_family(&embedded);
+    }
+
+    /*
+    This function opens the window of the preferences, editing a copy of the current ones, unless
+    it is already open.
+    */
+    pub fn click_preferences_button(ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        if data.preferences_window.is_some() {
+            return;
+        }
+        data.preferences = Preferences::from_state(data);
+
+        let window = WindowDesc::new(build_preferences_window)
+            .title("Preferences")
+            .window_size(Size::new(620., 560.));
+        data.preferences_window = Some(window.id);
+        ctx.new_window(window);
+    }
+
+    /*
+    This function switches the theme of the preferences to the next one of the list.
+    */
+    pub fn click_preferences_theme_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        let current = data.themes.iter().position(|theme| theme.name == data.preferences.theme).unwrap_or(0);
+        if let Some(next) = data.themes.get((current + 1) % data.themes.len().max(1)) {
+            data.preferences.theme = next.name.clone();
+        }
+    }
+
+    /*
+    This function applies the preferences edited in their window to the application and saves them.
+    */
+    pub fn click_apply_preferences_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.preferences.validate();
+        let preferences = data.preferences.clone();
+        preferences.apply(data);
+
+        if let Err(error) = Preferences::from_state(data).write() {
+            MessageDialog::new()
+                .set_type(MessageType::Error)
+                .set_text(format!("The preferences can't be saved: {}", error).as_str())
+                .set_title("Error")
+                .show_alert().ok();
+        }
+    }
+
+    /*
+    This function brings the preferences edited in their window back to the default ones; they are
+    used once they are applied.
+    */
+    pub fn click_reset_preferences_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.preferences = Preferences::default();
+    }
+
     /*
     This function updates the AppState variable display_menu in order to generate a new interactable
     section that manages bookmarks and chapters related to the selected Ebook.
@@ -524,7 +1377,7 @@ impl AppState {
 
             let mut found = false;
             for bookmark in data.saves.bookmarks.clone() {
-                if bookmark.1 == data.current_page {
+                if data.page_of_locator(&bookmark.1) == Some(data.current_page) {
                     found = true;
                 }
             }
@@ -536,7 +1389,8 @@ impl AppState {
                     .set_title("Bookmark already inserted")
                     .show_alert().expect("Error while founding the corresponding digital page");
             } else {
-                data.saves.bookmarks.push_back((data.string_bookmark.clone(), data.current_page.clone()));
+                let locator = data.current_locator().unwrap_or_default();
+                data.saves.bookmarks.push_back((data.string_bookmark.clone(), locator));
                 data.save_to_json();
                 data.string_bookmark = String::new();
                 data.new_bookmark = false;
@@ -545,10 +1399,58 @@ impl AppState {
     }
 
     /*
-    This function will delete the title inserted by the user in the TextBox of the bookmark section.
-    It checks if the constraints are met or it will open a new MessageDialog.
+    This function adds a bookmark to the current page, as the Apply button of the bookmark section
+    does, titled after the page when the user hasn't written a title. That title is removed again if
+    the bookmark isn't added, as when the page already has one.
     */
-    pub fn click_reject_bookmark_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+    pub fn bookmark_current_page(&mut self, ctx: &mut EventCtx, env: &Env) {
+        let generated = self.string_bookmark.trim().len() == 0;
+        if generated {
+            self.string_bookmark = format!("Page {}", self.current_page);
+        }
+        Self::click_confirm_bookmark_button(ctx, self, env);
+        if generated {
+            self.string_bookmark.clear();
+        }
+    }
+
+    /*
+    This function returns the theme chosen by the user, or the first one if it no longer exists.
+    */
+    pub fn current_theme(&self) -> Theme {
+        self.themes.iter()
+            .find(|theme| theme.name == self.theme)
+            .or(self.themes.front())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /*
+    This function returns the filter applied to the illustrations, which are only changed with a
+    dark theme.
+    */
+    pub fn current_image_filter(&self) -> ImageFilter {
+        if self.current_theme().dark { self.image_filter } else { ImageFilter::Normal }
+    }
+
+    /*
+    This function switches to the distraction-free view, which shows only the page in a maximized
+    window without the rows of the buttons and the title bar, or goes back to the normal view. druid
+    has no real fullscreen state, so the taskbar of the system stays visible.
+    */
+    pub fn toggle_distraction_free(&mut self, ctx: &mut EventCtx) {
+        self.distraction_free = !self.distraction_free;
+
+        let mut window = ctx.window().clone();
+        window.show_titlebar(!self.distraction_free);
+        window.set_window_state(if self.distraction_free { WindowState::MAXIMIZED } else { WindowState::RESTORED });
+    }
+
+    /*
+    This function will delete the title inserted by the user in the TextBox of the bookmark section.
+    It checks if the constraints are met or it will open a new MessageDialog.
+    */
+    pub fn click_reject_bookmark_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
         if data.ebook.len() == 0 {
             MessageDialog::new()
                 .set_type(MessageType::Info)
@@ -567,46 +1469,382 @@ impl AppState {
         }
     }
 
+    /*
+    This function highlights the text selected by the user with the chosen colour and note, storing
+    the locator of its first character so that the highlight follows the text when the Ebook is
+    paginated again, and updates the json related to the selected Ebook.
+    */
+    pub fn click_confirm_highlight_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        let selection = match data.selection.take() {
+            Some(selection) => selection,
+            None => return,
+        };
+        let page = match data.ebook.get(selection.page) {
+            Some(page) => page,
+            None => return,
+        };
+
+        let position = match data.sections.iter().find(|section| section.source == page.source) {
+            Some(section) => {
+                let start = section.text_positions(page.start, &[selection.start])[0];
+                locator_at(&page.source, &section.content, start)
+            }
+            None => return,
+        };
+
+        data.saves.highlights.push_back(Highlight {
+            position,
+            text: selection.text,
+            color: data.highlight_color,
+            note: data.string_note.trim().to_string(),
+        });
+        data.save_to_json();
+        data.string_note = String::new();
+    }
+
+    /*
+    This function shows or hides the library, listing the Ebooks found in the folders of the library.
+    The folders are scanned again every time the library is shown, so that the progress of the
+    Ebook just read is up to date.
+    */
+    pub fn click_library_button(ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.display_library = !data.display_library;
+        if data.display_library {
+            if data.ebook.len() > 0 {
+                data.save_position();
+            }
+            ctx.submit_command(SCAN_LIBRARY);
+        }
+    }
+
+    /*
+    This function opens the dialog choosing a folder to add to the library.
+    */
+    pub fn click_add_folder_button(ctx: &mut EventCtx, _data: &mut Self, _env: &Env) {
+        let options = FileDialogOptions::new()
+            .select_directories()
+            .title("Add a folder to the library")
+            .button_text("Add")
+            .accept_command(ADD_LIBRARY_FOLDER);
+
+        ctx.submit_command(commands::SHOW_OPEN_PANEL.with(options));
+    }
+
+    /*
+    This function scans again the folders of the library.
+    */
+    pub fn click_rescan_button(ctx: &mut EventCtx, _data: &mut Self, _env: &Env) {
+        ctx.submit_command(SCAN_LIBRARY);
+    }
+
+    /*
+    This function saves the folders of the library, printing the error if they can't be written.
+    */
+    fn save_library_folders(&self) {
+        let settings = LibrarySettings { folders: self.library_folders.clone() };
+        if let Err(error) = settings.write() {
+            println!("Error while saving the folders of the library: {}", error);
+        }
+    }
+
+    /*
+    This function opens the dialog choosing the file the bookmarks, the highlights and the notes of
+    the selected Ebook are exported to; the format follows the extension of the file.
+    It checks if the constraints are met or it will open a new MessageDialog.
+    */
+    pub fn click_export_button(ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        if data.ebook.len() == 0 {
+            MessageDialog::new()
+                .set_type(MessageType::Info)
+                .set_text("Please select an Ebook to enable this function.")
+                .set_title("Ebook not selected")
+                .show_alert().expect("Error while selecting an Ebook");
+        } else if data.loading {
+            MessageDialog::new()
+                .set_type(MessageType::Info)
+                .set_text("Please wait until the Ebook is loaded, so that every annotation can be found.")
+                .set_title("Ebook loading")
+                .show_alert().ok();
+        } else if data.saves.bookmarks.len() == 0 && data.saves.highlights.len() == 0 {
+            MessageDialog::new()
+                .set_type(MessageType::Info)
+                .set_text("There are no bookmarks or highlights to export for this Ebook.")
+                .set_title("Nothing to export")
+                .show_alert().ok();
+        } else {
+            let markdown = FileSpec::new("Markdown", &["md"]);
+            let json = FileSpec::new("JSON", &["json"]);
+            let csv = FileSpec::new("CSV", &["csv"]);
+
+            let options = FileDialogOptions::new()
+                .allowed_types(vec![markdown, json, csv])
+                .default_type(markdown)
+                .default_name(format!("{} - annotations.md", data.title))
+                .name_label("Target")
+                .title("Export the annotations")
+                .button_text("Export")
+                .accept_command(EXPORT_ANNOTATIONS);
+
+            ctx.submit_command(commands::SHOW_SAVE_PANEL.with(options));
+        }
+    }
+
+    /*
+    This function shows or hides the search bar. Hiding it removes the hits of the last search.
+    */
+    pub fn click_find_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.display_search = !data.display_search;
+        if !data.display_search {
+            data.search_hits.clear();
+            data.search_current = 0;
+            data.search_partial = false;
+            data.library_hits.clear();
+        }
+    }
+
+    /*
+    This function shows or hides the list of the Ebooks opened recently, checking which of them still
+    exist when it is shown.
+    */
+    pub fn click_recent_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.display_recent = !data.display_recent;
+        if data.display_recent {
+            check_recent(&mut data.recent_books);
+        }
+    }
+
+    /*
+    This function empties the list of the Ebooks opened recently.
+    */
+    pub fn click_clear_recent_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.recent_books.clear();
+        data.display_recent = false;
+        if let Err(error) = write_recent(&data.recent_books) {
+            println!("Error while saving the recent Ebooks: {}", error);
+        }
+    }
+
+    /*
+    This function switches to the next theme of the list.
+    */
+    pub fn click_theme_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        let current = data.themes.iter().position(|theme| theme.name == data.theme).unwrap_or(0);
+        if let Some(next) = data.themes.get((current + 1) % data.themes.len().max(1)) {
+            data.theme = next.name.clone();
+        }
+    }
+
+    /*
+    This function switches the way the illustrations are displayed with a dark theme.
+    */
+    pub fn click_image_filter_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.image_filter = data.image_filter.next();
+    }
+
+    /*
+    This function opens again the last Ebook read, at the last position read.
+    */
+    pub fn click_resume_button(ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        if let Some(book) = data.recent_books.front() {
+            ctx.submit_command(OPEN_PATH.with(book.path.clone()));
+        }
+    }
+
+    /*
+    This function hides the results of the last search in the library.
+    */
+    pub fn click_close_library_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.library_hits.clear();
+    }
+
+    /*
+    This function searches the text of the Ebook with the options chosen by the user, listing the
+    hits in the menu and moving to the first hit following the current page. The search runs in a
+    worker thread which sends the hits back with the command BOOK_SEARCHED.
+    With the Whole library option, the words of the query are searched in the index of all the
+    Ebooks opened so far and of the library folders instead, in a worker thread which sends the
+    results back with the command LIBRARY_SEARCHED.
+    It checks if the constraints are met or it will open a new MessageDialog.
+    */
+    pub fn click_search_button(ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        if data.search_options.library {
+            let query = data.search_query.clone();
+            let sink = ctx.get_external_handle();
+            std::thread::spawn(move || {
+                let hits: Vector<LibraryHit> = search_library(&query).into_iter().collect();
+                sink.submit_command(LIBRARY_SEARCHED, (query, hits), Target::Auto).ok();
+            });
+            return;
+        }
+        if data.ebook.len() == 0 {
+            MessageDialog::new()
+                .set_type(MessageType::Info)
+                .set_text("Please select an Ebook to enable this function.")
+                .set_title("Ebook not selected")
+                .show_alert().expect("Error while selecting an Ebook");
+            return;
+        }
+        if data.search_query.trim().len() == 0 {
+            data.search_hits.clear();
+            return;
+        }
+
+        let pattern = match build_pattern(&data.search_query, &data.search_options) {
+            Ok(pattern) => pattern,
+            Err(error) => {
+                MessageDialog::new()
+                    .set_type(MessageType::Warning)
+                    .set_text(format!("The regular expression isn't valid.\n{}", error).as_str())
+                    .set_title("Invalid regular expression")
+                    .show_alert().ok();
+                return;
+            }
+        };
+
+        data.search_partial = false;
+        data.search_book(ctx.get_external_handle(), pattern);
+    }
+
+    /*
+    This function searches the sections of the open Ebook in a worker thread, which sends the hits
+    back with the key of the Ebook and the query, so that the results of an older search are
+    ignored. While the Ebook is loading only the sections already read are searched.
+    */
+    fn search_book(&self, sink: ExtEventSink, pattern: Regex) {
+        let book = match self.book.clone() {
+            Some(book) => book,
+            None => return,
+        };
+        let (sections, query, partial) = (self.sections.clone(), self.search_query.clone(), self.loading);
+
+        std::thread::spawn(move || {
+            let hits: Vector<SearchHit> = search_sections(&book, &sections, &pattern).into_iter().collect();
+            sink.submit_command(BOOK_SEARCHED, (book.key.clone(), query, hits, partial), Target::Auto).ok();
+        });
+    }
+
+    /*
+    This function moves to the previous hit of the search, going back to the last one from the first.
+    */
+    pub fn click_previous_hit_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        if data.search_hits.len() > 0 {
+            let index = (data.search_current + data.search_hits.len() - 1) % data.search_hits.len();
+            data.go_to_hit(index);
+        }
+    }
+
+    /*
+    This function moves to the next hit of the search, going back to the first one from the last.
+    */
+    pub fn click_next_hit_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        if data.search_hits.len() > 0 {
+            let index = (data.search_current + 1) % data.search_hits.len();
+            data.go_to_hit(index);
+        }
+    }
+
+    /*
+    This function discards the text selected by the user and the note written for it.
+    */
+    pub fn click_reject_highlight_button(_ctx: &mut EventCtx, data: &mut Self, _env: &Env) {
+        data.selection = None;
+        data.string_note = String::new();
+    }
+
     /*
     This function creates a new json file related to the selected Ebook in order to save the last
-    page open and the bookmarks.
+    page open and the bookmarks. The file is stored in the data directory of the application and
+    named after the key of the Ebook, so that it doesn't depend on the name of the epub file. Nothing
+    is written when the file was saved by a newer version of the application, to avoid overwriting it.
     */
     pub fn save_to_json(&self) {
-        let serialized = serde_json::to_string(&self.saves).unwrap();
-
-        let filename = self.title.clone() + ".json";
-        let path = Path::new("\\Ebook_Reader\\Metadata\\");
+        if let Some(book) = &self.book {
+            self.save_to_key(&book.key);
+        }
+    }
 
-        std::fs::create_dir_all(path).unwrap();
+    /*
+    This function writes the saved data of the open Ebook in the json file of the given key, unless
+    the file was saved by a newer version of the application. It is also used to copy the data to
+    the key of an edited version of the Ebook, which doesn't have the same content.
+    */
+    fn save_to_key(&self, key: &str) {
+        if self.saves_read_only {
+            return;
+        }
+        let serialized = serde_json::to_string(&self.saves).unwrap();
+        let path = metadata_file(key);
 
-        let mut p = String::from(path.to_str().unwrap());
-        p.push_str(filename.as_str());
-        std::fs::write(p, serialized).unwrap();
+        if let Some(dir) = path.parent() {
+            if let Err(error) = fs::create_dir_all(dir) {
+                println!("Error while creating {}: {}", dir.display(), error);
+                return;
+            }
+        }
+        if let Err(error) = fs::write(&path, serialized) {
+            println!("Error while writing {}: {}", path.display(), error);
+        }
     }
 
     /*
     This function searches the json file related to the selected Ebook in order to load the last
-    page opened and the bookmarks.
+    page opened and the bookmarks. The files saved by the previous versions of the application were
+    named after the title of the Ebook (the name of its file): if there is no file for the key of the
+    Ebook, they are migrated to the new name.
     */
     pub fn load_from_json(&mut self) {
-        let mut path = String::from("\\Ebook_Reader\\Metadata\\");
-        path.push_str(self.title.as_str());
-        path.push_str(".json");
-        let file = File::open(path);
-
-        match file {
-            Ok(file) => {
-                let reader = BufReader::new(file);
-                let mut de = serde_json::Deserializer::from_reader(reader);
-                let u = JsonStruct::deserialize(&mut de).unwrap();
-
-                self.saves = u;
-                self.current_page = self.saves.last_page;
-
-                let new_page = self.current_page.to_string();
-                self.edit_current_page = new_page;
+        self.saves_read_only = false;
+        let key = match &self.book {
+            Some(book) => book.key.clone(),
+            None => return,
+        };
+        let path = metadata_file(&key);
+        let old_paths = [metadata_file(&self.title), legacy_metadata_file(&self.title)];
+
+        let (file_path, migrated) = if path.exists() {
+            (path.clone(), false)
+        } else {
+            match old_paths.iter().find(|old| old.exists()) {
+                Some(old) => (old.clone(), true),
+                None => return,
+            }
+        };
+
+        let saves = fs::read_to_string(&file_path)
+            .map_err(|e| SaveError::Invalid(e.to_string()))
+            .and_then(|text| JsonStruct::from_json(&text));
+
+        match saves {
+            Ok(saves) => {
+                self.saves = saves;
+
+                if migrated {
+                    self.save_to_json();
+                    if path.exists() {
+                        fs::remove_file(&file_path).ok();
+                    }
+                }
+            }
+            Err(SaveError::Newer(version)) => {
+                self.saves = JsonStruct::new();
+                self.saves_read_only = true;
+
+                MessageDialog::new()
+                    .set_type(MessageType::Warning)
+                    .set_text(format!("The bookmarks and the last page read of this Ebook were saved by a newer version of the application (format {}).\nThe file {} has been left untouched and nothing will be saved while this Ebook is open.", version, file_path.display()).as_str())
+                    .set_title("Saved data from a newer version")
+                    .show_alert().ok();
+            }
+            Err(error) => {
+                let backup = backup_file(&file_path);
+                self.saves = JsonStruct::new();
+
+                MessageDialog::new()
+                    .set_type(MessageType::Warning)
+                    .set_text(format!("The bookmarks and the last page read of this Ebook can't be read ({}), they have been reset.\nThe file has been moved to {}", error, backup.display()).as_str())
+                    .set_title("Saved data reset")
+                    .show_alert().ok();
             }
-            Err(_) => {}
         }
     }
 }
@@ -660,27 +1898,178 @@ pub fn doit(src_dir: &str, dst_file: &str, method: zip::CompressionMethod) -> zi
     Ok(())
 }
 
+/*
+This function checks if the href of a link points outside of the Ebook, as a web page or an e-mail
+address.
+*/
+pub fn is_external_link(href: &str) -> bool {
+    href.contains("://") || href.starts_with("mailto:") || href.starts_with("tel:")
+}
+
+/*
+This function opens a link pointing outside of the Ebook with the default application of the system.
+*/
+fn open_external_link(href: &str) {
+    let result = if cfg!(target_os = "windows") {
+        std::process::Command::new("cmd")
+            .args(["/C", "start", "", href])
+            .spawn()
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+            .arg(href)
+            .spawn()
+    } else {
+        std::process::Command::new("xdg-open")
+            .arg(href)
+            .spawn()
+    };
+
+    if let Err(error) = result {
+        println!("Unable to open {}: {}", href, error);
+    }
+}
+
 
 pub const GO_TO_POS: Selector<usize> = Selector::new("go_to_pos");
-pub const DELETE_BOOKMARK: Selector<(String, usize)> = Selector::new("delete_bookmark");
+pub const GO_TO_LOCATOR: Selector<Locator> = Selector::new("go_to_locator");
+pub const GO_TO_HIT: Selector<usize> = Selector::new("go_to_hit");
+pub const EXPORT_ANNOTATIONS: Selector<FileInfo> = Selector::new("export_annotations");
+pub const DELETE_HIGHLIGHT: Selector<Highlight> = Selector::new("delete_highlight");
+pub const OPEN_LIBRARY_HIT: Selector<LibraryHit> = Selector::new("open_library_hit");
+pub const OPEN_PATH: Selector<String> = Selector::new("open_path");
+pub const SCAN_LIBRARY: Selector = Selector::new("scan_library");
+pub const LIBRARY_SCANNED: Selector<Vector<LibraryBook>> = Selector::new("library_scanned");
+pub const LIBRARY_SEARCHED: Selector<(String, Vector<LibraryHit>)> = Selector::new("library_searched");
+pub const BOOK_SEARCHED: Selector<(String, String, Vector<SearchHit>, bool)> = Selector::new("book_searched");
+pub const ADD_LIBRARY_FOLDER: Selector<FileInfo> = Selector::new("add_library_folder");
+pub const REMOVE_LIBRARY_FOLDER: Selector<String> = Selector::new("remove_library_folder");
+pub const DELETE_BOOKMARK: Selector<(String, Locator)> = Selector::new("delete_bookmark");
 pub const MODIFY_EDIT_MODE: Selector<bool> = Selector::new("modify_edit_mode");
 pub const GO_TO_POS_FROM_EDIT: Selector<usize> = Selector::new("edit_current_page");
+pub const REPAGINATE: Selector = Selector::new("repaginate");
+pub const OPEN_LINK: Selector<(String, String, bool)> = Selector::new("open_link");
+pub const BOOK_OPENED: Selector<(usize, Arc<Book>)> = Selector::new("book_opened");
+pub const SECTIONS_LOADED: Selector<(usize, Vector<Section>, f64)> = Selector::new("sections_loaded");
+pub const LOADING_FINISHED: Selector<(usize, Vector<Chapter>, Vec<LoadError>)> = Selector::new("loading_finished");
+pub const LOADING_FAILED: Selector<(usize, LoadError)> = Selector::new("loading_failed");
+
+static NEXT_LOAD_ID: AtomicUsize = AtomicUsize::new(0);
+
+/*
+This function loads the epub file of the given task in a worker thread, sending every step of the
+loading back to the Delegate through the sink as a command carrying the id of the task.
+*/
+fn load_in_background(task: LoadTask, sink: ExtEventSink) {
+    std::thread::spawn(move || {
+        let id = task.id;
+        let result = load_epub(Path::new(&task.path), &task.cancel, |progress| {
+            let sent = match progress {
+                LoadProgress::Opened(book) => sink.submit_command(BOOK_OPENED, (id, book), Target::Auto),
+                LoadProgress::Sections(sections, fraction) => sink.submit_command(SECTIONS_LOADED, (id, sections, fraction), Target::Auto),
+                LoadProgress::Finished(chapters, errors) => sink.submit_command(LOADING_FINISHED, (id, chapters, errors), Target::Auto),
+            };
+            if sent.is_err() {
+                task.cancel.store(true, Ordering::Relaxed);
+            }
+        });
+
+        if let Err(error) = result {
+            sink.submit_command(LOADING_FAILED, (id, error), Target::Auto).ok();
+        }
+    });
+}
 
-pub struct Delegate;
+/*
+The Delegate keeps the sink used by the worker threads to send commands back to the application.
+*/
+pub struct Delegate {
+    sink: ExtEventSink,
+}
+
+impl Delegate {
+    pub fn new(sink: ExtEventSink) -> Self {
+        Self { sink }
+    }
+
+    /*
+    This function starts loading the epub file at the given path in a worker thread, cancelling the
+    loading in progress (if any). The Ebook is opened at the target, if given, instead of the last
+    position read.
+    */
+    fn open_book(&self, data: &mut AppState, path: String, target: Option<(String, usize)>) {
+        data.cancel_loading();
+
+        let task = LoadTask {
+            path,
+            id: NEXT_LOAD_ID.fetch_add(1, Ordering::Relaxed),
+            cancel: Arc::new(AtomicBool::new(false)),
+            opened: false,
+            resumed: false,
+            target,
+        };
+        data.loading = true;
+        data.loading_progress = 0.;
+        data.loading_task = Some(task.clone());
+        load_in_background(task, self.sink.clone());
+    }
+}
 
 /*
 The Delegate provides hooks for handling and modifying top-level events.
 - SAVE_FILE_AS: duplicates the selected Ebook, unzipping it and editing the file containing the
-current page edited by the user, zipping and converting into an epub file.
+current page edited by the user, zipping and converting into an epub file. The saved data of the
+Ebook are copied to the key of the new file, which is added to the index of the library in a worker
+thread.
 
 - OPEN_FILE (with scan_mode): given the path of the selected image by the user, it performs the
 following command "tesseract {path} stdout" collecting the result and searching through the open
 Ebook in the AppState giving a result based on the percentage of text matches.
 
-- OPEN_FILE (without scan_mode): given the path of the selected epub file, it opens it using
-EpubArchive crate and navigates through all its files filling the AppState variables related to the
-current Ebook. In particular, it fills the ebook variable page by page using the presence of tag
-<span class="x-ebookmaker-pageno" inside the HTML files.
+- OPEN_FILE (without scan_mode): given the path of the selected epub file, it starts loading it in a
+worker thread through the load_epub function, cancelling the loading in progress (if any). The
+worker follows the OPF spine and reports its progress with the following commands.
+
+- BOOK_SEARCHED: given the hits of a search in the open Ebook, it lists them in the menu and moves to
+the first one following the current page, unless the Ebook or the query have changed in the
+meantime. If the Ebook was still loading, the search is repeated when it is loaded, keeping the
+current hit and page.
+
+- LIBRARY_SEARCHED: given the results of a search in the library, it lists them above the page, unless
+the query has changed in the meantime; a dialog tells the user when nothing has been found.
+
+- OPEN_LIBRARY_HIT: given a result of the search in the library clicked by the user, it moves to the
+page of the result if its Ebook is open, otherwise it starts loading the Ebook as OPEN_FILE does,
+opening it at the result instead of the last position read.
+
+- OPEN_PATH: given the path of an Ebook clicked by the user in the library or in the list of the
+Ebooks opened recently, it starts loading it as OPEN_FILE does.
+
+- SCAN_LIBRARY: it searches the folders of the library for epub files in a worker thread, reading
+their title, author, language and cover and the progress saved for them; LIBRARY_SCANNED brings
+the Ebooks found back to the library. The same thread then adds the new or changed Ebooks to the
+index of the library, so that they can be searched without being opened. A scan requested while
+another one is running is ignored.
+
+- ADD_LIBRARY_FOLDER / REMOVE_LIBRARY_FOLDER: given the folder chosen by the user (or clicked in the
+list of the folders), it adds it to the library (or removes it), saving the folders and scanning
+them again.
+
+- BOOK_OPENED: the archive of the Ebook is open, the previous Ebook is replaced by the new one and
+the saved data of the new one are loaded. The Ebook is moved to the top of the list of the Ebooks
+opened recently. The archive stays open in the book variable, which decodes the pages when they
+are displayed.
+
+- SECTIONS_LOADED: a group of sections has been read, they are added to the sections variable in
+reading order and only they are split into pages, so that the first pages can be read while the
+rest is loading. The user is moved to the last position read as soon as it is available.
+
+- LOADING_FINISHED: the whole Ebook has been read, the chapters of its table of contents are set and
+the positions saved as page indices by the previous versions are converted into locators. If
+some chapters, images or stylesheets couldn't be read, a dialog lists them. The Ebook is added to
+the index of the library in a worker thread.
+
+- LOADING_FAILED: the epub file can't be opened (or has nothing to display), a dialog shows the
+reason and the part of the Ebook already displayed is closed.
 
 - GO_TO_POS_FROM_EDIT: it updates the AppState variable current_page when the user inserts a specific
 page inside the TextBox in the Page navigation section.
@@ -688,27 +2077,59 @@ page inside the TextBox in the Page navigation section.
 - MODIFY_EDIT_MODE: it updates the AppState variable edit_mode when the Undo button is clicked in
 the edit window in order to allow the user to interact again with the main window.
 
-- GO_TO_POS: it updates the AppState variable current_page when the user interacts with bookmarks
-and chapters in the menu section.
+- GO_TO_POS: it updates the AppState variable current_page when the user interacts with chapters in
+the menu section.
+
+- GO_TO_LOCATOR: it updates the AppState variable current_page when the user clicks on a bookmark in
+the menu section, finding the page containing the position of the bookmark.
 
 - DELETE_BOOKMARK: it deletes the selected bookmark in AppState and it also updates the json file
 related to the current Ebook.
+
+- GO_TO_HIT: it moves to the page of a hit of the search when the user clicks on it in the menu,
+making it the current hit.
+
+- EXPORT_ANNOTATIONS: given the path chosen by the user, it writes the bookmarks, the highlights and
+the notes of the current Ebook to a Markdown, JSON or CSV file depending on its extension (Markdown
+if it has none).
+
+- DELETE_HIGHLIGHT: it deletes the selected highlight in AppState and it also updates the json file
+related to the current Ebook.
+
+- REPAGINATE: it splits again the Ebook into pages when the font size or the size of the window
+change.
+
+- OPEN_LINK: given the file of the page and the href of the link clicked by the user, it opens the
+links pointing outside of the Ebook (web pages and e-mail addresses) with the default application
+of the system, while internal links move to the page of their target (or show the footnote they
+point to) through the follow_link function.
+
+When a window is closed the preferences are saved, so that they are kept when the application is
+closed; closing the edit window also gives back the control to the main window.
 */
 impl AppDelegate<AppState> for Delegate {
     fn window_removed(
         &mut self,
-        _: WindowId,
+        id: WindowId,
         data: &mut AppState,
         _: &Env,
         _: &mut DelegateCtx<'_>,
     ) {
-        data.edit_mode = false;
+        if data.preferences_window == Some(id) {
+            data.preferences_window = None;
+        } else {
+            data.edit_mode = false;
+        }
+
+        if let Err(error) = Preferences::from_state(data).write() {
+            println!("Error while saving the preferences: {}", error);
+        }
     }
 
 
     fn command(
         &mut self,
-        _ctx: &mut DelegateCtx,
+        ctx: &mut DelegateCtx,
         _target: Target,
         cmd: &Command,
         data: &mut AppState,
@@ -726,116 +2147,37 @@ impl AppDelegate<AppState> for Delegate {
                 fs::copy(data.file_info.clone(), Path::new(file_info.path().to_str().unwrap())).expect("Error while trying to copy file");
 
                 let path = PathBuf::from(file_info.path().to_str().unwrap());
-                let dest_path = Path::new("/Ebook_Reader/output/");
-
-                if Path::exists(dest_path){
-                    fs::remove_dir_all(dest_path).expect("Path not found");
-                }
 
-                fs::create_dir(dest_path).unwrap();
-                fs::create_dir(Path::new("/Ebook_Reader/output/META-INF")).unwrap();
-                fs::create_dir(Path::new("/Ebook_Reader/output/OEBPS")).unwrap();
+                /*
+                The Ebook is extracted into a temporary directory, removed when staging goes out of
+                scope.
+                */
+                let staging = tempfile::Builder::new().prefix("ebook-reader").tempdir().expect("Error while creating the temporary directory");
+                let dest_path = staging.path();
 
                 let fname = std::path::Path::new(&path);
                 let file = fs::File::open(&fname).unwrap();
 
                 let mut archive = zip::ZipArchive::new(file).unwrap();
 
-                let mut current_chapter = 0;
-                let mut start_page_chapter = 0;
-                let mut last_initial_page = 0;
-                let mut stop_page = 0;
-                let mut found_start_page = false;
-                let mut already_found = false;
-                let mut i_found = 0;
-                let mut next_stop_page = 0;
-
-                data.chapters.iter().enumerate().for_each(|(i, x)| {
-                    if i == i_found {
-                        next_stop_page = x.target_page;
-                    }
-
-                    if found_start_page && stop_page == 0 {
-                        stop_page = x.target_page;
-                        found_start_page = false;
-                    }
-                    if x.target_page > data.current_page && !already_found {
-                        current_chapter = i - 2;
-                        start_page_chapter = last_initial_page;
-                        stop_page = x.target_page;
-                        found_start_page = true;
-                        already_found = true;
-                        i_found = i + 1;
-
-                        if !data.ebook[start_page_chapter].text.contains("<?xml") {
-                            start_page_chapter -= 1;
-                        }
-                    } else if x.target_page == data.current_page && !already_found {
-                        current_chapter = i - 1;
-                        start_page_chapter = x.target_page;
-                        found_start_page = true;
-                        already_found = true;
-                        i_found = i + 1;
-
-                        if !data.ebook[start_page_chapter].text.contains("<?xml") {
-                            start_page_chapter -= 1;
-                        }
-                    }
-
-                    last_initial_page = x.target_page;
-                });
-
+                /*
+                The new version of the file containing the current page is the concatenation of all
+                the pages loaded from the same spine item, with the edited text in place of the
+                current page.
+                */
+                let file_to_edit = data.ebook[data.current_page].source.clone();
                 let mut new_content = String::new();
-                let mut next_content = String::new();
-                let mut one_page = false;
 
-                if start_page_chapter == (stop_page - 1) {
-                    one_page = true;
-                    new_content.push_str(data.current_page_text.as_str());
-                } else {
-                    for i in start_page_chapter..stop_page {
-                        if data.current_page == i {
-                            if i == start_page_chapter {
-                                next_content.push_str("\n");
-                                new_content.push_str(&data.current_page_text[data.current_page_text.find("<?xml").unwrap()..]);
-                            } else if i == stop_page - 1 {
-                                new_content.push_str(&data.current_page_text[..data.current_page_text.find("</html>").unwrap() + 7]);
-                                for j in i..next_stop_page {
-                                    if j == i {
-                                        next_content.push_str(&data.current_page_text[data.current_page_text.find("<?xml").unwrap()..]);
-                                        next_content.push_str("\n");
-                                    } else if j == next_stop_page - 1 {
-                                        next_content.push_str(&data.ebook[j].text[..data.ebook[j].text.find("</html>").unwrap() + 7]);
-                                    } else {
-                                        next_content.push_str(data.ebook[j].text.as_str());
-                                    }
-                                }
-                            } else {
-                                new_content.push_str(data.current_page_text.as_str());
-                            }
+                for (i, page) in data.ebook.iter().enumerate() {
+                    if page.source == file_to_edit {
+                        if i == data.current_page {
+                            new_content.push_str(data.current_page_text.as_str());
                         } else {
-                            if i == start_page_chapter {
-                                new_content.push_str(&data.ebook[i].text[data.ebook[i].text.find("<?xml").unwrap()..]);
-                            } else if i == stop_page - 1 {
-                                new_content.push_str(&data.ebook[i].text[..data.ebook[i].text.find("</html>").unwrap() + 7]);
-                            } else {
-                                if i == start_page_chapter + 1 {
-                                    new_content.push_str("\n");
-                                }
-                                new_content.push_str(data.ebook[i].text.as_str());
-                            }
+                            new_content.push_str(page.text.as_str());
                         }
                     }
                 }
 
-                let mut file_to_find = "h-".to_string();
-                file_to_find.push_str(current_chapter.to_string().as_str());
-                file_to_find.push_str(".htm.html");
-
-                let mut file_to_find_next = "h-".to_string();
-                file_to_find_next.push_str((current_chapter + 1).to_string().as_str());
-                file_to_find_next.push_str(".htm.html");
-
                 for i in 0..archive.len() {
                     let mut file = archive.by_index(i).unwrap();
                     let outpath = match file.enclosed_name() {
@@ -845,46 +2187,20 @@ impl AppDelegate<AppState> for Delegate {
 
 
                     if (file.name()).ends_with('/') {
-                        fs::create_dir_all(&outpath).unwrap();
+                        fs::create_dir_all(dest_path.join(&outpath)).unwrap();
                     } else {
-                        if file.name().contains(&file_to_find.clone()) {
-                            file_to_find = file.name().to_string();
-                        }
-
-                        if file.name().contains(&file_to_find_next.clone()) {
-                            file_to_find_next = file.name().to_string();
-                        }
-
-
                         if let Some(p) = outpath.parent() {
-                            if !p.exists() {
-                                let mut a = dest_path.to_str().unwrap().to_string();
-                                a.push_str(p.to_str().unwrap());
-                                fs::create_dir_all(a).unwrap();
-                            }
+                            fs::create_dir_all(dest_path.join(p)).unwrap();
                         }
-                        let mut a2 = dest_path.to_str().unwrap().to_string();
-                        a2.push_str(outpath.to_str().unwrap());
-                        let mut outfile = fs::File::create(a2).unwrap();
+                        let mut outfile = fs::File::create(dest_path.join(&outpath)).unwrap();
                         io::copy(&mut file, &mut outfile).unwrap();
                     }
                 }
 
-                if data.current_page == stop_page - 1 && !one_page {
-                    let mut file_to_edit = dest_path.to_str().unwrap().to_string();
-                    file_to_edit.push_str(file_to_find_next.as_str());
-                    File::create(file_to_edit.clone()).expect("Error while trying to create file to be edited");
-
-                    let mut f2 = std::fs::OpenOptions::new().write(true).truncate(true).open(file_to_edit).unwrap();
-                    f2.write_all(next_content.as_bytes()).unwrap();
-                    f2.flush().unwrap();
-                }
-
-                let mut file_to_edit = dest_path.to_str().unwrap().to_string();
-                file_to_edit.push_str(file_to_find.as_str());
-                File::create(file_to_edit.clone()).expect("Error while trying to create file to be edited");
+                let path_to_edit = dest_path.join(file_to_edit.as_str());
+                File::create(path_to_edit.clone()).expect("Error while trying to create file to be edited");
 
-                let mut f2 = std::fs::OpenOptions::new().write(true).truncate(true).open(file_to_edit).unwrap();
+                let mut f2 = std::fs::OpenOptions::new().write(true).truncate(true).open(path_to_edit).unwrap();
 
                 f2.write_all(new_content.as_bytes()).unwrap();
                 f2.flush().unwrap();
@@ -893,6 +2209,22 @@ impl AppDelegate<AppState> for Delegate {
                 let mut str = "File correctly saved at: ".to_string();
                 str.push_str(file_info.path().to_str().unwrap());
 
+                /*
+                The edited version has its own key, so the position and the bookmarks of the Ebook
+                are saved again under it.
+                */
+                match EpubDoc::new(&path) {
+                    Ok(doc) => data.save_to_key(&book_key(&path, &doc)),
+                    Err(error) => println!("Error while reading {}: {}", path.display(), error),
+                }
+
+                let saved = path.clone();
+                std::thread::spawn(move || {
+                    if let Err(error) = index_epub_file(&saved) {
+                        println!("Error while indexing {}: {}", saved.display(), error);
+                    }
+                });
+
 
                 MessageDialog::new()
                     .set_type(MessageType::Info)
@@ -901,9 +2233,6 @@ impl AppDelegate<AppState> for Delegate {
                     .reset_owner()
                     .show_alert()
                     .expect("Failed");
-
-
-                fs::remove_dir_all(dest_path).expect("Error while trying to remove directory");
             }
         }
 
@@ -995,6 +2324,26 @@ impl AppDelegate<AppState> for Delegate {
             data.edit_current_page = new_page;
         }
 
+        if let Some(locator) = cmd.get(GO_TO_LOCATOR) {
+            if let Some(page) = data.page_of_locator(locator) {
+                data.current_page = page;
+                data.edit_current_page = page.to_string();
+            }
+        }
+
+        if cmd.is(REPAGINATE) {
+            data.repaginate();
+        }
+
+        if let Some((source, href, noteref)) = cmd.get(OPEN_LINK) {
+            if is_external_link(href) {
+                open_external_link(href);
+            } else {
+                data.follow_link(source, href, *noteref);
+            }
+            return Handled::Yes;
+        }
+
         if cmd.is(DELETE_BOOKMARK) {
             let pos = cmd.get_unchecked(DELETE_BOOKMARK);
             let mut i = 0;
@@ -1010,6 +2359,38 @@ impl AppDelegate<AppState> for Delegate {
             data.save_to_json();
         }
 
+        if let Some(index) = cmd.get(GO_TO_HIT) {
+            data.go_to_hit(*index);
+            return Handled::Yes;
+        }
+
+        if let Some(file_info) = cmd.get(EXPORT_ANNOTATIONS) {
+            let mut path = file_info.path().to_path_buf();
+            let format = match ExportFormat::from_path(&path) {
+                Some(format) => format,
+                None => {
+                    path.set_extension("md");
+                    ExportFormat::Markdown
+                }
+            };
+
+            if let Err(error) = export_annotations(data, &path, format) {
+                MessageDialog::new()
+                    .set_type(MessageType::Error)
+                    .set_text(format!("Unable to export the annotations to {}.\n{}", path.display(), error).as_str())
+                    .set_title("Error while exporting the annotations")
+                    .show_alert().ok();
+            }
+            return Handled::Yes;
+        }
+
+        if let Some(highlight) = cmd.get(DELETE_HIGHLIGHT) {
+            if let Some(i) = data.saves.highlights.index_of(highlight) {
+                data.saves.highlights.remove(i);
+                data.save_to_json();
+            }
+        }
+
 
         if !data.scan_mode {
             if let Some(file_info) = cmd.get(commands::OPEN_FILE) {
@@ -1022,381 +2403,320 @@ impl AppDelegate<AppState> for Delegate {
                     return Handled::Yes;
                 }
 
-                data.file_info = file_info.clone().path().to_str().unwrap().to_string();
-                match EpubArchive::new(file_info.clone().path())
-                {
-                    Ok(mut archive) => {
-                        if data.ebook.len() > 0 {
-                            data.saves.last_page = data.current_page;
-                            data.save_to_json();
-                        }
+                self.open_book(data, file_info.path().to_str().unwrap().to_string(), None);
+                return Handled::Yes;
+            }
+        }
 
-                        data.ebook.clear();
-                        data.current_page = 0;
-                        data.edit_current_page = String::from("0");
-                        data.title = file_info.clone().path().to_str().unwrap().split("\\")
-                            .last().unwrap().split(".")
-                            .next().unwrap().to_string();
-                        data.saves.last_page = 0;
-                        data.saves.bookmarks.clear();
-                        data.display_menu = false;
-                        data.edit_mode = false;
-                        data.new_bookmark = false;
-                        data.chapters.clear();
-
-                        data.load_from_json();
-
-                        let mut page_no = 1;
-                        let mut page_not_ended = false;
-                        let mut chapter_title: String = String::new();
-                        let mut past_page_no = 0;
-
-
-                        alphanumeric_sort::sort_path_slice(&mut archive.files);
-
-                        for f in archive.files.clone() {
-                            if f.contains("OEBPS") && (f.contains("htm.html") || f.contains("wrap")) {
-                                data.ebook.push_back(Page::new());
-                                if f.contains("wrap") {
-                                    past_page_no = page_no;
-                                    page_no = 0;
-                                } else {
-                                    data.ebook.push_back(Page::new());
-                                }
+        if let Some(path) = cmd.get(OPEN_PATH) {
+            if data.edit_mode {
+                MessageDialog::new()
+                    .set_type(MessageType::Warning)
+                    .set_text("There is an Ebook open in edit mode, close that window to use again this function.")
+                    .set_title("Ebook in edit mode")
+                    .show_alert().expect("Error while selecting an Ebook but the app is in EDIT MODE");
+                return Handled::Yes;
+            }
 
-                                let res = archive.get_entry_as_str(f.clone());
-
-                                if res.is_ok() {
-                                    let init = res.as_ref().unwrap().find("<?xml");
-
-                                    if res.as_ref().unwrap()[init.unwrap()..].contains("class=\"x-ebookmaker-cover\"") {
-                                        chapter_title = "COVER".to_string();
-                                    } else if res.as_ref().unwrap()[init.unwrap()..].contains("START OF THIS PROJECT GUTENBERG EBOOK") {
-                                        chapter_title = "START OF THIS PROJECT GUTENBERG EBOOK".to_string();
-                                    } else if res.as_ref().unwrap()[init.unwrap()..].contains("END OF THIS PROJECT GUTENBERG EBOOK") {
-                                        chapter_title = "END OF THIS PROJECT GUTENBERG EBOOK".to_string();
-                                    } else if res.as_ref().unwrap()[init.unwrap()..].contains("CONTENTS") {
-                                        chapter_title = "CONTENTS".to_string();
-                                    } else if res.as_ref().unwrap()[init.unwrap()..].contains("pgepubid00000") {
-                                        let inizio = res.as_ref().unwrap().find("<title>").unwrap();
-                                        let fine = res.as_ref().unwrap().find("</title>").unwrap();
-                                        chapter_title = strip_tags(&res.as_ref().unwrap()[inizio..fine].replace("\n", "").trim_start().trim_end());
-                                    } else if res.as_ref().unwrap()[init.unwrap()..].contains("PREFACE") {
-                                        chapter_title = "PREFACE".to_string();
-                                    } else if res.as_ref().unwrap()[init.unwrap()..].contains("ILLUSTRATIONS") {
-                                        chapter_title = "ILLUSTRATIONS".to_string();
-                                    } else if res.as_ref().unwrap().find("<div class=\"chapter\"").is_none() {
-                                        chapter_title = "POSTFACE".to_string();
-                                    } else {
-                                        chapter_title = strip_tags(&res.as_ref().unwrap()[res.as_ref().unwrap().find("<div class=\"chapter\"").unwrap()..res.as_ref().unwrap().find("</div>").unwrap()])
-                                            .replace("\n", " ").trim_start().trim_end().to_string();
-                                    }
-
-                                    if chapter_title.eq("COVER") {
-                                        data.chapters.push_front(Chapter::from(chapter_title, page_no));
-                                    } else {
-                                        data.chapters.push_back(Chapter::from(chapter_title, page_no));
-                                    }
-
-
-                                    let page_occ = res.as_ref().unwrap()[init.unwrap()..].matches("<span class=\"x-ebookmaker-pageno\"").count();
-
-                                    if page_occ > 0 {
-                                        let mut pos_pageno = res.as_ref().unwrap()[init.unwrap()..].find("<span class=\"x-ebookmaker-pageno\"").unwrap();
-                                        if page_not_ended {
-                                            pos_pageno += res.as_ref().unwrap()[init.unwrap() + pos_pageno..].find("</span>").unwrap() + 7;
-                                        }else{
-                                            pos_pageno += res.as_ref().unwrap()[init.unwrap() + pos_pageno..].find("</span>").unwrap() + 6;
-                                        }
-
-                                        let mut text = res.as_ref().unwrap()[init.unwrap()..pos_pageno].to_string().clone();
-                                        let mut img_occ = text.matches("<img").count();
-                                        let mut pos = text.find("<img");
-                                        if img_occ > 0 {
-                                            if pos.is_some() {
-                                                let mut displacement: usize = 0;
-                                                for _ in 0..img_occ {
-                                                    let mut s1 = String::from("OEBPS/");
-                                                    let app = text[pos.unwrap() + 3 + displacement..].find("src=");
-                                                    for c in text[pos.unwrap() + 3 + app.unwrap() + 5 + displacement..].chars() {
-                                                        if c == '"' {
-                                                            break;
-                                                        } else {
-                                                            s1.push(c);
-                                                        }
-                                                    }
-                                                    let (width, height) = match blob_size(archive.get_entry(s1.clone()).unwrap().as_slice()) {
-                                                        Ok(dim) => { (dim.width, dim.height) }
-                                                        Err(why) => {
-                                                            println!("Error getting dimensions: {:?}", why);
-                                                            (0, 0)
-                                                        }
-                                                    };
-
-                                                    let r;
-
-                                                    if s1.to_lowercase().contains("jpg") || s1.to_lowercase().contains("jpeg") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Jpeg).unwrap();
-                                                    } else if s1.to_lowercase().contains("png") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Png).unwrap();
-                                                    } else if s1.to_lowercase().contains("gif") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Gif).unwrap();
-                                                    } else if s1.to_lowercase().contains("webp") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::WebP).unwrap();
-                                                    } else if s1.to_lowercase().contains("pnm") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Pnm).unwrap();
-                                                    } else if s1.to_lowercase().contains("tiff") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Tiff).unwrap();
-                                                    } else if s1.to_lowercase().contains("tga") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Tga).unwrap();
-                                                    } else if s1.to_lowercase().contains("bmp") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Bmp).unwrap();
-                                                    } else if s1.to_lowercase().contains("ico") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Ico).unwrap();
-                                                    } else if s1.to_lowercase().contains("hdr") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Hdr).unwrap();
-                                                    } else {
-                                                        panic!("Formato non supportato");
-                                                    }
-                                                    let result = r.into_bytes();
-                                                    if page_not_ended {
-                                                        data.ebook[page_no - 1].images.push_back(ImageOfPage::from(Vector::from(result), width, height));
-                                                    } else {
-                                                        data.ebook[page_no].images.push_back(ImageOfPage::from(Vector::from(result), width, height));
-                                                    }
-                                                    let resapp = text[pos.unwrap() + 3 + app.unwrap() + 5 + displacement..].find("<img");
-                                                    displacement = pos.unwrap() + 3 + app.unwrap() + 5 + displacement;
-                                                    pos = resapp;
-                                                }
-                                            }
-                                        }
-
-                                        if !page_not_ended {
-                                            data.ebook[page_no].text.push_str(text.as_str());
-                                            data.ebook.push_back(Page::new());
-                                            page_no += 1;
-                                        } else {
-                                            data.ebook[page_no - 1].text.push_str(text.as_str());
-                                            page_not_ended = false;
-                                        }
-
-                                        pos_pageno += 1;
-
-                                        for _ in 1..page_occ {
-                                            let mut next_page = res.as_ref().unwrap()[init.unwrap() + pos_pageno..].find("<span class=\"x-ebookmaker-pageno\"").unwrap();
-                                            next_page += res.as_ref().unwrap()[init.unwrap() + pos_pageno + next_page..].find("</span>").unwrap() + 6;
-                                            let text = res.as_ref().unwrap()[init.unwrap() + pos_pageno..init.unwrap() + pos_pageno + next_page + 1].to_string().clone();
-                                            let img_occ = text.matches("<img").count();
-                                            let mut pos = text.find("<img");
-                                            if img_occ > 0 {
-                                                if pos.is_some() {
-                                                    let mut displacement: usize = 0;
-                                                    for _ in 0..img_occ {
-                                                        let mut s1 = String::from("OEBPS/");
-                                                        let app = text[pos.unwrap() + 3 + displacement..].find("src=");
-                                                        for c in text[pos.unwrap() + 3 + app.unwrap() + 5 + displacement..].chars() {
-                                                            if c == '"' {
-                                                                break;
-                                                            } else {
-                                                                s1.push(c);
-                                                            }
-                                                        }
-                                                        let (width, height) = match blob_size(archive.get_entry(s1.clone()).unwrap().as_slice()) {
-                                                            Ok(dim) => { (dim.width, dim.height) }
-                                                            Err(why) => {
-                                                                println!("Error getting dimensions: {:?}", why);
-                                                                (0, 0)
-                                                            }
-                                                        };
-
-                                                        let r;
-
-                                                        if s1.to_lowercase().contains("jpg") || s1.to_lowercase().contains("jpeg") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Jpeg).unwrap();
-                                                        } else if s1.to_lowercase().contains("png") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Png).unwrap();
-                                                        } else if s1.to_lowercase().contains("gif") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Gif).unwrap();
-                                                        } else if s1.to_lowercase().contains("webp") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::WebP).unwrap();
-                                                        } else if s1.to_lowercase().contains("pnm") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Pnm).unwrap();
-                                                        } else if s1.to_lowercase().contains("tiff") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Tiff).unwrap();
-                                                        } else if s1.to_lowercase().contains("tga") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Tga).unwrap();
-                                                        } else if s1.to_lowercase().contains("bmp") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Bmp).unwrap();
-                                                        } else if s1.to_lowercase().contains("ico") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Ico).unwrap();
-                                                        } else if s1.to_lowercase().contains("hdr") {
-                                                            r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Hdr).unwrap();
-                                                        } else {
-                                                            panic!("Formato non supportato");
-                                                        }
-                                                        let result = r.into_bytes();
-                                                        if page_not_ended {
-                                                            data.ebook[page_no - 1].images.push_back(ImageOfPage::from(Vector::from(result), width, height));
-                                                        } else {
-                                                            data.ebook[page_no].images.push_back(ImageOfPage::from(Vector::from(result), width, height));
-                                                        }
-                                                        let resapp = text[pos.unwrap() + 3 + app.unwrap() + 5 + displacement..].find("<img");
-                                                        displacement = pos.unwrap() + 3 + app.unwrap() + 5 + displacement;
-                                                        pos = resapp;
-                                                    }
-                                                }
-                                            }
-
-                                            data.ebook[page_no].text.push_str(text.as_str());
-                                            data.ebook.push_back(Page::new());
-                                            page_no += 1;
-                                            pos_pageno += next_page + 1;
-                                        }
-
-                                        page_not_ended = true;
-                                        text = res.as_ref().unwrap()[init.unwrap() + pos_pageno..res.as_ref().unwrap().len()].to_string().clone();
-                                        img_occ = text.matches("<img").count();
-                                        pos = text.find("<img");
-
-                                        if img_occ > 0 {
-                                            if pos.is_some() {
-                                                let mut displacement: usize = 0;
-                                                for _ in 0..img_occ {
-                                                    let mut s1 = String::from("OEBPS/");
-                                                    let app = text[pos.unwrap() + 3 + displacement..].find("src=");
-                                                    for c in text[pos.unwrap() + 3 + app.unwrap() + 5 + displacement..].chars() {
-                                                        if c == '"' {
-                                                            break;
-                                                        } else {
-                                                            s1.push(c);
-                                                        }
-                                                    }
-                                                    let (width, height) = match blob_size(archive.get_entry(s1.clone()).unwrap().as_slice()) {
-                                                        Ok(dim) => { (dim.width, dim.height) }
-                                                        Err(why) => {
-                                                            println!("Error getting dimensions: {:?}", why);
-                                                            (0, 0)
-                                                        }
-                                                    };
-
-                                                    let r;
-
-                                                    if s1.to_lowercase().contains("jpg") || s1.to_lowercase().contains("jpeg") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Jpeg).unwrap();
-                                                    } else if s1.to_lowercase().contains("png") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Png).unwrap();
-                                                    } else if s1.to_lowercase().contains("gif") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Gif).unwrap();
-                                                    } else if s1.to_lowercase().contains("webp") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::WebP).unwrap();
-                                                    } else if s1.to_lowercase().contains("pnm") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Pnm).unwrap();
-                                                    } else if s1.to_lowercase().contains("tiff") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Tiff).unwrap();
-                                                    } else if s1.to_lowercase().contains("tga") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Tga).unwrap();
-                                                    } else if s1.to_lowercase().contains("bmp") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Bmp).unwrap();
-                                                    } else if s1.to_lowercase().contains("ico") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Ico).unwrap();
-                                                    } else if s1.to_lowercase().contains("hdr") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Hdr).unwrap();
-                                                    } else {
-                                                        panic!("Formato non supportato");
-                                                    }
-                                                    let result = r.into_bytes();
-                                                    data.ebook[page_no].images.push_back(ImageOfPage::from(Vector::from(result), width, height));
-                                                    let resapp = text[pos.unwrap() + 3 + app.unwrap() + 5 + displacement..].find("<img");
-                                                    displacement = pos.unwrap() + 3 + app.unwrap() + 5 + displacement;
-                                                    pos = resapp;
-                                                }
-                                            }
-                                        }
-
-                                        data.ebook[page_no].text.push_str(text.as_str());
-
-                                        data.ebook.push_back(Page::new());
-                                        page_no += 1;
-                                    } else {
-                                        page_not_ended = false;
-                                        let img_occ = res.as_ref().unwrap()[init.unwrap()..].matches("<img").count();
-                                        let mut pos = res.as_ref().unwrap()[init.unwrap()..].find("<img");
-                                        if img_occ > 0 {
-                                            if pos.is_some() {
-                                                let mut displacement: usize = 0;
-                                                for _ in 0..img_occ {
-                                                    let mut s1 = String::from("OEBPS/");
-                                                    let app = res.as_ref().unwrap()[pos.unwrap() + 3 + displacement..].find("src=");
-                                                    for c in res.as_ref().unwrap()[pos.unwrap() + 3 + app.unwrap() + 5 + displacement..].chars() {
-                                                        if c == '"' {
-                                                            break;
-                                                        } else {
-                                                            s1.push(c);
-                                                        }
-                                                    }
-                                                    let (width, height) = match blob_size(archive.get_entry(s1.clone()).unwrap().as_slice()) {
-                                                        Ok(dim) => { (dim.width, dim.height) }
-                                                        Err(why) => {
-                                                            println!("Error getting dimensions: {:?}", why);
-                                                            (0, 0)
-                                                        }
-                                                    };
-
-                                                    let r;
-                                                    if s1.to_lowercase().contains("jpg") || s1.to_lowercase().contains("jpeg") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Jpeg).unwrap();
-                                                    } else if s1.to_lowercase().contains("png") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Png).unwrap();
-                                                    } else if s1.to_lowercase().contains("gif") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Gif).unwrap();
-                                                    } else if s1.to_lowercase().contains("webp") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::WebP).unwrap();
-                                                    } else if s1.to_lowercase().contains("pnm") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Pnm).unwrap();
-                                                    } else if s1.to_lowercase().contains("tiff") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Tiff).unwrap();
-                                                    } else if s1.to_lowercase().contains("tga") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Tga).unwrap();
-                                                    } else if s1.to_lowercase().contains("bmp") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Bmp).unwrap();
-                                                    } else if s1.to_lowercase().contains("ico") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Ico).unwrap();
-                                                    } else if s1.to_lowercase().contains("hdr") {
-                                                        r = image::load_from_memory_with_format(archive.get_entry(s1.clone()).unwrap().as_slice(), image::ImageFormat::Hdr).unwrap();
-                                                    } else {
-                                                        panic!("Formato non supportato");
-                                                    }
-                                                    let result = r.into_bytes();
-                                                    data.ebook[page_no].images.push_back(ImageOfPage::from(Vector::from(result), width, height));
-                                                    let resapp = res.as_ref().unwrap()[pos.unwrap() + 3 + app.unwrap() + 5 + displacement..].find("<img");
-                                                    displacement = pos.unwrap() + 3 + app.unwrap() + 5 + displacement;
-                                                    pos = resapp;
-                                                }
-                                            }
-                                        }
-                                        data.ebook[page_no].text.push_str(res.as_ref().unwrap()[init.unwrap()..res.as_ref().unwrap().len()].to_string().clone().as_str());
-
-                                        page_no += 1;
-                                    }
-                                }
-                                if f.contains("wrap") {
-                                    page_no = past_page_no;
-                                }
-                            }
-                        }
+            self.open_book(data, path.clone(), None);
+            return Handled::Yes;
+        }
 
-                        while data.ebook.last().unwrap().text.len() == 0 {
-                            data.ebook.pop_back();
-                        }
-                    }
-                    Err(error) => {
-                        println!("Error while opening archive: {}", error);
+        if cmd.is(SCAN_LIBRARY) {
+            if !data.library_scanning {
+                data.library_scanning = true;
+                let folders = data.library_folders.clone();
+                let sink = self.sink.clone();
+                std::thread::spawn(move || {
+                    let books: Vector<LibraryBook> = scan_library(&folders).into_iter().collect();
+                    let paths: Vec<String> = books.iter().map(|book| book.path.clone()).collect();
+                    sink.submit_command(LIBRARY_SCANNED, books, Target::Auto).ok();
+                    index_library(&paths);
+                });
+            }
+            return Handled::Yes;
+        }
+
+        if let Some((key, query, hits, partial)) = cmd.get(BOOK_SEARCHED) {
+            if data.book.as_ref().map(|book| &book.key) != Some(key) || *query != data.search_query {
+                return Handled::Yes;
+            }
+
+            let repeated = data.search_partial;
+            let current = data.search_hits.get(data.search_current).map(|hit| (hit.source.clone(), hit.offset));
+            data.search_hits = hits.clone();
+            data.search_current = 0;
+            data.search_partial = *partial;
+            data.locate_search_hits();
+
+            if repeated {
+                data.search_current = current
+                    .and_then(|(source, offset)| data.search_hits.iter().position(|hit| hit.source == source && hit.offset == offset))
+                    .unwrap_or(0);
+                return Handled::Yes;
+            }
+            if hits.len() == 0 {
+                let text = if *partial {
+                    format!("\"{}\" wasn't found in the part of the Ebook loaded so far, the search will be repeated when the Ebook is loaded.", query)
+                } else {
+                    format!("\"{}\" wasn't found in the Ebook.", query)
+                };
+                MessageDialog::new()
+                    .set_type(MessageType::Info)
+                    .set_text(text.as_str())
+                    .set_title("No results")
+                    .show_alert().ok();
+                return Handled::Yes;
+            }
+            if hits.len() >= MAX_HITS {
+                MessageDialog::new()
+                    .set_type(MessageType::Info)
+                    .set_text(format!("Only the first {} results are shown.", MAX_HITS).as_str())
+                    .set_title("Too many results")
+                    .show_alert().ok();
+            }
+
+            data.display_menu = true;
+            let first = data.search_hits.iter().position(|hit| hit.page >= data.current_page).unwrap_or(0);
+            data.go_to_hit(first);
+            return Handled::Yes;
+        }
+
+        if let Some((query, hits)) = cmd.get(LIBRARY_SEARCHED) {
+            if *query != data.search_query {
+                return Handled::Yes;
+            }
+            data.library_hits = hits.clone();
+
+            if hits.len() == 0 && query.trim().len() > 0 {
+                MessageDialog::new()
+                    .set_type(MessageType::Info)
+                    .set_text(format!("\"{}\" wasn't found in the library.", query).as_str())
+                    .set_title("No results")
+                    .show_alert().ok();
+            }
+            return Handled::Yes;
+        }
+
+        if let Some(books) = cmd.get(LIBRARY_SCANNED) {
+            data.library_books = books.clone();
+            data.library_scanning = false;
+            return Handled::Yes;
+        }
+
+        if let Some(file_info) = cmd.get(ADD_LIBRARY_FOLDER) {
+            let folder = file_info.path().to_string_lossy().to_string();
+            if !data.library_folders.contains(&folder) {
+                data.library_folders.push_back(folder);
+                data.save_library_folders();
+                ctx.submit_command(SCAN_LIBRARY);
+            }
+            return Handled::Yes;
+        }
+
+        if let Some(folder) = cmd.get(REMOVE_LIBRARY_FOLDER) {
+            data.library_folders.retain(|other| other != folder);
+            data.save_library_folders();
+            ctx.submit_command(SCAN_LIBRARY);
+            return Handled::Yes;
+        }
+
+        if let Some(hit) = cmd.get(OPEN_LIBRARY_HIT) {
+            if data.edit_mode {
+                MessageDialog::new()
+                    .set_type(MessageType::Warning)
+                    .set_text("There is an Ebook open in edit mode, close that window to use again this function.")
+                    .set_title("Ebook in edit mode")
+                    .show_alert().expect("Error while selecting an Ebook but the app is in EDIT MODE");
+                return Handled::Yes;
+            }
+
+            if data.file_info == hit.path && data.loading_task.is_none() {
+                if let Some(page) = data.page_of_text(&hit.source, hit.chars) {
+                    data.current_page = page;
+                    data.edit_current_page = page.to_string();
+                }
+            } else {
+                if data.ebook.len() > 0 {
+                    data.save_position();
+                }
+                self.open_book(data, hit.path.clone(), Some((hit.source.clone(), hit.chars)));
+            }
+            return Handled::Yes;
+        }
+
+        if let Some((id, book)) = cmd.get(BOOK_OPENED) {
+            let path = match data.loading_task.as_mut() {
+                Some(task) if task.id == *id => {
+                    task.opened = true;
+                    task.path.clone()
+                }
+                _ => return Handled::Yes,
+            };
+
+            if data.ebook.len() > 0 {
+                data.save_position();
+            }
+
+            data.close_book();
+            data.file_info = path.clone();
+            data.title = Path::new(&path).file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            data.display_library = false;
+            data.display_recent = false;
+            data.edit_mode = false;
+            data.new_bookmark = false;
+            data.book = Some(book.clone());
+
+            add_recent(&mut data.recent_books, &path, &data.title);
+            if let Err(error) = write_recent(&data.recent_books) {
+                println!("Error while saving the recent Ebooks: {}", error);
+            }
+
+            data.load_from_json();
+            data.current_page = 0;
+            data.edit_current_page = String::from("0");
+            return Handled::Yes;
+        }
+
+        if let Some((id, sections, fraction)) = cmd.get(SECTIONS_LOADED) {
+            if data.loading_task.as_ref().map(|task| task.id) != Some(*id) {
+                return Handled::Yes;
+            }
+
+            /*
+            The user stays on the first page until the last position read (or the target of the
+            loading) is loaded, unless they moved to another page in the meantime.
+            */
+            let waiting = data.current_page == 0 && !data.loading_task.as_ref().map(|task| task.resumed).unwrap_or(true);
+            let target = data.loading_task.as_ref().and_then(|task| task.target.clone());
+
+            data.append_sections(sections);
+            data.loading_progress = *fraction;
+
+            let page = match (waiting, target) {
+                (false, _) => None,
+                (true, Some((source, chars))) => data.page_of_text(&source, chars),
+                (true, None) => data.page_of_locator(&data.saves.position),
+            };
+            if let Some(page) = page {
+                data.current_page = page;
+                data.edit_current_page = page.to_string();
+            }
+            if !waiting || page.is_some() {
+                if let Some(task) = data.loading_task.as_mut() {
+                    task.resumed = true;
+                }
+            }
+            return Handled::Yes;
+        }
+
+        if let Some((id, chapters, errors)) = cmd.get(LOADING_FINISHED) {
+            if data.loading_task.as_ref().map(|task| task.id) != Some(*id) {
+                return Handled::Yes;
+            }
+
+            data.chapters = chapters.clone();
+            locate_chapters(&mut data.chapters, &data.ebook, 0);
+            data.resolve_page_locators();
+            data.loading = false;
+            data.loading_task = None;
+
+            if data.search_partial && data.display_search {
+                if let Ok(pattern) = build_pattern(&data.search_query, &data.search_options) {
+                    data.search_book(self.sink.clone(), pattern);
+                }
+            }
+
+            /*
+            The Ebook is added to the index of the library in a worker thread; an Ebook already
+            indexed is skipped.
+            */
+            if let Some(book) = data.book.as_ref() {
+                let (key, path, title) = (book.key.clone(), data.file_info.clone(), data.title.clone());
+                let (sections, chapters) = (data.sections.clone(), data.chapters.clone());
+                std::thread::spawn(move || {
+                    if let Err(error) = index_book(&key, &path, &title, &sections, &chapters) {
+                        println!("Error while indexing {}: {}", path, error);
                     }
+                });
+            }
+
+            if errors.len() > 0 {
+                let mut text = String::from("Some parts of the Ebook can't be displayed and have been skipped:");
+                for error in errors.iter().take(10) {
+                    text.push_str(format!("\n- {}", error).as_str());
                 }
+                if errors.len() > 10 {
+                    text.push_str(format!("\n... and {} more", errors.len() - 10).as_str());
+                }
+
+                MessageDialog::new()
+                    .set_type(MessageType::Warning)
+                    .set_text(text.as_str())
+                    .set_title("Ebook partially loaded")
+                    .show_alert().ok();
+            }
+            return Handled::Yes;
+        }
+
+        if let Some((id, error)) = cmd.get(LOADING_FAILED) {
+            if data.loading_task.as_ref().map(|task| task.id) != Some(*id) {
                 return Handled::Yes;
             }
+
+            if data.loading_task.as_ref().map(|task| task.opened).unwrap_or(false) {
+                data.close_book();
+            }
+            data.loading = false;
+            data.loading_task = None;
+
+            MessageDialog::new()
+                .set_type(MessageType::Error)
+                .set_text(format!("Unable to open the Ebook.\n{}", error).as_str())
+                .set_title("Error while opening the Ebook")
+                .show_alert().ok();
+            return Handled::Yes;
         }
         Handled::No
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_v1_to_v3() {
+        let saves = JsonStruct::from_json(r#"{"bookmarks": [["Chapter 2", 12]], "last_page": 30}"#)
+            .ok().expect("a file of the first version can be read");
+
+        assert_eq!(saves.version, SAVE_VERSION);
+        assert!(saves.position == Locator::from_page(30));
+        assert!(saves.bookmarks == Vector::unit((String::from("Chapter 2"), Locator::from_page(12))));
+        assert_eq!(saves.highlights.len(), 0);
+    }
+
+    #[test]
+    fn malformed_bookmarks_are_dropped() {
+        let text = r#"{"version": 2, "last_page": 0, "bookmarks": [["Kept", 3], ["No page"], [4, 5],
+            ["Not a number", "6"], ["Too long", 7, 8], "Not a pair", ["Negative", -1]]}"#;
+        let saves = JsonStruct::from_json(text).ok().expect("the malformed bookmarks are dropped");
+
+        assert!(saves.bookmarks == Vector::unit((String::from("Kept"), Locator::from_page(3))));
+    }
+
+    #[test]
+    fn last_page_not_a_number_is_invalid() {
+        let saves = JsonStruct::from_json(r#"{"version": 2, "last_page": "ten", "bookmarks": []}"#);
+
+        assert!(matches!(saves, Err(SaveError::Invalid(_))));
+    }
+
+    #[test]
+    fn newer_version_is_not_read() {
+        assert!(matches!(JsonStruct::from_json(r#"{"version": 99}"#), Err(SaveError::Newer(99))));
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde::Serialize;
+use crate::data::{flatten_chapters, AppState};
+use crate::markup::blocks_text;
+
+/*
+The number of characters of the page quoted for a bookmark.
+*/
+const QUOTE_LENGTH: usize = 200;
+
+/*
+The formats the annotations of an Ebook can be exported to, chosen by the extension of the file.
+*/
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "json" => Some(ExportFormat::Json),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/*
+A bookmark or a highlight of the Ebook ready to be exported: the title of the chapter containing it,
+its page in the current pagination and the quoted text (the highlighted passage, or the beginning of
+the page for a bookmark). The note of a bookmark is its title.
+*/
+#[derive(Clone, Serialize)]
+pub struct Annotation {
+    pub kind: &'static str,
+    pub chapter: String,
+    pub page: Option<usize>,
+    pub text: String,
+    pub note: String,
+    pub color: String,
+}
+
+#[derive(Serialize)]
+struct ExportedBook<'a> {
+    title: &'a str,
+    annotations: &'a Vec<Annotation>,
+}
+
+/*
+This function collects the bookmarks and the highlights of the open Ebook, sorted by page. Those
+whose page can't be found are placed at the end.
+*/
+pub fn annotations(state: &AppState) -> Vec<Annotation> {
+    let mut annotations = Vec::new();
+
+    for (title, locator) in state.saves.bookmarks.iter() {
+        let page = state.page_of_locator(locator);
+        let text = page.and_then(|page| state.decoded_page(page))
+            .map(|decoded| quote(&blocks_text(&decoded.blocks)))
+            .unwrap_or_default();
+
+        annotations.push(Annotation {
+            kind: "bookmark",
+            chapter: chapter_of(state, page),
+            page,
+            text,
+            note: title.clone(),
+            color: String::new(),
+        });
+    }
+
+    for highlight in state.saves.highlights.iter() {
+        let page = state.page_of_locator(&highlight.position);
+
+        annotations.push(Annotation {
+            kind: "highlight",
+            chapter: chapter_of(state, page),
+            page,
+            text: highlight.text.clone(),
+            note: highlight.note.clone(),
+            color: highlight.color.name().to_string(),
+        });
+    }
+
+    annotations.sort_by_key(|annotation| annotation.page.unwrap_or(usize::MAX));
+    annotations
+}
+
+/*
+This function writes the annotations of the open Ebook to the given file, in the format given by
+its extension.
+*/
+pub fn export_annotations(state: &AppState, path: &Path, format: ExportFormat) -> io::Result<()> {
+    let annotations = annotations(state);
+
+    let text = match format {
+        ExportFormat::Markdown => to_markdown(&state.title, &annotations),
+        ExportFormat::Json => serde_json::to_string_pretty(&ExportedBook { title: &state.title, annotations: &annotations })
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+        ExportFormat::Csv => to_csv(&annotations),
+    };
+
+    fs::write(path, text)
+}
+
+/*
+This function returns the title of the last chapter starting before the given page, or an empty
+string if there isn't one.
+*/
+fn chapter_of(state: &AppState, page: Option<usize>) -> String {
+    let page = match page {
+        Some(page) => page,
+        None => return String::new(),
+    };
+
+    flatten_chapters(&state.chapters).into_iter()
+        .filter(|(_, chapter)| chapter.target_page <= page)
+        .max_by_key(|(_, chapter)| chapter.target_page)
+        .map(|(_, chapter)| chapter.title.trim().to_string())
+        .unwrap_or_default()
+}
+
+/*
+This function returns the beginning of a text on a single line, cut at the end of a word.
+*/
+fn quote(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if text.chars().count() <= QUOTE_LENGTH {
+        return text;
+    }
+
+    let cut: String = text.chars().take(QUOTE_LENGTH).collect();
+    match cut.rfind(' ') {
+        Some(end) => cut[..end].to_string() + "...",
+        None => cut + "...",
+    }
+}
+
+/*
+This function writes the annotations as a Markdown document: a section for every chapter, in the
+order of the Ebook, containing its bookmarks and highlights with their quoted text and notes.
+*/
+fn to_markdown(title: &str, annotations: &Vec<Annotation>) -> String {
+    let mut text = format!("# {}\n", title);
+    let mut chapter = None;
+
+    for annotation in annotations {
+        if chapter != Some(&annotation.chapter) {
+            chapter = Some(&annotation.chapter);
+            let heading = if annotation.chapter.len() > 0 { annotation.chapter.as_str() } else { "Without chapter" };
+            text.push_str(&format!("\n## {}\n", heading));
+        }
+
+        let page = annotation.page.map(|page| format!("page {}", page)).unwrap_or(String::from("page not found"));
+        if annotation.kind == "bookmark" {
+            text.push_str(&format!("\n**Bookmark: {}** ({})\n", annotation.note, page));
+        } else {
+            text.push_str(&format!("\n**Highlight** ({}, {})\n", page, annotation.color.to_lowercase()));
+        }
+
+        if annotation.text.len() > 0 {
+            text.push('\n');
+            for line in annotation.text.lines() {
+                text.push_str(&format!("> {}\n", line));
+            }
+        }
+        if annotation.kind == "highlight" && annotation.note.len() > 0 {
+            text.push_str(&format!("\nNote: {}\n", annotation.note));
+        }
+    }
+
+    text
+}
+
+/*
+This function writes the annotations as CSV, with a header and a row for every annotation. Every
+field is quoted, so the text can contain commas, quotes and line breaks.
+*/
+fn to_csv(annotations: &Vec<Annotation>) -> String {
+    let mut text = String::from("kind,chapter,page,color,text,note\r\n");
+
+    for annotation in annotations {
+        let page = annotation.page.map(|page| page.to_string()).unwrap_or_default();
+        let fields = [annotation.kind, annotation.chapter.as_str(), page.as_str(), annotation.color.as_str(),
+            annotation.text.as_str(), annotation.note.as_str()];
+
+        let row: Vec<String> = fields.iter()
+            .map(|field| format!("\"{}\"", field.replace('"', "\"\"")))
+            .collect();
+        text.push_str(&row.join(","));
+        text.push_str("\r\n");
+    }
+
+    text
+}
@@ -0,0 +1,74 @@
+use druid::{Color, Data};
+use serde::{Deserialize, Serialize};
+use crate::locator::Locator;
+
+/*
+The colours a passage of the Ebook can be highlighted with.
+*/
+#[derive(Clone, Copy, Data, PartialEq, Serialize, Deserialize)]
+pub enum HighlightColor {
+    Yellow,
+    Green,
+    Blue,
+    Pink,
+}
+
+impl HighlightColor {
+    pub const ALL: [HighlightColor; 4] = [HighlightColor::Yellow, HighlightColor::Green, HighlightColor::Blue, HighlightColor::Pink];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HighlightColor::Yellow => "Yellow",
+            HighlightColor::Green => "Green",
+            HighlightColor::Blue => "Blue",
+            HighlightColor::Pink => "Pink",
+        }
+    }
+
+    /*
+    This function returns the colour painted behind the highlighted text, light enough to keep the
+    text readable.
+    */
+    pub fn color(&self) -> Color {
+        match self {
+            HighlightColor::Yellow => Color::rgb8(0xff, 0xeb, 0x3b),
+            HighlightColor::Green => Color::rgb8(0xa5, 0xd6, 0xa7),
+            HighlightColor::Blue => Color::rgb8(0x90, 0xca, 0xf9),
+            HighlightColor::Pink => Color::rgb8(0xf4, 0x8f, 0xb1),
+        }
+    }
+}
+
+/*
+A passage of the Ebook highlighted by the user: the locator of its first character, its text (whose
+length, without whitespace, gives the end of the passage), its colour and the note attached to it.
+*/
+#[derive(Clone, Data, PartialEq, Serialize, Deserialize)]
+pub struct Highlight {
+    pub position: Locator,
+    pub text: String,
+    pub color: HighlightColor,
+    #[serde(default)]
+    pub note: String,
+}
+
+impl Highlight {
+    /*
+    This function returns the number of characters of the passage, without whitespace.
+    */
+    pub fn length(&self) -> usize {
+        self.text.chars().filter(|c| !c.is_whitespace()).count()
+    }
+}
+
+/*
+The text selected by the user on a page, waiting to be highlighted: the index of the page and the
+range of the characters of its visible text (without whitespace) it covers.
+*/
+#[derive(Clone, Data, PartialEq)]
+pub struct Selection {
+    pub page: usize,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::time::UNIX_EPOCH;
+use druid::Data;
+use druid::im::Vector;
+use serde::{Deserialize, Serialize};
+use crate::data::{flatten_chapters, Chapter, Section};
+use crate::library::ebook_title;
+use crate::loader::{load_epub, LoadProgress};
+use crate::markup::{blocks_text, parse_blocks};
+use crate::storage::{index_file, indexed_text_file};
+
+/*
+The version of the format of the index. An index saved with another version is built again from
+scratch, as the Ebooks are opened.
+*/
+const INDEX_VERSION: u64 = 1;
+
+/*
+The maximum number of results of a search in the library.
+*/
+pub const MAX_RESULTS: usize = 100;
+
+/*
+The number of characters of the snippet shown before and after the first word found in a result.
+*/
+const SNIPPET_LENGTH: usize = 60;
+
+/*
+The words shorter than this aren't indexed.
+*/
+const MIN_TERM_LENGTH: usize = 2;
+
+/*
+The index, read from its file the first time it is needed and kept in memory. It is used by the
+threads indexing the Ebooks and by the search one at a time, and only while the index itself is
+read or changed: the text of the Ebooks is extracted before taking it.
+*/
+static INDEX: Mutex<Option<LibraryIndex>> = Mutex::new(None);
+
+fn with_index<T>(f: impl FnOnce(&mut LibraryIndex) -> T) -> T {
+    let mut index = INDEX.lock().unwrap_or_else(|e| e.into_inner());
+    f(index.get_or_insert_with(LibraryIndex::read))
+}
+
+/*
+The inverted index of the library: the Ebooks it contains, indexed by their key, and for every word
+the files of the Ebooks containing it, with the number of times it appears. The text of every Ebook
+is saved in its own file next to it, to build the snippets of the results.
+*/
+#[derive(Default, Serialize, Deserialize)]
+struct LibraryIndex {
+    version: u64,
+    books: HashMap<String, IndexedBook>,
+    terms: HashMap<String, Vec<Posting>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct IndexedBook {
+    path: String,
+    title: String,
+    sections: usize,
+    #[serde(default)]
+    modified: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Posting {
+    book: String,
+    section: usize,
+    count: usize,
+}
+
+/*
+The text of a file of an Ebook, as displayed, with the title of the chapter it belongs to.
+*/
+#[derive(Serialize, Deserialize)]
+struct IndexedSection {
+    source: String,
+    chapter: String,
+    text: String,
+}
+
+/*
+A result of the search in the library: the path and the title of the Ebook, the chapter and the
+snippet of text containing the words searched, and the position of the first of them, as the file
+and the number of characters of visible text without whitespace preceding it.
+*/
+#[derive(Clone, Data, PartialEq)]
+pub struct LibraryHit {
+    pub path: String,
+    pub title: String,
+    pub chapter: String,
+    pub snippet: String,
+    pub source: String,
+    pub chars: usize,
+    pub score: f64,
+}
+
+impl LibraryIndex {
+    /*
+    This function reads the index of the library, returning an empty index if it doesn't exist yet,
+    can't be read or was saved by another version.
+    */
+    fn read() -> Self {
+        let index = fs::read_to_string(index_file()).ok()
+            .and_then(|text| serde_json::from_str::<LibraryIndex>(&text).ok())
+            .filter(|index| index.version == INDEX_VERSION);
+
+        index.unwrap_or_else(|| LibraryIndex { version: INDEX_VERSION, ..Default::default() })
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let path = index_file();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let text = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        fs::write(path, text)
+    }
+
+    /*
+    This function removes an Ebook and its words from the index.
+    */
+    fn remove(&mut self, key: &str) {
+        self.books.remove(key);
+        for postings in self.terms.values_mut() {
+            postings.retain(|posting| posting.book != key);
+        }
+        self.terms.retain(|_, postings| postings.len() > 0);
+
+        fs::remove_file(indexed_text_file(key)).ok();
+    }
+}
+
+/*
+This function adds an Ebook to the index of the library, or updates it. An Ebook already indexed is
+only moved to its new path, taking its new title; an Ebook replacing another one at the same path
+(as a file edited and saved again) replaces it in the index.
+*/
+pub fn index_book(key: &str, path: &str, title: &str, sections: &Vector<Section>, chapters: &Vector<Chapter>) -> io::Result<()> {
+    let modified = modified_time(Path::new(path));
+    let needed = with_index(|index| {
+        if let Some(book) = index.books.get_mut(key) {
+            if book.path != path {
+                book.path = path.to_string();
+                book.title = title.to_string();
+                book.modified = modified;
+                return index.write().map(|_| false);
+            }
+            if book.modified == modified && indexed_text_file(key).exists() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    })?;
+    if !needed {
+        return Ok(());
+    }
+
+    let texts = section_texts(sections, chapters);
+    let counts: Vec<HashMap<String, usize>> = texts.iter()
+        .map(|section| {
+            let mut counts = HashMap::new();
+            for term in terms(&section.text) {
+                *counts.entry(term).or_insert(0) += 1;
+            }
+            counts
+        })
+        .collect();
+    let text = serde_json::to_string(&texts).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    with_index(|index| {
+        let replaced: Vec<String> = index.books.iter()
+            .filter(|(other, book)| book.path == path || other.as_str() == key)
+            .map(|(other, _)| other.clone())
+            .collect();
+        for other in replaced {
+            index.remove(&other);
+        }
+
+        for (i, counts) in counts.into_iter().enumerate() {
+            for (term, count) in counts {
+                index.terms.entry(term).or_default().push(Posting { book: key.to_string(), section: i, count });
+            }
+        }
+        index.books.insert(key.to_string(), IndexedBook {
+            path: path.to_string(),
+            title: title.to_string(),
+            sections: texts.len(),
+            modified,
+        });
+
+        let text_file = indexed_text_file(key);
+        if let Some(parent) = text_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(text_file, &text)?;
+
+        index.write()
+    })
+}
+
+/*
+This function reads an epub file and adds it to the index of the library, as index_book does, with
+the title shown by the library. It is used for the Ebooks that aren't open, as the ones saved in
+edit mode.
+*/
+pub fn index_epub_file(path: &Path) -> io::Result<()> {
+    let mut key = String::new();
+    let mut title = String::new();
+    let mut sections = Vector::new();
+    let mut chapters = Vector::new();
+
+    load_epub(path, &AtomicBool::new(false), |progress| match progress {
+        LoadProgress::Opened(book) => {
+            key = book.key.clone();
+            title = ebook_title(&book.archive(), path);
+        }
+        LoadProgress::Sections(loaded, _) => sections.append(loaded),
+        LoadProgress::Finished(toc, _) => chapters = toc,
+    }).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    index_book(&key, &path.to_string_lossy(), &title, &sections, &chapters)
+}
+
+/*
+This function adds to the index the Ebooks of the library that aren't in it yet, or that have
+changed since they were indexed, one at a time. It is meant to run in a worker thread after the
+library has been scanned, so that the search covers the Ebooks never opened too.
+*/
+pub fn index_library(paths: &[String]) {
+    for path in paths {
+        let modified = modified_time(Path::new(path));
+        let indexed = with_index(|index| index.books.values().any(|book| book.path == *path && book.modified == modified));
+
+        if !indexed {
+            if let Err(error) = index_epub_file(Path::new(path)) {
+                println!("Error while indexing {}: {}", path, error);
+            }
+        }
+    }
+}
+
+/*
+This function returns the time the file was last modified, in seconds, or 0 if it isn't known.
+*/
+pub fn modified_time(path: &Path) -> u64 {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/*
+This function searches the words of the query in the whole library. Every file of an Ebook is
+ranked by the sum, over the words it contains, of their number of occurrences weighted by how rare
+the word is in the library (tf-idf); the files containing more of the words come first. The Ebooks
+whose file doesn't exist anymore are skipped. It is meant to run in a worker thread, since it reads
+the files of the text of the Ebooks found.
+*/
+pub fn search_library(query: &str) -> Vec<LibraryHit> {
+    let words: Vec<String> = terms(query).collect();
+    let ranked = with_index(|index| rank(index, &words));
+
+    let mut texts: HashMap<String, Vec<IndexedSection>> = HashMap::new();
+    let mut hits = Vec::new();
+
+    for (key, book, section, score) in ranked {
+        let sections = texts.entry(key.clone()).or_insert_with(|| {
+            fs::read_to_string(indexed_text_file(&key)).ok()
+                .and_then(|text| serde_json::from_str(&text).ok())
+                .unwrap_or_default()
+        });
+        let section = match sections.get(section) {
+            Some(section) => section,
+            None => continue,
+        };
+        let (snippet, chars) = snippet(&section.text, &words);
+
+        hits.push(LibraryHit {
+            path: book.path.clone(),
+            title: book.title.clone(),
+            chapter: section.chapter.clone(),
+            snippet,
+            source: section.source.clone(),
+            chars,
+            score,
+        });
+    }
+
+    hits
+}
+
+/*
+This function returns the best files of the Ebooks of the index for the given words, with their
+Ebook and their score.
+*/
+fn rank(index: &LibraryIndex, words: &Vec<String>) -> Vec<(String, IndexedBook, usize, f64)> {
+    let documents = index.books.values().map(|book| book.sections).sum::<usize>().max(1) as f64;
+    let mut scores: HashMap<(String, usize), (usize, f64)> = HashMap::new();
+
+    for word in words.iter() {
+        let postings = match index.terms.get(word) {
+            Some(postings) => postings,
+            None => continue,
+        };
+        let idf = (documents / postings.len() as f64).ln() + 1.;
+
+        for posting in postings {
+            let score = scores.entry((posting.book.clone(), posting.section)).or_insert((0, 0.));
+            score.0 += 1;
+            score.1 += (1. + (posting.count as f64).ln()) * idf;
+        }
+    }
+
+    let mut ranked: Vec<((String, usize), (usize, f64))> = scores.into_iter()
+        .filter(|((book, _), _)| index.books.get(book).map(|b| Path::new(&b.path).exists()).unwrap_or(false))
+        .collect();
+    ranked.sort_by(|(_, a), (_, b)| b.0.cmp(&a.0).then(b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal)));
+    ranked.truncate(MAX_RESULTS);
+
+    ranked.into_iter()
+        .map(|((key, section), (_, score))| {
+            let book = index.books[&key].clone();
+            (key, book, section, score)
+        })
+        .collect()
+}
+
+/*
+This function returns the text of every file of an Ebook, as displayed, with the title of the
+chapter containing it: the first chapter of the table of contents pointing to the file, or the one
+of the previous file.
+*/
+fn section_texts(sections: &Vector<Section>, chapters: &Vector<Chapter>) -> Vec<IndexedSection> {
+    let chapters = flatten_chapters(chapters);
+    let mut chapter = String::new();
+
+    sections.iter()
+        .map(|section| {
+            if let Some((_, found)) = chapters.iter().find(|(_, c)| c.source == section.source) {
+                chapter = found.title.trim().to_string();
+            }
+
+            IndexedSection {
+                source: section.source.clone(),
+                chapter: chapter.clone(),
+                text: blocks_text(&parse_blocks(&section.content, &section.stylesheet)),
+            }
+        })
+        .collect()
+}
+
+/*
+This function splits a text into the words stored in the index, in lowercase.
+*/
+fn terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_TERM_LENGTH)
+        .map(|word| word.to_lowercase())
+}
+
+/*
+This function returns the part of a text around the first of the given words it contains, and the
+number of characters without whitespace preceding the word.
+*/
+fn snippet(text: &str, words: &Vec<String>) -> (String, usize) {
+    let start = text.split(|c: char| !c.is_alphanumeric())
+        .find(|word| word.len() > 0 && words.contains(&word.to_lowercase()))
+        .map(|word| word.as_ptr() as usize - text.as_ptr() as usize)
+        .unwrap_or(0);
+
+    let before: Vec<char> = text[..start].chars().collect();
+    let after: Vec<char> = text[start..].chars().collect();
+    let mut snippet = String::new();
+
+    if before.len() > SNIPPET_LENGTH {
+        snippet.push_str("...");
+    }
+    snippet.extend(before[before.len().saturating_sub(SNIPPET_LENGTH)..].iter());
+    snippet.extend(after.iter().take(SNIPPET_LENGTH * 2));
+    if after.len() > SNIPPET_LENGTH * 2 {
+        snippet.push_str("...");
+    }
+
+    let chars = text[..start].chars().filter(|c| !c.is_whitespace()).count();
+    (snippet.split_whitespace().collect::<Vec<&str>>().join(" "), chars)
+}
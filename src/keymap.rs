@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+use druid::{Env, Event, EventCtx, KbKey, KeyEvent, LifeCycle, LifeCycleCtx, Widget};
+use druid::widget::Controller;
+use serde::{Deserialize, Serialize};
+use crate::data::{AppState, GO_TO_POS};
+use crate::storage::keymap_file;
+
+/*
+The actions that can be bound to a keyboard shortcut.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    NextPage,
+    PreviousPage,
+    FirstPage,
+    LastPage,
+    Find,
+    Bookmark,
+    FontBigger,
+    FontSmaller,
+    #[serde(alias = "fullscreen")]
+    DistractionFree,
+}
+
+/*
+The shortcuts bound to the actions when the key map doesn't change them.
+*/
+const DEFAULT_BINDINGS: [(Action, &[&str]); 9] = [
+    (Action::NextPage, &["ArrowRight", "ArrowDown", "PageDown"]),
+    (Action::PreviousPage, &["ArrowLeft", "ArrowUp", "PageUp"]),
+    (Action::FirstPage, &["Home"]),
+    (Action::LastPage, &["End"]),
+    (Action::Find, &["Ctrl+F"]),
+    (Action::Bookmark, &["Ctrl+B"]),
+    (Action::FontBigger, &["Ctrl++", "Ctrl+="]),
+    (Action::FontSmaller, &["Ctrl+-"]),
+    (Action::DistractionFree, &["F11"]),
+];
+
+/*
+A keyboard shortcut: a key and the modifiers held with it. It is written as the modifiers (Ctrl,
+Shift, Alt, Meta) followed by the key, separated by "+", as "Ctrl+F" or "Ctrl++"; the key is either
+a single character or the name of a key as reported by the keyboard ("ArrowRight", "PageDown",
+"F11", "Space"...). Shift is ignored for the characters unless it is written, since it is often
+needed to type them.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shortcut {
+    key: KbKey,
+    ctrl: bool,
+    shift: bool,
+    alt: bool,
+    meta: bool,
+}
+
+impl Shortcut {
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let (modifiers, key) = if text == "+" || text.ends_with("++") {
+            (&text[..text.len() - 1], "+")
+        } else {
+            match text.rfind('+') {
+                Some(i) => (&text[..i], &text[i + 1..]),
+                None => ("", text),
+            }
+        };
+
+        let key = match key {
+            "Space" => KbKey::Character(String::from(" ")),
+            key if key.chars().count() == 1 => KbKey::Character(key.to_lowercase()),
+            key => match KbKey::from_str(key) {
+                Ok(KbKey::Character(_)) | Err(_) => return None,
+                Ok(key) => key,
+            },
+        };
+        let mut shortcut = Shortcut { key, ctrl: false, shift: false, alt: false, meta: false };
+
+        for modifier in modifiers.split('+').filter(|modifier| modifier.len() > 0) {
+            match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => shortcut.ctrl = true,
+                "shift" => shortcut.shift = true,
+                "alt" | "option" => shortcut.alt = true,
+                "meta" | "cmd" | "super" => shortcut.meta = true,
+                _ => return None,
+            }
+        }
+
+        Some(shortcut)
+    }
+
+    /*
+    This function checks if a key pressed by the user is the shortcut. On macOS Ctrl also matches
+    the Command key.
+    */
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        let mods = &event.mods;
+        let command = cfg!(target_os = "macos") && self.ctrl && !self.meta;
+        let ctrl = if command { mods.ctrl() || mods.meta() } else { mods.ctrl() };
+        let meta = if command { true } else { mods.meta() == self.meta };
+
+        let key = match (&self.key, &event.key) {
+            (KbKey::Character(expected), KbKey::Character(pressed)) => {
+                if self.shift && !mods.shift() {
+                    return false;
+                }
+                *expected == pressed.to_lowercase()
+            }
+            (expected, pressed) => expected == pressed && mods.shift() == self.shift,
+        };
+
+        key && ctrl == self.ctrl && mods.alt() == self.alt && meta
+    }
+}
+
+/*
+The key map of the application: the shortcuts bound to every action. It is read from a json file
+in the configuration directory, mapping the name of every action to the list of its shortcuts, as
+{"next_page": ["ArrowRight", "PageDown"], "find": ["Ctrl+F"]}. The actions missing from the file
+keep their default shortcuts, and an empty list disables an action; the file is created with the
+default shortcuts the first time, so that the user can edit it.
+*/
+pub struct KeyMap {
+    bindings: Vec<(Shortcut, Action)>,
+}
+
+impl KeyMap {
+    pub fn load() -> Self {
+        let mut config: BTreeMap<Action, Vec<String>> = DEFAULT_BINDINGS.iter()
+            .map(|(action, shortcuts)| (*action, shortcuts.iter().map(|s| s.to_string()).collect()))
+            .collect();
+        let path = keymap_file();
+
+        match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str::<BTreeMap<Action, Vec<String>>>(&text) {
+                Ok(user) => config.extend(user),
+                Err(error) => println!("The key map {} isn't valid, using the default shortcuts: {}", path.display(), error),
+            },
+            Err(_) => {
+                let written = path.parent().map(|dir| fs::create_dir_all(dir)).unwrap_or(Ok(()))
+                    .and_then(|_| fs::write(&path, serde_json::to_string_pretty(&config).unwrap_or_default()));
+                if let Err(error) = written {
+                    println!("Error while writing {}: {}", path.display(), error);
+                }
+            }
+        }
+
+        let mut bindings = Vec::new();
+        for (action, shortcuts) in config {
+            for text in shortcuts {
+                match Shortcut::parse(&text) {
+                    Some(shortcut) => bindings.push((shortcut, action)),
+                    None => println!("Unknown shortcut \"{}\" in the key map", text),
+                }
+            }
+        }
+
+        Self { bindings }
+    }
+
+    /*
+    This function returns the action bound to a key pressed by the user, if any.
+    */
+    pub fn action(&self, event: &KeyEvent) -> Option<Action> {
+        self.bindings.iter()
+            .find(|(shortcut, _)| shortcut.matches(event))
+            .map(|(_, action)| *action)
+    }
+}
+
+/*
+The controller wrapping the page view, which runs the actions bound to the keys pressed by the
+user. The page view takes the keyboard focus when the window opens and when it is clicked, so the
+shortcuts don't interfere with typing in the text boxes.
+*/
+pub struct ShortcutController {
+    keymap: KeyMap,
+}
+
+impl ShortcutController {
+    pub fn new(keymap: KeyMap) -> Self {
+        Self { keymap }
+    }
+}
+
+impl<W: Widget<AppState>> Controller<AppState, W> for ShortcutController {
+    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        match event {
+            Event::WindowConnected | Event::MouseDown(_) => ctx.request_focus(),
+            Event::KeyDown(key) if !data.edit_mode => {
+                if let Some(action) = self.keymap.action(key) {
+                    run_action(action, ctx, data, env);
+                    ctx.set_handled();
+                    return;
+                }
+            }
+            _ => {}
+        }
+
+        child.event(ctx, event, data, env);
+    }
+
+    fn lifecycle(&mut self, child: &mut W, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &AppState, env: &Env) {
+        if let LifeCycle::WidgetAdded = event {
+            ctx.register_for_focus();
+        }
+
+        child.lifecycle(ctx, event, data, env);
+    }
+}
+
+/*
+This function runs an action, through the same functions as the buttons when there is one. The
+actions moving in the Ebook do nothing while no Ebook is open, and the font size changes stay inside
+the sizes allowed by the preferences.
+*/
+fn run_action(action: Action, ctx: &mut EventCtx, data: &mut AppState, env: &Env) {
+    let open = data.ebook.len() > 0;
+
+    match action {
+        Action::NextPage if open => AppState::click_next_button(ctx, data, env),
+        Action::PreviousPage if open => AppState::click_previous_button(ctx, data, env),
+        Action::FirstPage if open => ctx.submit_command(GO_TO_POS.with(0)),
+        Action::LastPage if open => ctx.submit_command(GO_TO_POS.with(data.ebook.len() - 1)),
+        Action::Find => AppState::click_find_button(ctx, data, env),
+        Action::Bookmark => data.bookmark_current_page(ctx, env),
+        Action::FontBigger => data.set_font_size(data.font_size + 1.),
+        Action::FontSmaller => data.set_font_size(data.font_size - 1.),
+        Action::DistractionFree => data.toggle_distraction_free(ctx),
+        _ => {}
+    }
+}
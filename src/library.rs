@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use druid::Data;
+use druid::im::Vector;
+use epub::doc::EpubDoc;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+use crate::data::{ImageOfPage, JsonStruct};
+use crate::index::modified_time;
+use crate::loader::{book_key, resolve_href};
+use crate::locator::offset_of;
+use crate::storage::{library_file, metadata_file};
+
+/*
+The height of the covers shown in the library, in pixels. They are scaled down when the library is
+scanned, so that only small images are kept in memory.
+*/
+pub const COVER_HEIGHT: u32 = 180;
+
+/*
+The covers already read, by path of the Ebook, with the time its file was modified: scanning the
+library again only decodes the covers of the new or changed Ebooks.
+*/
+static COVERS: Mutex<Option<HashMap<String, (u64, Option<ImageOfPage>)>>> = Mutex::new(None);
+
+/*
+The folders scanned for the Ebooks of the library, saved in their own json file.
+*/
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct LibrarySettings {
+    pub folders: Vector<String>,
+}
+
+impl LibrarySettings {
+    /*
+    This function reads the folders of the library, returning no folders if the file doesn't exist
+    or can't be read.
+    */
+    pub fn read() -> Self {
+        fs::read_to_string(library_file()).ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn write(&self) -> io::Result<()> {
+        let path = library_file();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let text = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        fs::write(path, text)
+    }
+}
+
+/*
+The orders the Ebooks of the library can be listed in.
+*/
+#[derive(Clone, Copy, Data, PartialEq, Serialize, Deserialize)]
+pub enum LibrarySort {
+    Title,
+    Author,
+    Progress,
+}
+
+/*
+The ways the Ebooks of the library can be shown: as a grid of cards with their cover, or as a list
+with one Ebook per line.
+*/
+#[derive(Clone, Copy, Data, PartialEq, Serialize, Deserialize)]
+pub enum LibraryLayout {
+    Grid,
+    List,
+}
+
+/*
+An Ebook found in the folders of the library, with the metadata read from its package document: the
+title (the name of the file if it has none), the author, the language and the cover. The progress is
+the fraction of the Ebook read when it was last closed, None if it has never been opened.
+*/
+#[derive(Clone, Data)]
+pub struct LibraryBook {
+    pub path: String,
+    pub title: String,
+    pub author: String,
+    pub language: String,
+    pub cover: Option<ImageOfPage>,
+    pub progress: Option<f64>,
+}
+
+/*
+This function searches the folders and their subfolders for epub files, reading the metadata of
+every Ebook found. The files that can't be opened are skipped.
+*/
+pub fn scan_library(folders: &Vector<String>) -> Vec<LibraryBook> {
+    let mut books: Vec<LibraryBook> = Vec::new();
+
+    for folder in folders.iter() {
+        for entry in WalkDir::new(folder).follow_links(true).into_iter().filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let is_epub = path.extension()
+                .map(|extension| extension.to_string_lossy().eq_ignore_ascii_case("epub"))
+                .unwrap_or(false);
+
+            if entry.file_type().is_file() && is_epub && !books.iter().any(|book| Path::new(&book.path) == path) {
+                match read_book(path) {
+                    Some(book) => books.push(book),
+                    None => println!("Unable to read the Ebook {}", path.display()),
+                }
+            }
+        }
+    }
+
+    if let Some(covers) = COVERS.lock().unwrap().as_mut() {
+        covers.retain(|path, _| books.iter().any(|book| book.path == *path));
+    }
+
+    books
+}
+
+/*
+This function reads the metadata and the cover of an epub file, and the progress saved for it. The
+cover is taken from the covers already read if the file hasn't changed since.
+*/
+fn read_book(path: &Path) -> Option<LibraryBook> {
+    let mut doc = EpubDoc::new(path).ok()?;
+    let key = book_key(path, &doc);
+    let name = path.to_string_lossy().to_string();
+
+    let title = ebook_title(&doc, path);
+    let progress = fs::read_to_string(metadata_file(&key)).ok()
+        .and_then(|text| JsonStruct::from_json(&text).ok())
+        .map(|saves| saved_progress(path, &mut doc, &saves));
+
+    let modified = modified_time(path);
+    let cached = COVERS.lock().unwrap().as_ref()
+        .and_then(|covers| covers.get(&name))
+        .filter(|(time, _)| *time == modified)
+        .map(|(_, cover)| cover.clone());
+    let cover = match cached {
+        Some(cover) => cover,
+        None => {
+            let cover = read_cover(&mut doc);
+            COVERS.lock().unwrap().get_or_insert_with(HashMap::new).insert(name.clone(), (modified, cover.clone()));
+            cover
+        }
+    };
+
+    Some(LibraryBook {
+        path: name,
+        title,
+        author: doc.mdata("creator").unwrap_or_default().trim().to_string(),
+        language: doc.mdata("language").unwrap_or_default().trim().to_string(),
+        cover,
+        progress,
+    })
+}
+
+/*
+This function returns the title of an Ebook given by its metadata, or the name of its file when the
+metadata don't have one.
+*/
+pub fn ebook_title(doc: &EpubDoc<File>, path: &Path) -> String {
+    doc.mdata("title")
+        .filter(|title| title.trim().len() > 0)
+        .or(path.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+/*
+This function returns the fraction of the Ebook read, as saved when it was last closed. The files
+saved while the Ebook was still loading, or by the versions which didn't save it, only have the last
+position read: the fraction is then estimated from the size of the spine items preceding it and
+from the position in its own item.
+*/
+fn saved_progress(path: &Path, doc: &mut EpubDoc<File>, saves: &JsonStruct) -> f64 {
+    if saves.progress > 0. || saves.position.is_page() {
+        return saves.progress;
+    }
+    let mut archive = match File::open(path).ok().and_then(|file| zip::ZipArchive::new(file).ok()) {
+        Some(archive) => archive,
+        None => return saves.progress,
+    };
+
+    let (mut read, mut total, mut found) = (0, 0, false);
+    for idref in doc.spine.clone() {
+        let item_path = match doc.resources.get(&idref) {
+            Some((item_path, _)) => resolve_href("", &item_path.to_string_lossy()),
+            None => continue,
+        };
+        let size = archive.by_name(&item_path).map(|entry| entry.size()).unwrap_or(0);
+
+        if item_path == saves.position.source {
+            let offset = doc.get_resource_str_by_path(&item_path).ok()
+                .and_then(|content| offset_of(&content, &saves.position))
+                .unwrap_or(0);
+            read += size.min(offset as u64);
+            found = true;
+        } else if !found {
+            read += size;
+        }
+        total += size;
+    }
+
+    if found && total > 0 { read as f64 / total as f64 } else { saves.progress }
+}
+
+/*
+This function returns the cover of an Ebook scaled to the height of the covers of the library. The
+cover is the image named by the cover metadata (EPUB 2), or else the first image whose id or path
+contains "cover", as most EPUB 3 files name it.
+*/
+fn read_cover(doc: &mut EpubDoc<File>) -> Option<ImageOfPage> {
+    let bytes = doc.get_cover().ok().or_else(|| {
+        let mut ids: Vec<String> = doc.resources.iter()
+            .filter(|(id, (path, mime))| mime.starts_with("image/")
+                && (id.to_lowercase().contains("cover") || path.to_string_lossy().to_lowercase().contains("cover")))
+            .map(|(id, _)| id.clone())
+            .collect();
+        ids.sort();
+        ids.first().and_then(|id| doc.get_resource(id).ok())
+    })?;
+
+    let decoded = image::load_from_memory(&bytes).ok()?;
+    let width = (decoded.width() * COVER_HEIGHT / decoded.height().max(1)).max(1);
+    let rgba = decoded.thumbnail(width, COVER_HEIGHT).to_rgba8();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+
+    Some(ImageOfPage::from(Vector::from(rgba.into_raw()), width, height))
+}
+
+/*
+This function returns the Ebooks of the library whose title, author or language contains the filter
+(ignoring the case), in the given order. The Ebooks never opened come last when they are sorted by
+progress.
+*/
+pub fn filter_books(books: &Vector<LibraryBook>, filter: &str, sort: LibrarySort) -> Vec<LibraryBook> {
+    let filter = filter.trim().to_lowercase();
+    let mut filtered: Vec<LibraryBook> = books.iter()
+        .filter(|book| filter.len() == 0
+            || book.title.to_lowercase().contains(&filter)
+            || book.author.to_lowercase().contains(&filter)
+            || book.language.to_lowercase().contains(&filter))
+        .cloned()
+        .collect();
+
+    filtered.sort_by(|a, b| {
+        let by_title = a.title.to_lowercase().cmp(&b.title.to_lowercase());
+        match sort {
+            LibrarySort::Title => by_title,
+            LibrarySort::Author => a.author.to_lowercase().cmp(&b.author.to_lowercase()).then(by_title),
+            LibrarySort::Progress => b.progress.unwrap_or(-1.).partial_cmp(&a.progress.unwrap_or(-1.))
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(by_title),
+        }
+    });
+
+    filtered
+}
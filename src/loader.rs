@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use druid::im::Vector;
+use epub::doc::EpubDoc;
+use crate::book::Book;
+use crate::css::Stylesheet;
+use crate::data::{Chapter, Section};
+use crate::markup::hidden_ranges;
+use crate::toc::read_toc;
+use voca_rs::strip::strip_tags;
+
+const REPORT_INTERVAL: Duration = Duration::from_millis(300);
+
+/*
+The number of bytes read from the beginning of an image to find its size in its header. The whole
+image is only read when its header is longer, as some JPEG files with big metadata.
+*/
+const IMAGE_HEADER_BYTES: u64 = 64 * 1024;
+
+/*
+The problems that can occur while loading an epub file. If the archive can't be opened, or it
+doesn't contain anything that can be displayed, the loading fails; the other errors only concern a
+part of the book (a chapter, an image or a stylesheet), which is skipped while the rest of the book
+is loaded.
+*/
+#[derive(Clone, Debug)]
+pub enum LoadError {
+    Open(String),
+    NoContent,
+    MissingEntry(String),
+    BadEncoding(String),
+    UnsupportedImage(String),
+    MalformedMarkup(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Open(error) => write!(f, "The file is not a valid epub: {}", error),
+            LoadError::NoContent => write!(f, "The Ebook doesn't contain any readable chapter"),
+            LoadError::MissingEntry(path) => write!(f, "{} is missing from the archive", path),
+            LoadError::BadEncoding(path) => write!(f, "{} is not encoded in UTF-8, UTF-16 or ISO-8859-1", path),
+            LoadError::UnsupportedImage(path) => write!(f, "{} is not an image format that can be displayed", path),
+            LoadError::MalformedMarkup(path) => write!(f, "{} is not a valid XHTML document", path),
+        }
+    }
+}
+
+/*
+The steps of the loading of an epub file, reported by load_epub while it reads the archive: the
+Book is reported as soon as the archive is open (its key identifies the saved data of the book),
+then the sections are reported in groups together with the fraction of the spine read so far, and
+finally the chapters of the table of contents (or built from the spine items, if the book doesn't
+have one) with the errors of the parts of the book that have been skipped.
+*/
+pub enum LoadProgress {
+    Opened(Arc<Book>),
+    Sections(Vector<Section>, f64),
+    Finished(Vector<Chapter>, Vec<LoadError>),
+}
+
+/*
+This function opens the epub file at the given path using the EpubDoc API and navigates through the
+items of the OPF spine in order. Every XHTML item of the spine is read through the manifest, so that
+any EPUB 2/3 file is displayed in reading order. Only the size of the images is read here, from
+their header while their section is prepared, since the paginator needs it; the images are decoded
+by the Book when their page is displayed. The sections are split into pages later, depending on
+the size of the window and of the font.
+It is meant to run in a worker thread: the sections are reported while they are read (the first one
+immediately, then at most every REPORT_INTERVAL) so that the first pages can be displayed before the
+whole book is read, and the loading stops as soon as cancel is set. A chapter that can't be read is
+skipped, an image or a stylesheet that can't be read is ignored.
+*/
+pub fn load_epub(path: &Path, cancel: &AtomicBool, mut report: impl FnMut(LoadProgress)) -> Result<(), LoadError> {
+    let doc = EpubDoc::new(path).map_err(|e| LoadError::Open(e.to_string()))?;
+    let spine = doc.spine.clone();
+    let resources = doc.resources.clone();
+    let key = book_key(path, &doc);
+    let book = Arc::new(Book::new(doc, key));
+    report(LoadProgress::Opened(book.clone()));
+    let mut images = File::open(path).ok().and_then(|file| zip::ZipArchive::new(file).ok());
+
+    let mut chapters = Vector::new();
+    let mut sections = Vector::new();
+    let mut stylesheets = HashMap::new();
+    let mut parsed = HashMap::new();
+    let mut errors = Vec::new();
+    let mut loaded = 0;
+    let mut reported = None;
+
+    for (i, idref) in spine.iter().enumerate() {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let (item_path, mime) = match resources.get(idref) {
+            Some(resource) => resource.clone(),
+            None => {
+                errors.push(LoadError::MissingEntry(idref.clone()));
+                continue;
+            }
+        };
+
+        if !is_content_document(&mime) {
+            continue;
+        }
+
+        let item_path = resolve_href("", &item_path.to_string_lossy());
+        let content = match read_document(&book, &item_path) {
+            Ok(content) => content,
+            Err(error) => {
+                errors.push(error);
+                continue;
+            }
+        };
+
+        let mut chapter = Chapter::from(section_title(&content, &item_path), 0);
+        chapter.source = item_path.clone();
+        chapters.push_back(chapter);
+
+        let mut section = Section::new();
+        section.source = item_path.clone();
+
+        for src in image_sources(&content) {
+            if section.image_sizes.contains_key(&src) {
+                continue;
+            }
+
+            let image_path = resolve_href(&item_path, &src);
+            match images.as_mut().and_then(|images| images.by_name(&image_path).ok()) {
+                Some(entry) => match image_size(entry) {
+                    Some(size) => {
+                        section.image_sizes.insert(src, size);
+                    }
+                    None => errors.push(LoadError::UnsupportedImage(image_path)),
+                },
+                None => errors.push(LoadError::MissingEntry(image_path)),
+            }
+        }
+
+        let styles = document_styles(&book, &content, &item_path, &mut stylesheets, &mut errors);
+        section.stylesheet = parsed.entry(styles)
+            .or_insert_with_key(|styles| Arc::new(Stylesheet::new(styles)))
+            .clone();
+        section.hidden = hidden_ranges(&content, &section.stylesheet).into_iter().collect();
+        section.content = content;
+        sections.push_back(section);
+        loaded += 1;
+
+        if reported.map(|time: Instant| time.elapsed() >= REPORT_INTERVAL).unwrap_or(true) {
+            report(LoadProgress::Sections(std::mem::take(&mut sections), (i + 1) as f64 / spine.len() as f64));
+            reported = Some(Instant::now());
+        }
+    }
+
+    if loaded == 0 {
+        return Err(LoadError::NoContent);
+    }
+    if sections.len() > 0 {
+        report(LoadProgress::Sections(sections, 1.));
+    }
+
+    if let Some(toc) = read_toc(&mut book.archive()) {
+        chapters = toc;
+    }
+    report(LoadProgress::Finished(chapters, errors));
+
+    Ok(())
+}
+
+/*
+This function computes the key identifying an Ebook in the saved data: the dc:identifier of the
+package followed by a hash of the content of the spine items. The key doesn't depend on the name or
+on the location of the file, while an edited version of the book gets its own key. The hash uses the
+CRC32 stored by the zip archive for every entry, so that the files don't have to be decompressed.
+*/
+pub fn book_key(path: &Path, doc: &EpubDoc<File>) -> String {
+    let identifier = doc.unique_identifier.clone().or(doc.mdata("identifier")).unwrap_or_default();
+    let mut archive = File::open(path).ok().and_then(|file| zip::ZipArchive::new(file).ok());
+    let mut hash = FNV_OFFSET;
+
+    for idref in doc.spine.iter() {
+        let item_path = match doc.resources.get(idref) {
+            Some((item_path, _)) => resolve_href("", &item_path.to_string_lossy()),
+            None => continue,
+        };
+        let crc = archive.as_mut()
+            .and_then(|archive| archive.by_name(&item_path).ok().map(|entry| entry.crc32()));
+
+        hash = fnv1a(hash, item_path.as_bytes());
+        if let Some(crc) = crc {
+            hash = fnv1a(hash, &crc.to_le_bytes());
+        }
+    }
+
+    format!("{:016x}-{:016x}", fnv1a(FNV_OFFSET, identifier.trim().as_bytes()), hash)
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/*
+This function adds some bytes to a 64 bit FNV-1a hash, which gives the same result on every platform
+and with every version of Rust.
+*/
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/*
+This function reads a content document of the Ebook, decoding its text with the encoding given by
+its byte order mark or by its XML declaration (UTF-8 if there is none). An error is returned if the
+document is missing, if its text can't be decoded or if it has no body. The archive is only locked
+while the document is read, so that the pages can be decoded in the meantime.
+*/
+fn read_document(book: &Book, path: &str) -> Result<String, LoadError> {
+    let bytes = book.archive().get_resource_by_path(path).map_err(|_| LoadError::MissingEntry(path.to_string()))?;
+
+    let content = if let Some(rest) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        String::from_utf8(rest.to_vec()).ok()
+    } else if bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff]) {
+        let little_endian = bytes[0] == 0xff;
+        let units: Vec<u16> = bytes[2..].chunks_exact(2)
+            .map(|pair| if little_endian { u16::from_le_bytes([pair[0], pair[1]]) } else { u16::from_be_bytes([pair[0], pair[1]]) })
+            .collect();
+        String::from_utf16(&units).ok()
+    } else {
+        let declaration = String::from_utf8_lossy(&bytes[..bytes.len().min(100)]).to_ascii_lowercase();
+        let encoding = declaration.strip_prefix("<?xml")
+            .and_then(|declaration| attribute_value(declaration, "encoding"))
+            .unwrap_or_default();
+
+        if encoding == "iso-8859-1" || encoding == "latin1" {
+            Some(bytes.iter().map(|b| *b as char).collect())
+        } else {
+            String::from_utf8(bytes).ok()
+        }
+    };
+
+    let content = content.ok_or(LoadError::BadEncoding(path.to_string()))?;
+    if !content.to_ascii_lowercase().contains("<body") {
+        return Err(LoadError::MalformedMarkup(path.to_string()));
+    }
+
+    Ok(content)
+}
+
+/*
+This function checks if a manifest media-type corresponds to a document that can be displayed as
+text, so that images or fonts listed in the spine are skipped.
+*/
+fn is_content_document(mime: &str) -> bool {
+    mime == "application/xhtml+xml" || mime == "text/html" || mime == "application/xml" || mime == "text/xml"
+}
+
+/*
+This function returns the sources of the images in the given HTML, in the order they appear: the src
+attribute of every <img> tag and the href of the <image> tags used by SVG covers.
+*/
+pub fn image_sources(text: &str) -> Vec<String> {
+    let mut sources = Vec::new();
+    let mut rest = text;
+
+    while let Some(pos) = rest.find("<im") {
+        let tag_end = rest[pos..].find('>').map(|end| pos + end).unwrap_or(rest.len());
+        let tag = &rest[pos..tag_end];
+
+        let src = if tag.starts_with("<img") {
+            attribute_value(tag, "src")
+        } else if tag.starts_with("<image") {
+            attribute_value(tag, "xlink:href").or(attribute_value(tag, "href"))
+        } else {
+            None
+        };
+        if let Some(src) = src {
+            sources.push(src);
+        }
+        rest = &rest[tag_end.max(pos + 3)..];
+    }
+
+    sources
+}
+
+/*
+This function returns the CSS used by a content document: the stylesheets linked by its <link> tags
+and the content of its <style> tags, in the order they appear. The stylesheets read from the archive
+are kept in a cache, since most books use the same stylesheet for every file. A stylesheet missing
+from the archive is added to errors and ignored. The archive is only locked while a stylesheet is
+read.
+*/
+fn document_styles(book: &Book, content: &str, item_path: &str, cache: &mut HashMap<String, String>, errors: &mut Vec<LoadError>) -> String {
+    let lower = content.to_ascii_lowercase();
+    let head_end = lower.find("<body").unwrap_or(lower.len());
+    let mut styles = String::new();
+    let mut pos = 0;
+
+    while let Some(open) = lower[pos..head_end].find('<') {
+        let open = pos + open;
+        let close = match lower[open..].find('>') {
+            Some(close) => open + close + 1,
+            None => break,
+        };
+        let tag = &content[open..close];
+
+        if lower[open..].starts_with("<link") {
+            let rel = attribute_value(tag, "rel").unwrap_or_default().to_ascii_lowercase();
+            if let (true, Some(href)) = (rel.contains("stylesheet"), attribute_value(tag, "href")) {
+                let path = resolve_href(item_path, &href);
+                if !cache.contains_key(&path) {
+                    let bytes = book.archive().get_resource_by_path(&path);
+                    let css = match bytes {
+                        Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+                        Err(_) => {
+                            errors.push(LoadError::MissingEntry(path.clone()));
+                            String::new()
+                        }
+                    };
+                    cache.insert(path.clone(), css);
+                }
+                styles.push_str(&cache[&path]);
+                styles.push('\n');
+            }
+        } else if lower[open..].starts_with("<style") {
+            let end = lower[close..].find("</style").map(|end| close + end).unwrap_or(close);
+            styles.push_str(&content[close..end]);
+            styles.push('\n');
+        }
+        pos = close;
+    }
+
+    styles
+}
+
+/*
+This function extracts the value of an attribute from the text of a single tag, accepting both
+single and double quotes.
+*/
+pub fn attribute_value(tag: &str, name: &str) -> Option<String> {
+    let mut search = 0;
+
+    while let Some(pos) = tag[search..].find(name) {
+        let pos = search + pos;
+        let before = tag[..pos].chars().last();
+        let after = tag[pos + name.len()..].trim_start();
+
+        if before.map(|c| c.is_whitespace()).unwrap_or(false) && after.starts_with('=') {
+            let value = after[1..].trim_start();
+            let quote = value.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let end = value[1..].find(quote)?;
+                return Some(value[1..end + 1].to_string());
+            }
+            let end = value.find(|c: char| c.is_whitespace() || c == '>' || c == '/').unwrap_or(value.len());
+            return Some(value[..end].to_string());
+        }
+        search = pos + name.len();
+    }
+
+    None
+}
+
+/*
+This function resolves an href found inside the archive file base_file into the full path of the
+referenced archive entry, removing the fragment and the percent-encoding.
+*/
+pub fn resolve_href(base_file: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or("");
+    let href = percent_encoding::percent_decode_str(href).decode_utf8_lossy().to_string();
+
+    if href.len() == 0 {
+        return base_file.to_string();
+    }
+
+    let mut joined = String::new();
+    if !href.starts_with('/') {
+        if let Some(pos) = base_file.rfind('/') {
+            joined.push_str(&base_file[..pos + 1]);
+        }
+    }
+    joined.push_str(&href);
+
+    normalize_path(&joined)
+}
+
+/*
+This function normalizes an archive path using forward slashes and removing the "." and ".."
+components.
+*/
+pub fn normalize_path(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    let path = path.replace('\\', "/");
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+
+    components.join("/")
+}
+
+/*
+This function reads the width and the height of an image stored in the archive from its header,
+guessing its format from the content, without decoding its pixels nor reading the whole image.
+*/
+fn image_size(mut entry: zip::read::ZipFile) -> Option<(usize, usize)> {
+    let mut bytes = Vec::new();
+    (&mut entry).take(IMAGE_HEADER_BYTES).read_to_end(&mut bytes).ok()?;
+
+    let header = |bytes: &[u8]| image::io::Reader::new(Cursor::new(bytes)).with_guessed_format().ok()?.into_dimensions().ok();
+    let (width, height) = match header(&bytes) {
+        Some(size) => size,
+        None if bytes.len() as u64 == IMAGE_HEADER_BYTES => {
+            entry.read_to_end(&mut bytes).ok()?;
+            header(&bytes)?
+        }
+        None => return None,
+    };
+
+    Some((width as usize, height as usize))
+}
+
+/*
+This function finds a title for a spine item looking for its first heading, then for the <title>
+tag and finally using the name of the file.
+*/
+fn section_title(content: &str, item_path: &str) -> String {
+    let lower = content.to_ascii_lowercase();
+
+    for tag in ["<h1", "<h2", "<h3", "<title"] {
+        if let Some(start) = lower.find(tag) {
+            let close = format!("</{}", &tag[1..]);
+            if let Some(end) = lower[start..].find(close.as_str()) {
+                let title = strip_tags(&content[start..start + end])
+                    .replace("\n", " ")
+                    .trim()
+                    .to_string();
+                if title.len() > 0 {
+                    return title;
+                }
+            }
+        }
+    }
+
+    item_path.split('/').last().unwrap_or(item_path).split('.').next().unwrap_or(item_path).to_string()
+}
@@ -0,0 +1,252 @@
+use druid::Data;
+use druid::im::Vector;
+use serde::{Deserialize, Serialize};
+use crate::markup::{parse_tag, VOID_TAGS};
+
+/*
+A position in the Ebook that doesn't depend on the pagination, similar to an EPUB CFI: the file of
+the spine item, the path of the element containing the position (the index of every element among
+the elements of its parent, starting from the root of the file) and the number of characters of
+text of that element preceding the position. Whitespace isn't counted, so a locator still points
+to the same sentence if the file is formatted again.
+A locator without file is the index of a page, saved by the versions of the application which
+didn't use locators: it is converted into a real locator once the Ebook has been paginated.
+*/
+#[derive(Clone, Data, Default, PartialEq, Serialize, Deserialize)]
+pub struct Locator {
+    pub source: String,
+    pub path: Vector<usize>,
+    pub offset: usize,
+}
+
+impl Locator {
+    pub fn from_page(page: usize) -> Self {
+        Self {
+            source: String::new(),
+            path: Vector::new(),
+            offset: page,
+        }
+    }
+
+    pub fn is_page(&self) -> bool {
+        self.source.len() == 0
+    }
+}
+
+/*
+This struct keeps track of the elements that are open at a position of a file while it is scanned:
+for each of them its name, its index among the elements of its parent, the number of elements it
+contains so far and the number of characters of text it contains so far. The first entry is the
+root of the file.
+*/
+struct ElementPath {
+    stack: Vec<(String, usize, usize, usize)>,
+}
+
+impl ElementPath {
+    fn new() -> Self {
+        Self {
+            stack: vec![(String::new(), 0, 0, 0)],
+        }
+    }
+
+    /*
+    This function opens or closes the elements following a tag. The text of the elements that are
+    closed is added to their parent.
+    */
+    fn tag(&mut self, tag: &str) {
+        if tag.starts_with("</") {
+            let name = tag[2..tag.len() - 1].trim().rsplit(':').next().unwrap_or("").to_ascii_lowercase();
+            if let Some(index) = self.stack.iter().rposition(|(open, ..)| *open == name) {
+                while self.stack.len() > index.max(1) {
+                    let (_, _, _, text) = self.stack.pop().unwrap();
+                    self.stack.last_mut().unwrap().3 += text;
+                }
+            }
+        } else if !tag.starts_with("<!") && !tag.starts_with("<?") {
+            let (name, _, self_closing, _) = parse_tag(tag);
+            let parent = self.stack.last_mut().unwrap();
+            let index = parent.2;
+            parent.2 += 1;
+
+            if !self_closing && !VOID_TAGS.contains(&name.as_str()) {
+                self.stack.push((name, index, 0, 0));
+            }
+        }
+    }
+
+    fn add_text(&mut self, text: &str) {
+        self.stack.last_mut().unwrap().3 += count_text(text);
+    }
+
+    fn text(&self) -> usize {
+        self.stack.last().unwrap().3
+    }
+
+    fn path(&self) -> Vector<usize> {
+        self.stack.iter().skip(1).map(|(_, index, ..)| *index).collect()
+    }
+
+    fn is(&self, path: &Vector<usize>) -> bool {
+        self.stack.len() == path.len() + 1 && self.stack.iter().skip(1).zip(path.iter()).all(|((_, index, ..), i)| index == i)
+    }
+
+    fn is_inside(&self, path: &Vector<usize>) -> bool {
+        self.stack.len() > path.len() && self.stack.iter().skip(1).zip(path.iter()).all(|((_, index, ..), i)| index == i)
+    }
+}
+
+fn count_text(text: &str) -> usize {
+    text.chars().filter(|c| !c.is_whitespace()).count()
+}
+
+/*
+This function returns the locator of the byte at position at of the content of the given file.
+*/
+pub fn locator_at(source: &str, content: &str, at: usize) -> Locator {
+    let at = at.min(content.len());
+    let mut path = ElementPath::new();
+    let mut pos = 0;
+
+    while pos < at {
+        if content[pos..].starts_with('<') {
+            let close = match content[pos..].find('>') {
+                Some(close) if pos + close < at => pos + close + 1,
+                _ => break,
+            };
+            path.tag(&content[pos..close]);
+            pos = close;
+        } else {
+            let end = content[pos..at].find('<').map(|end| pos + end).unwrap_or(at);
+            path.add_text(&content[pos..end]);
+            pos = end;
+        }
+    }
+
+    Locator {
+        source: source.to_string(),
+        path: path.path(),
+        offset: path.text(),
+    }
+}
+
+/*
+This function returns the byte of the content of a file the locator points to: the first character
+(or tag) following the given number of characters of text inside the element of the locator. If the
+element has less text, the end of the element is returned; None is returned if the element doesn't
+exist anymore.
+*/
+pub fn offset_of(content: &str, locator: &Locator) -> Option<usize> {
+    let mut path = ElementPath::new();
+    let mut pos = 0;
+
+    while pos < content.len() {
+        let (end, is_tag) = if content[pos..].starts_with('<') {
+            match content[pos..].find('>') {
+                Some(close) => (pos + close + 1, true),
+                None => break,
+            }
+        } else {
+            (content[pos..].find('<').map(|end| pos + end).unwrap_or(content.len()), false)
+        };
+        let token = &content[pos..end];
+
+        if path.is(&locator.path) {
+            match locator.offset.checked_sub(path.text()) {
+                None => return Some(pos),
+                Some(0) if is_tag => return Some(pos),
+                Some(mut missing) if !is_tag && missing < count_text(token) => {
+                    for (i, c) in token.char_indices() {
+                        if !c.is_whitespace() {
+                            if missing == 0 {
+                                return Some(pos + i);
+                            }
+                            missing -= 1;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let was_inside = path.is_inside(&locator.path);
+        if is_tag {
+            path.tag(token);
+        } else {
+            path.add_text(token);
+        }
+        if was_inside && !path.is_inside(&locator.path) {
+            return Some(pos);
+        }
+        pos = end;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &str = "<html><body>\n<div class=\"chapter\"><h1>The <em>first</em> chapter</h1>\n<p>Some <b>bold <i>and italic</i></b> text,<br/> then a <a href=\"#n1\">note</a>.</p>\n</div>\n<p>The end</p></body></html>";
+
+    /*
+    A tag and the text following it without any text in between (as after <br/>) have the same
+    locator, which points to the first of them.
+    */
+    #[test]
+    fn locator_round_trip_on_nested_markup() {
+        let mut in_tag = false;
+        let mut first: Vec<(Locator, usize)> = Vec::new();
+
+        for (pos, c) in CONTENT.char_indices() {
+            let located = match c {
+                '<' => {
+                    in_tag = true;
+                    true
+                }
+                '>' => {
+                    in_tag = false;
+                    false
+                }
+                _ => !in_tag && !c.is_whitespace(),
+            };
+            if !located {
+                continue;
+            }
+
+            let locator = locator_at("text.xhtml", CONTENT, pos);
+            let expected = match first.iter().find(|(other, _)| *other == locator) {
+                Some((_, first)) => *first,
+                None => {
+                    first.push((locator.clone(), pos));
+                    pos
+                }
+            };
+            assert_eq!(offset_of(CONTENT, &locator), Some(expected), "{:?} at {}", c, pos);
+        }
+    }
+
+    #[test]
+    fn offset_before_the_text_of_a_closed_child() {
+        let content = "<p>ab<b>cd</b>ef</p>";
+        let locator = Locator {
+            source: String::from("text.xhtml"),
+            path: Vector::unit(0),
+            offset: 3,
+        };
+
+        assert_eq!(offset_of(content, &locator), content.find("ef"));
+    }
+
+    #[test]
+    fn offset_of_a_missing_element() {
+        let locator = Locator {
+            source: String::from("text.xhtml"),
+            path: Vector::from(vec![5, 2]),
+            offset: 0,
+        };
+
+        assert_eq!(offset_of(CONTENT, &locator), None);
+    }
+}
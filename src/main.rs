@@ -1,27 +1,104 @@
 extern crate core;
 
-use druid::{AppLauncher, WindowDesc};
+use druid::{AppLauncher, Target, WindowDesc};
 
 mod data;
 
 use data::AppState;
 use data::Delegate;
+use data::SCAN_LIBRARY;
 
 mod view;
 
 use view::build_ui;
 
+mod loader;
+
+mod book;
+
+mod toc;
+
+mod paginator;
+
+mod markup;
+
+mod css;
+
+mod rich_text;
+
+mod storage;
+
+mod locator;
+
+mod highlight;
+
+mod export;
+
+mod search;
+
+mod index;
+
+mod library;
+
+use library::LibrarySettings;
+
+mod recent;
+
+use recent::read_recent;
+
+mod keymap;
+
+mod page_turner;
+
+mod theme;
+
+use theme::Theme;
+
+mod typography;
+
+mod preferences;
+
+use preferences::Preferences;
+
+mod util;
+
 
 pub fn main() {
 
     //Creating and launching the main window of the application
-    let initial_state = AppState::new();
+    let mut initial_state = AppState::new();
+
+    //The library is shown at launch when some folders have been added to it
+    initial_state.library_folders = LibrarySettings::read().folders;
+    initial_state.display_library = initial_state.library_folders.len() > 0;
+
+    //The last Ebook read can be opened again from the welcome screen and the library, where the list
+    //of the recent ones is shown at launch when it can be continued
+    initial_state.recent_books = read_recent();
+    initial_state.display_recent = initial_state.recent_books.front().map(|book| book.available).unwrap_or(false);
+
+    //The built-in themes and the ones written by the user
+    initial_state.themes = Theme::all();
+
+    //The preferences saved when the application was last closed
+    let preferences = Preferences::read();
+    preferences.apply(&mut initial_state);
+    initial_state.window_size = preferences.window_width;
+    initial_state.window_height = preferences.window_height;
+
     let main_window = WindowDesc::new(build_ui)
         .title("Ebook Reader")
-        .window_size((1100., 600.));
+        .window_size((preferences.window_width, preferences.window_height));
+
+    let launcher = AppLauncher::with_window(main_window);
+    let sink = launcher.get_external_handle();
+
+    if initial_state.display_library {
+        sink.submit_command(SCAN_LIBRARY, (), Target::Auto).expect("Failed to scan the library");
+    }
 
-    AppLauncher::with_window(main_window)
-        .delegate(Delegate)
+    launcher
+        .delegate(Delegate::new(sink))
         .launch(initial_state)
         .expect("Failed to launch application");
 }
@@ -0,0 +1,944 @@
+use crate::css::{ElementInfo, Style, Stylesheet};
+
+/*
+This module turns the XHTML of a page into a typed model of blocks (paragraphs, headings, lists,
+block quotes, images, tables and preformatted text) that is displayed by the view. The parser is
+tolerant: pages are pieces of a file, so they can start or end in the middle of an element.
+*/
+
+pub const VOID_TAGS: [&str; 14] = ["img", "br", "hr", "meta", "link", "input", "col", "area", "base", "wbr",
+    "source", "image", "param", "embed"];
+
+const SKIPPED_TAGS: [&str; 4] = ["head", "script", "style", "title"];
+
+const BLOCK_TAGS: [&str; 32] = ["p", "div", "h1", "h2", "h3", "h4", "h5", "h6", "ul", "ol", "li", "blockquote",
+    "pre", "table", "thead", "tbody", "tfoot", "tr", "td", "th", "section", "article", "aside", "nav",
+    "header", "footer", "figure", "figcaption", "hr", "dl", "dt", "dd"];
+
+/*
+A node of the document tree built from the HTML of a page.
+*/
+#[derive(Clone, Debug)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+    pub style: Style,
+}
+
+impl Element {
+    pub fn new(name: String, attributes: Vec<(String, String)>) -> Self {
+        Self {
+            name,
+            attributes,
+            children: Vec::new(),
+            style: Style::default(),
+        }
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/*
+The inline formatting of a piece of text, given by the elements containing it and by the stylesheet:
+<code> is monospace, <sup>, <sub> and <small> are smaller and <a> is a link to its href, while bold,
+italic and small caps come from the computed style. Links marked as references to a footnote have
+the noteref flag.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpanStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub small_caps: bool,
+    pub underline: bool,
+    pub monospace: bool,
+    pub small: bool,
+    pub link: Option<String>,
+    pub noteref: bool,
+}
+
+/*
+A piece of text inside a block, with its formatting.
+*/
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub text: String,
+    pub style: SpanStyle,
+}
+
+/*
+A block of a page with the style computed from the stylesheets of the Ebook.
+*/
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub kind: BlockKind,
+    pub style: Style,
+}
+
+/*
+The kinds of blocks a page is made of. Lists and block quotes contain other blocks, tables contain
+rows of cells made of spans.
+*/
+#[derive(Clone, Debug)]
+pub enum BlockKind {
+    Paragraph(Vec<Span>),
+    Heading(usize, Vec<Span>),
+    List { ordered: bool, start: usize, items: Vec<Vec<Block>> },
+    Quote(Vec<Block>),
+    Image(String),
+    Table(Vec<Vec<Vec<Span>>>),
+    Preformatted(String),
+}
+
+/*
+This function parses the HTML of a page, applies the stylesheet to its elements and returns its
+blocks. Elements hidden by the stylesheet are skipped.
+*/
+pub fn parse_blocks(html: &str, stylesheet: &Stylesheet) -> Vec<Block> {
+    let mut nodes = parse_html(html);
+    apply_stylesheet(&mut nodes, stylesheet, &mut Vec::new(), &Style::default());
+
+    parse_children(&nodes, &SpanStyle::default(), &Style::default())
+}
+
+/*
+This function computes the style of every element of the document tree, given the elements
+containing the nodes and the style of their parent.
+*/
+pub fn apply_stylesheet(nodes: &mut Vec<Node>, stylesheet: &Stylesheet, ancestors: &mut Vec<ElementInfo>, parent: &Style) {
+    for node in nodes.iter_mut() {
+        if let Node::Element(element) = node {
+            element.style = stylesheet.compute(element, ancestors, parent);
+
+            ancestors.push(ElementInfo::of(element));
+            let style = element.style.clone();
+            apply_stylesheet(&mut element.children, stylesheet, ancestors, &style);
+            ancestors.pop();
+        }
+    }
+}
+
+/*
+This function builds the document tree of an HTML fragment. Unclosed elements are closed at the end
+of the fragment and closing tags without the corresponding opening tag are ignored.
+*/
+pub fn parse_html(html: &str) -> Vec<Node> {
+    let mut stack: Vec<Element> = vec![Element::new(String::new(), Vec::new())];
+    let mut pos = 0;
+
+    while pos < html.len() {
+        let rest = &html[pos..];
+
+        if rest.starts_with("<!--") {
+            pos += rest.find("-->").map(|end| end + 3).unwrap_or(rest.len());
+        } else if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").unwrap_or(rest.len());
+            push_text(&mut stack, &rest[9..end.max(9)]);
+            pos += (end + 3).min(rest.len());
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            pos += rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+        } else if rest.starts_with("</") {
+            let end = rest.find('>').unwrap_or(rest.len());
+            let name = local_name(rest[2..end].trim());
+            close_element(&mut stack, &name);
+            pos += (end + 1).min(rest.len());
+        } else if rest.starts_with('<') && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let (name, attributes, self_closing, length) = parse_tag(rest);
+            pos += length;
+
+            if SKIPPED_TAGS.contains(&name.as_str()) && !self_closing {
+                pos = skip_element(html, pos, &name);
+                continue;
+            }
+
+            close_implicitly(&mut stack, &name);
+
+            let element = Element::new(name.clone(), attributes);
+            if self_closing || VOID_TAGS.contains(&name.as_str()) {
+                stack.last_mut().unwrap().children.push(Node::Element(element));
+            } else {
+                stack.push(element);
+            }
+        } else {
+            let skip = if rest.starts_with('<') { 1 } else { 0 };
+            let end = rest[skip..].find('<').map(|end| end + skip).unwrap_or(rest.len());
+            push_text(&mut stack, &decode_entities(&rest[..end]));
+            pos += end;
+        }
+    }
+
+    while stack.len() > 1 {
+        let element = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(Node::Element(element));
+    }
+
+    stack.pop().unwrap().children
+}
+
+/*
+This function checks if an open element is closed implicitly by the start tag of another one.
+*/
+fn closed_by(top: &str, name: &str) -> bool {
+    match name {
+        "li" => top == "li",
+        "td" | "th" => top == "td" || top == "th",
+        "tr" => top == "tr" || top == "td" || top == "th",
+        "dt" | "dd" => top == "dt" || top == "dd",
+        _ => top == "p" && BLOCK_TAGS.contains(&name),
+    }
+}
+
+/*
+This function returns the byte ranges of the elements of a file hidden by its stylesheet (with
+display: none), which aren't displayed nor counted in its visible text. The ranges are sorted and
+don't overlap: the hidden elements inside another hidden element aren't listed.
+*/
+pub fn hidden_ranges(html: &str, stylesheet: &Stylesheet) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut stack: Vec<(ElementInfo, Style)> = Vec::new();
+    let mut hidden: Option<(usize, usize)> = None;
+    let mut pos = 0;
+
+    while pos < html.len() {
+        let rest = &html[pos..];
+
+        if rest.starts_with("<!--") {
+            pos += rest.find("-->").map(|end| end + 3).unwrap_or(rest.len());
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            pos += rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+        } else if rest.starts_with("</") {
+            let end = rest.find('>').unwrap_or(rest.len());
+            let name = local_name(rest[2..end].trim());
+            pos += (end + 1).min(rest.len());
+
+            if let Some(index) = stack.iter().rposition(|(info, _)| info.name == name) {
+                stack.truncate(index);
+                if let Some((start, depth)) = hidden {
+                    if stack.len() < depth {
+                        ranges.push((start, pos));
+                        hidden = None;
+                    }
+                }
+            }
+        } else if rest.starts_with('<') && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let (name, attributes, self_closing, length) = parse_tag(rest);
+            let start = pos;
+            pos += length;
+
+            if SKIPPED_TAGS.contains(&name.as_str()) && !self_closing {
+                pos = skip_element(html, pos, &name);
+                continue;
+            }
+            if stack.last().map(|(top, _)| closed_by(&top.name, &name)).unwrap_or(false) {
+                stack.pop();
+            }
+
+            let element = Element::new(name.clone(), attributes);
+            let ancestors: Vec<ElementInfo> = stack.iter().map(|(info, _)| info.clone()).collect();
+            let parent = stack.last().map(|(_, style)| style.clone()).unwrap_or_default();
+            let style = stylesheet.compute(&element, &ancestors, &parent);
+            let is_hidden = style.hidden;
+
+            if self_closing || VOID_TAGS.contains(&name.as_str()) {
+                if is_hidden && hidden.is_none() {
+                    ranges.push((start, pos));
+                }
+            } else {
+                stack.push((ElementInfo::of(&element), style));
+                if is_hidden && hidden.is_none() {
+                    hidden = Some((start, stack.len()));
+                }
+            }
+        } else {
+            let skip = if rest.starts_with('<') { 1 } else { 0 };
+            pos += rest[skip..].find('<').map(|end| end + skip).unwrap_or(rest.len());
+        }
+
+        if let Some((start, depth)) = hidden {
+            if stack.len() < depth {
+                ranges.push((start, pos));
+                hidden = None;
+            }
+        }
+    }
+
+    if let Some((start, _)) = hidden {
+        ranges.push((start, html.len()));
+    }
+
+    ranges
+}
+
+/*
+This function walks through the HTML of a page as parse_html does, counting the characters of its
+visible text without whitespace: an entity is one character and the content of the skipped elements
+isn't counted, nor the content of the given hidden ranges, so the count is the same as in the
+blocks displaying the page. It returns the byte positions of the characters with the given numbers
+(starting from 0, in increasing order), or the length of the HTML for those beyond the end of the
+text, and the number of characters of the text.
+*/
+pub fn text_positions(html: &str, hidden: &[(usize, usize)], counts: &[usize]) -> (Vec<usize>, usize) {
+    let mut positions = Vec::new();
+    let mut chars = 0;
+    let mut pos = 0;
+    let mut next_hidden = 0;
+
+    /*
+    This closure is called for every visible character, returning true when all the positions have
+    been found.
+    */
+    let mut visit = |at: usize, chars: &mut usize| -> bool {
+        while positions.len() < counts.len() && counts[positions.len()] <= *chars {
+            positions.push(at);
+        }
+        *chars += 1;
+        positions.len() == counts.len() && counts.len() > 0
+    };
+
+    while pos < html.len() {
+        while next_hidden < hidden.len() && hidden[next_hidden].1 <= pos {
+            next_hidden += 1;
+        }
+        if next_hidden < hidden.len() && hidden[next_hidden].0 <= pos {
+            pos = hidden[next_hidden].1;
+            continue;
+        }
+        let rest = &html[pos..];
+
+        if rest.starts_with("<!--") {
+            pos += rest.find("-->").map(|end| end + 3).unwrap_or(rest.len());
+        } else if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").unwrap_or(rest.len()).max(9);
+            for (i, c) in rest[9..end].char_indices() {
+                if !c.is_whitespace() && visit(pos + 9 + i, &mut chars) {
+                    return (positions, chars);
+                }
+            }
+            pos += (end + 3).min(rest.len());
+        } else if rest.starts_with("<!") || rest.starts_with("<?") || rest.starts_with("</") {
+            pos += rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+        } else if rest.starts_with('<') && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let (name, _, self_closing, length) = parse_tag(rest);
+            pos += length;
+
+            if SKIPPED_TAGS.contains(&name.as_str()) && !self_closing {
+                pos = skip_element(html, pos, &name);
+            }
+        } else {
+            let skip = if rest.starts_with('<') { 1 } else { 0 };
+            let end = rest[skip..].find('<').map(|end| end + skip).unwrap_or(rest.len());
+            let mut i = 0;
+
+            while i < end {
+                let entity = rest[i..end].find(';')
+                    .filter(|e| rest[i..].starts_with('&') && *e < 12)
+                    .map(|e| decode_entities(&rest[i..i + e + 1]))
+                    .filter(|decoded| decoded.chars().count() == 1);
+                let (c, length) = match entity {
+                    Some(decoded) => (decoded.chars().next().unwrap(), rest[i..end].find(';').unwrap() + 1),
+                    None => {
+                        let c = rest[i..].chars().next().unwrap();
+                        (c, c.len_utf8())
+                    }
+                };
+
+                if !c.is_whitespace() && visit(pos + i, &mut chars) {
+                    return (positions, chars);
+                }
+                i += length;
+            }
+            pos += end;
+        }
+    }
+
+    positions.resize(counts.len(), html.len());
+    (positions, chars)
+}
+
+/*
+This function returns the position following the end tag of a skipped element whose start tag ends
+at pos, or the end of the HTML if the element isn't closed.
+*/
+fn skip_element(html: &str, pos: usize, name: &str) -> usize {
+    let close = format!("</{}", name);
+    let lower = html[pos..].to_ascii_lowercase();
+
+    pos + lower.find(close.as_str())
+        .map(|end| end + html[pos + end..].find('>').map(|e| e + 1).unwrap_or(0))
+        .unwrap_or(html.len() - pos)
+}
+
+/*
+This function parses a start tag returning its name, its attributes, if it is self-closing and its
+length in bytes.
+*/
+pub fn parse_tag(rest: &str) -> (String, Vec<(String, String)>, bool, usize) {
+    let bytes = rest.as_bytes();
+    let mut i = 1;
+
+    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' && bytes[i] != b'/' {
+        i += 1;
+    }
+    let name = local_name(&rest[1..i]);
+    let mut attributes = Vec::new();
+    let mut self_closing = false;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'>' => return (name, attributes, self_closing, i + 1),
+            b'/' => {
+                self_closing = true;
+                i += 1;
+            }
+            c if c.is_ascii_whitespace() => i += 1,
+            _ => {
+                self_closing = false;
+                let start = i;
+                while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'=' && bytes[i] != b'>' && bytes[i] != b'/' {
+                    i += 1;
+                }
+                let key = rest[start..i].to_ascii_lowercase();
+
+                while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+
+                let mut value = String::new();
+                if i < bytes.len() && bytes[i] == b'=' {
+                    i += 1;
+                    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                        i += 1;
+                    }
+                    if i < bytes.len() && (bytes[i] == b'"' || bytes[i] == b'\'') {
+                        let quote = bytes[i];
+                        let end = rest[i + 1..].find(quote as char).map(|end| i + 1 + end).unwrap_or(rest.len());
+                        value = decode_entities(&rest[i + 1..end]);
+                        i = (end + 1).min(rest.len());
+                    } else {
+                        let start = i;
+                        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                            i += 1;
+                        }
+                        value = decode_entities(&rest[start..i]);
+                    }
+                }
+                attributes.push((key, value));
+            }
+        }
+    }
+
+    (name, attributes, self_closing, rest.len())
+}
+
+/*
+This function returns the lowercase name of a tag without its namespace prefix.
+*/
+fn local_name(name: &str) -> String {
+    name.rsplit(':').next().unwrap_or(name).to_ascii_lowercase()
+}
+
+fn push_text(stack: &mut Vec<Element>, text: &str) {
+    if text.len() > 0 {
+        stack.last_mut().unwrap().children.push(Node::Text(text.to_string()));
+    }
+}
+
+/*
+This function closes the innermost open element with the given name and all the elements opened
+inside it. Nothing happens if there isn't such an element.
+*/
+fn close_element(stack: &mut Vec<Element>, name: &str) {
+    if let Some(index) = stack.iter().rposition(|element| element.name == name) {
+        if index == 0 {
+            return;
+        }
+        while stack.len() > index {
+            let element = stack.pop().unwrap();
+            stack.last_mut().unwrap().children.push(Node::Element(element));
+        }
+    }
+}
+
+/*
+This function closes the elements that HTML closes implicitly before the given tag, as a paragraph
+before a block or a list item before the next one.
+*/
+fn close_implicitly(stack: &mut Vec<Element>, name: &str) {
+    let top = stack.last().unwrap().name.clone();
+
+    if closed_by(&top, name) {
+        close_element(stack, &top);
+        if name == "tr" && stack.last().unwrap().name == "tr" {
+            close_element(stack, "tr");
+        }
+    }
+}
+
+/*
+This function walks through the document tree filling the list of blocks. Text and inline elements
+are collected in spans until a block element is found, then they become a paragraph with the style
+of the container. The left and right margins of the containers are added to the blocks inside them.
+*/
+fn collect_blocks(nodes: &Vec<Node>, style: &SpanStyle, container: &Style, blocks: &mut Vec<Block>, spans: &mut Vec<Span>) {
+    for node in nodes {
+        let element = match node {
+            Node::Text(text) => {
+                push_span(spans, text, style);
+                continue;
+            }
+            Node::Element(element) if element.style.hidden => continue,
+            Node::Element(element) => element,
+        };
+
+        let span_style = &inline_style(element, style);
+        let block_style = nested_style(element, container);
+
+        match element.name.as_str() {
+            "br" => spans.push(Span { text: "\n".to_string(), style: style.clone() }),
+            "img" | "image" => {
+                let src = element.attribute("src")
+                    .or(element.attribute("xlink:href"))
+                    .or(element.attribute("href"));
+                if let Some(src) = src {
+                    flush_paragraph(blocks, spans, container);
+                    blocks.push(Block { kind: BlockKind::Image(src.to_string()), style: block_style });
+                }
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                flush_paragraph(blocks, spans, container);
+                let level = element.name[1..].parse::<usize>().unwrap_or(1);
+                let mut heading = Vec::new();
+                collect_inline(&element.children, span_style, &block_style, blocks, &mut heading);
+                if let Some(heading) = trim_spans(heading) {
+                    blocks.push(Block { kind: BlockKind::Heading(level, heading), style: block_style });
+                }
+            }
+            "ul" | "ol" => {
+                flush_paragraph(blocks, spans, container);
+                let start = element.attribute("start").and_then(|s| s.parse::<usize>().ok()).unwrap_or(1);
+                let inner = Style::inherit(&element.style);
+                let mut items = Vec::new();
+
+                for child in element.children.iter() {
+                    match child {
+                        Node::Element(item) if item.style.hidden => {}
+                        Node::Element(item) if item.name == "li" => {
+                            items.push(parse_children(&item.children, &inline_style(item, span_style), &Style::inherit(&item.style)));
+                        }
+                        Node::Text(text) if text.trim().len() == 0 => {}
+                        other => {
+                            let item = parse_children(&vec![other.clone()], span_style, &inner);
+                            if item.len() > 0 {
+                                items.push(item);
+                            }
+                        }
+                    }
+                }
+                blocks.push(Block { kind: BlockKind::List { ordered: element.name == "ol", start, items }, style: block_style });
+            }
+            "blockquote" => {
+                flush_paragraph(blocks, spans, container);
+                let quote = parse_children(&element.children, span_style, &Style::inherit(&element.style));
+                blocks.push(Block { kind: BlockKind::Quote(quote), style: block_style });
+            }
+            "pre" => {
+                flush_paragraph(blocks, spans, container);
+                let mut text = String::new();
+                raw_text(&element.children, &mut text);
+                blocks.push(Block { kind: BlockKind::Preformatted(text.trim_matches('\n').to_string()), style: block_style });
+            }
+            "table" => {
+                flush_paragraph(blocks, spans, container);
+                let mut rows = Vec::new();
+                collect_rows(&element.children, span_style, &mut rows);
+                blocks.push(Block { kind: BlockKind::Table(rows), style: block_style });
+            }
+            name if BLOCK_TAGS.contains(&name) || name == "body" || name == "html" => {
+                flush_paragraph(blocks, spans, container);
+                collect_blocks(&element.children, span_style, &block_style, blocks, spans);
+                flush_paragraph(blocks, spans, &block_style);
+            }
+            _ => collect_blocks(&element.children, span_style, container, blocks, spans),
+        }
+    }
+}
+
+/*
+This function returns the style of the text inside an element, adding the formatting of the element
+to the style of its parent.
+*/
+fn inline_style(element: &Element, parent: &SpanStyle) -> SpanStyle {
+    let mut style = parent.clone();
+
+    style.bold = element.style.bold.unwrap_or(style.bold);
+    style.italic = element.style.italic.unwrap_or(style.italic);
+    style.small_caps = element.style.small_caps.unwrap_or(style.small_caps);
+
+    match element.name.as_str() {
+        "u" | "ins" => style.underline = true,
+        "code" | "tt" | "kbd" | "samp" => style.monospace = true,
+        "sup" | "sub" | "small" => style.small = true,
+        "a" => {
+            if let Some(href) = element.attribute("href") {
+                style.link = Some(href.to_string());
+                style.noteref = has_semantic(element, &["noteref", "doc-noteref"]);
+            }
+        }
+        _ => {}
+    }
+
+    style
+}
+
+/*
+This function returns the style of a block element placed inside a container: the left and right
+margins of the container are added to the ones of the element.
+*/
+fn nested_style(element: &Element, container: &Style) -> Style {
+    let mut style = element.style.clone();
+
+    if container.margin_left.is_some() {
+        style.margin_left = Some(style.margin_left.unwrap_or(0.) + container.margin_left.unwrap_or(0.));
+    }
+    if container.margin_right.is_some() {
+        style.margin_right = Some(style.margin_right.unwrap_or(0.) + container.margin_right.unwrap_or(0.));
+    }
+
+    style
+}
+
+/*
+This function collects the inline content of an element (as a heading) in the given spans. Images
+found inside it are added to the blocks.
+*/
+fn collect_inline(nodes: &Vec<Node>, style: &SpanStyle, container: &Style, blocks: &mut Vec<Block>, spans: &mut Vec<Span>) {
+    let mut inner = Vec::new();
+    collect_blocks(nodes, style, container, &mut inner, spans);
+
+    for block in inner {
+        match block.kind {
+            BlockKind::Paragraph(paragraph) => {
+                push_span(spans, " ", style);
+                spans.extend(paragraph);
+            }
+            _ => blocks.push(block),
+        }
+    }
+}
+
+fn parse_children(nodes: &Vec<Node>, style: &SpanStyle, container: &Style) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut spans = Vec::new();
+
+    collect_blocks(nodes, style, container, &mut blocks, &mut spans);
+    flush_paragraph(&mut blocks, &mut spans, container);
+
+    blocks
+}
+
+/*
+This function collects the rows of a table, looking inside <thead>, <tbody> and <tfoot>.
+*/
+fn collect_rows(nodes: &Vec<Node>, style: &SpanStyle, rows: &mut Vec<Vec<Vec<Span>>>) {
+    for node in nodes {
+        if let Node::Element(element) = node {
+            if element.style.hidden {
+                continue;
+            }
+
+            match element.name.as_str() {
+                "tr" => {
+                    let mut row = Vec::new();
+                    for cell in element.children.iter() {
+                        if let Node::Element(cell) = cell {
+                            if cell.name == "td" || cell.name == "th" {
+                                let mut blocks = Vec::new();
+                                let mut spans = Vec::new();
+                                collect_inline(&cell.children, &inline_style(cell, style), &cell.style, &mut blocks, &mut spans);
+                                row.push(trim_spans(spans).unwrap_or_default());
+                            }
+                        }
+                    }
+                    rows.push(row);
+                }
+                _ => collect_rows(&element.children, &inline_style(element, style), rows),
+            }
+        }
+    }
+}
+
+/*
+This function appends the text of the nodes without collapsing the whitespace, as needed by <pre>.
+*/
+fn raw_text(nodes: &Vec<Node>, text: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => text.push_str(t),
+            Node::Element(element) if element.name == "br" => text.push('\n'),
+            Node::Element(element) => raw_text(&element.children, text),
+        }
+    }
+}
+
+/*
+This function adds some text to the spans collapsing its whitespace as HTML does.
+*/
+fn push_span(spans: &mut Vec<Span>, text: &str, style: &SpanStyle) {
+    let mut collapsed = String::new();
+    let ends_with_space = spans.last().map(|s| s.text.ends_with(|c: char| c == ' ' || c == '\n')).unwrap_or(true);
+    let mut last_space = ends_with_space;
+
+    for c in text.chars() {
+        if c.is_whitespace() && c != '\u{a0}' {
+            if !last_space {
+                collapsed.push(' ');
+                last_space = true;
+            }
+        } else {
+            collapsed.push(c);
+            last_space = false;
+        }
+    }
+
+    if collapsed.len() > 0 {
+        match spans.last_mut() {
+            Some(last) if last.style == *style => last.text.push_str(&collapsed),
+            _ => spans.push(Span { text: collapsed, style: style.clone() }),
+        }
+    }
+}
+
+/*
+This function removes the whitespace at the beginning and at the end of a list of spans, returning
+None if no text is left.
+*/
+fn trim_spans(mut spans: Vec<Span>) -> Option<Vec<Span>> {
+    while spans.first().map(|s| s.text.trim().len() == 0).unwrap_or(false) {
+        spans.remove(0);
+    }
+    while spans.last().map(|s| s.text.trim().len() == 0).unwrap_or(false) {
+        spans.pop();
+    }
+
+    if spans.len() == 0 {
+        return None;
+    }
+
+    let first = spans.first_mut().unwrap();
+    first.text = first.text.trim_start().to_string();
+    let last = spans.last_mut().unwrap();
+    last.text = last.text.trim_end().to_string();
+
+    Some(spans)
+}
+
+fn flush_paragraph(blocks: &mut Vec<Block>, spans: &mut Vec<Span>, style: &Style) {
+    if let Some(paragraph) = trim_spans(std::mem::take(spans)) {
+        blocks.push(Block { kind: BlockKind::Paragraph(paragraph), style: style.clone() });
+    }
+}
+
+/*
+This function checks if the epub:type or the role attribute of an element contains one of the
+given values.
+*/
+fn has_semantic(element: &Element, values: &[&str]) -> bool {
+    ["epub:type", "role"].iter().any(|attribute| {
+        element.attribute(attribute)
+            .map(|value| value.split_whitespace().any(|v| values.contains(&v)))
+            .unwrap_or(false)
+    })
+}
+
+/*
+This function searches the document tree for the element with the given id, returning the chain of
+its ancestors ending with the element itself.
+*/
+fn find_element<'a>(nodes: &'a Vec<Node>, id: &str) -> Option<Vec<&'a Element>> {
+    for node in nodes {
+        if let Node::Element(element) = node {
+            if element.attribute("id") == Some(id) || (element.name == "a" && element.attribute("name") == Some(id)) {
+                return Some(vec![element]);
+            }
+            if let Some(mut chain) = find_element(&element.children, id) {
+                chain.insert(0, element);
+                return Some(chain);
+            }
+        }
+    }
+
+    None
+}
+
+/*
+This function returns the text of the footnote with the given id in an HTML document. The target is
+considered a footnote when it (or one of its ancestors) is marked as a footnote, an endnote or an
+<aside>, or when the link pointing to it is marked as a noteref. If the target is an empty anchor,
+the text of the block containing it is returned. None is returned if the target isn't a footnote.
+*/
+pub fn footnote_text(html: &str, id: &str, noteref: bool) -> Option<String> {
+    let nodes = parse_html(html);
+    let chain = find_element(&nodes, id)?;
+
+    let note = chain.iter().rposition(|element| {
+        element.name == "aside" || has_semantic(element, &["footnote", "endnote", "rearnote", "note", "doc-footnote", "doc-endnote"])
+    });
+    if note.is_none() && !noteref {
+        return None;
+    }
+
+    let mut index = note.unwrap_or(chain.len() - 1);
+    while index > 0 && !BLOCK_TAGS.contains(&chain[index].name.as_str()) && chain[index].name != "aside" {
+        index -= 1;
+    }
+    if chain[index].name == "html" || chain[index].name == "body" {
+        return None;
+    }
+
+    let mut text = Vec::new();
+    for block in parse_children(&chain[index].children, &SpanStyle::default(), &Style::default()) {
+        match block.kind {
+            BlockKind::Paragraph(spans) | BlockKind::Heading(_, spans) => text.push(spans_text(&spans)),
+            BlockKind::Preformatted(pre) => text.push(pre),
+            _ => {}
+        }
+    }
+
+    if text.len() > 0 {
+        Some(text.join("\n"))
+    } else {
+        None
+    }
+}
+
+/*
+This function returns the text of a list of blocks, one line for every paragraph, heading, list item,
+table row and preformatted text.
+*/
+pub fn blocks_text(blocks: &Vec<Block>) -> String {
+    let mut lines = Vec::new();
+
+    for block in blocks {
+        match &block.kind {
+            BlockKind::Paragraph(spans) | BlockKind::Heading(_, spans) => lines.push(spans_text(spans)),
+            BlockKind::List { items, .. } => lines.extend(items.iter().map(blocks_text)),
+            BlockKind::Quote(blocks) => lines.push(blocks_text(blocks)),
+            BlockKind::Table(rows) => lines.extend(rows.iter()
+                .map(|row| row.iter().map(spans_text).collect::<Vec<String>>().join(" "))),
+            BlockKind::Preformatted(text) => lines.push(text.clone()),
+            BlockKind::Image(_) => {}
+        }
+    }
+
+    lines.join("\n")
+}
+
+/*
+This function returns the whole text of a list of spans.
+*/
+pub fn spans_text(spans: &Vec<Span>) -> String {
+    spans.iter().map(|span| span.text.as_str()).collect()
+}
+
+/*
+This function replaces the character references of HTML (&amp;, &#8212;, &#x2014;...) with the
+corresponding characters. Unknown references are left as they are.
+*/
+pub fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+
+    let mut decoded = String::new();
+    let mut rest = text;
+
+    while let Some(pos) = rest.find('&') {
+        decoded.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        let end = rest.find(';').filter(|end| *end < 12);
+        let character = end.and_then(|end| {
+            let name = &rest[1..end];
+            if let Some(hex) = name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+                u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+            } else if let Some(number) = name.strip_prefix('#') {
+                number.parse::<u32>().ok().and_then(char::from_u32)
+            } else {
+                named_entity(name)
+            }
+        });
+
+        match (character, end) {
+            (Some(character), Some(end)) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    decoded
+}
+
+fn named_entity(name: &str) -> Option<char> {
+    let character = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "shy" => '\u{ad}',
+        "mdash" => '—',
+        "ndash" => '–',
+        "hellip" => '…',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "deg" => '°',
+        "middot" => '·',
+        "bull" => '•',
+        "times" => '×',
+        "sect" => '§',
+        "para" => '¶',
+        "dagger" => '†',
+        "Dagger" => '‡',
+        "eacute" => 'é',
+        "egrave" => 'è',
+        "agrave" => 'à',
+        "aacute" => 'á',
+        "ograve" => 'ò',
+        "oacute" => 'ó',
+        "ugrave" => 'ù',
+        "uacute" => 'ú',
+        "igrave" => 'ì',
+        "iacute" => 'í',
+        "ccedil" => 'ç',
+        "ntilde" => 'ñ',
+        "auml" => 'ä',
+        "ouml" => 'ö',
+        "uuml" => 'ü',
+        "szlig" => 'ß',
+        _ => return None,
+    };
+
+    Some(character)
+}
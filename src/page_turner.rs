@@ -0,0 +1,135 @@
+use druid::{BoxConstraints, Env, Event, EventCtx, LayoutCtx, LifeCycle, LifeCycleCtx, MouseButton, PaintCtx, Point, Size, UpdateCtx, Widget, WidgetPod};
+use druid::widget::Scroll;
+use crate::data::AppState;
+
+/*
+The distance the user has to scroll past the top or the bottom of a page to turn it, so that the
+inertia of a touchpad reaching the end of the page doesn't turn it by itself.
+*/
+const OVERSCROLL: f64 = 120.;
+
+/*
+The distance the mouse can move between pressing and releasing the button for a click.
+*/
+const CLICK_DISTANCE: f64 = 5.;
+
+/*
+The widget wrapping the reading area, which turns the pages without the Previous/Next buttons:
+scrolling past the bottom of a page moves to the next one and scrolling past the top to the
+previous one, and a click on the left or right third of the reading area moves backward or forward.
+A click only turns the page when it doesn't follow a link, press a button, select some text or
+clear the current selection. Both behaviours can be disabled in the settings row.
+*/
+pub struct PageTurner<W> {
+    scroll: WidgetPod<AppState, Scroll<AppState, W>>,
+    overscroll: f64,
+    pressed: Option<Point>,
+}
+
+impl<W: Widget<AppState>> PageTurner<W> {
+    pub fn new(scroll: Scroll<AppState, W>) -> Self {
+        Self {
+            scroll: WidgetPod::new(scroll),
+            overscroll: 0.,
+            pressed: None,
+        }
+    }
+
+    /*
+    This function checks if a wheel movement pushes past the top or the bottom of the page, adding
+    it to the distance scrolled past the end. It returns the direction to turn the page to (1 for
+    the next page, -1 for the previous one) once that distance is reached.
+    */
+    fn overscrolled(&mut self, delta: f64) -> Option<i32> {
+        let scroll = self.scroll.widget();
+        let offset = scroll.offset().y;
+        let hidden = scroll.child_size().height - self.scroll.layout_rect().height();
+
+        let past_end = (delta > 0. && offset >= hidden - 1.) || (delta < 0. && offset <= 1.);
+        if !past_end || self.overscroll * delta < 0. {
+            self.overscroll = 0.;
+        }
+        if !past_end {
+            return None;
+        }
+
+        self.overscroll += delta;
+        if self.overscroll.abs() < OVERSCROLL {
+            return None;
+        }
+
+        self.overscroll = 0.;
+        Some(if delta > 0. { 1 } else { -1 })
+    }
+}
+
+/*
+This function turns the page forward or backward, as the Previous/Next buttons do.
+*/
+fn turn_page(direction: i32, ctx: &mut EventCtx, data: &mut AppState, env: &Env) {
+    if direction > 0 {
+        AppState::click_next_button(ctx, data, env);
+    } else {
+        AppState::click_previous_button(ctx, data, env);
+    }
+}
+
+impl<W: Widget<AppState>> Widget<AppState> for PageTurner<W> {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        let turning = data.ebook.len() > 0 && !data.edit_mode;
+
+        match event {
+            Event::Wheel(mouse) if turning && data.wheel_turning => {
+                if let Some(direction) = self.overscrolled(mouse.wheel_delta.y) {
+                    turn_page(direction, ctx, data, env);
+                    ctx.set_handled();
+                    return;
+                }
+            }
+            Event::MouseDown(mouse) if mouse.button == MouseButton::Left => {
+                let selected = data.selection.is_some();
+                self.scroll.event(ctx, event, data, env);
+
+                self.pressed = if ctx.is_handled() || self.scroll.has_active() || selected { None } else { Some(mouse.pos) };
+                return;
+            }
+            Event::MouseUp(mouse) if mouse.button == MouseButton::Left => {
+                self.scroll.event(ctx, event, data, env);
+
+                let clicked = self.pressed.take()
+                    .map(|pressed| pressed.distance(mouse.pos) <= CLICK_DISTANCE)
+                    .unwrap_or(false);
+                if turning && data.click_turning && clicked && data.selection.is_none() {
+                    let width = ctx.size().width;
+                    if mouse.pos.x < width / 3. {
+                        turn_page(-1, ctx, data, env);
+                    } else if mouse.pos.x > width * 2. / 3. {
+                        turn_page(1, ctx, data, env);
+                    }
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        self.scroll.event(ctx, event, data, env);
+    }
+
+    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &AppState, env: &Env) {
+        self.scroll.lifecycle(ctx, event, data, env);
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx, _old_data: &AppState, data: &AppState, env: &Env) {
+        self.scroll.update(ctx, data, env);
+    }
+
+    fn layout(&mut self, ctx: &mut LayoutCtx, bc: &BoxConstraints, data: &AppState, env: &Env) -> Size {
+        let size = self.scroll.layout(ctx, bc, data, env);
+        self.scroll.set_origin(ctx, data, env, Point::ORIGIN);
+        size
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &AppState, env: &Env) {
+        self.scroll.paint(ctx, data, env);
+    }
+}
@@ -0,0 +1,496 @@
+use druid::im::Vector;
+use crate::css::{ElementInfo, Style, Stylesheet};
+use crate::data::{Chapter, Page, Section};
+use crate::loader::attribute_value;
+use crate::markup::{parse_blocks, parse_tag, spans_text, Block, BlockKind, Element, Span, VOID_TAGS};
+
+const BLOCK_TAGS: [&str; 20] = ["p", "div", "h1", "h2", "h3", "h4", "h5", "h6", "li", "ul", "ol", "blockquote",
+    "pre", "table", "tr", "figure", "section", "dl", "dd", "dt"];
+
+/*
+This struct describes the space available to display a page: the size of the font, the height of
+the lines (in multiples of the font size), the space after the paragraphs (in em) and the width and
+the height of the area containing the text.
+*/
+pub struct PageLayout {
+    pub font_size: f64,
+    pub line_height: f64,
+    pub paragraph_spacing: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl PageLayout {
+    fn line_height(&self) -> f64 {
+        self.font_size * self.line_height
+    }
+
+    fn chars_per_line(&self) -> usize {
+        chars_per_line(self.width, self.font_size)
+    }
+}
+
+/*
+This struct keeps track of the elements that are open at a position of a file while it is scanned,
+with the text of their start tags.
+*/
+struct OpenElements {
+    stack: Vec<(String, ElementInfo)>,
+    pos: usize,
+}
+
+impl OpenElements {
+    fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /*
+    This function scans the tags of the content from the last position up to the given one, opening
+    and closing the elements.
+    */
+    fn advance(&mut self, content: &str, to: usize) {
+        while let Some(open) = content[self.pos..to].find('<') {
+            let open = self.pos + open;
+            let close = match content[open..to].find('>') {
+                Some(close) => open + close + 1,
+                None => break,
+            };
+            let tag = &content[open..close];
+
+            if tag.starts_with("</") {
+                let name = tag[2..tag.len() - 1].trim().rsplit(':').next().unwrap_or("").to_ascii_lowercase();
+                if let Some(index) = self.stack.iter().rposition(|(_, info)| info.name == name) {
+                    self.stack.truncate(index);
+                }
+            } else if !tag.starts_with("<!") && !tag.starts_with("<?") {
+                let (name, attributes, self_closing, _) = parse_tag(tag);
+                if !self_closing && !VOID_TAGS.contains(&name.as_str()) {
+                    let element = Element::new(name, attributes);
+                    self.stack.push((tag.to_string(), ElementInfo::of(&element)));
+                }
+            }
+            self.pos = close;
+        }
+    }
+
+    fn context(&self) -> String {
+        self.stack.iter().map(|(tag, _)| tag.as_str()).collect()
+    }
+
+    fn ancestors(&self) -> Vec<ElementInfo> {
+        self.stack.iter().map(|(_, info)| info.clone()).collect()
+    }
+}
+
+/*
+This function splits the content of every section of the ebook into pages that fit the given
+layout. The content is measured block by block (paragraphs, headings, images...) estimating the
+number of lines needed by the text, and a block taller than a whole page is split between words.
+A new page is also started before the elements with the CSS property page-break-before.
+Concatenating the pages of a section gives back its original content.
+*/
+pub fn paginate(sections: &Vector<Section>, layout: &PageLayout) -> Vector<Page> {
+    paginate_after(&Vector::new(), sections, layout)
+}
+
+/*
+This function splits the given sections into pages as paginate does, as if they followed the pages
+already computed for the previous sections: the number of the printed page continues from the last
+of them. Since a page never spans two sections, the sections read while the Ebook is loading can be
+paginated without paginating the previous ones again.
+*/
+pub fn paginate_after(previous: &Vector<Page>, sections: &Vector<Section>, layout: &PageLayout) -> Vector<Page> {
+    let mut pages = Vector::new();
+    let mut print_page = previous.last().map(|page| page.print_page.clone()).unwrap_or_default();
+
+    for section in sections.iter() {
+        let content = section.content.as_str();
+        let stylesheet = &section.stylesheet;
+        let mut open_elements = OpenElements::new();
+        let mut cuts = vec![0];
+        let mut used = 0.;
+
+        for (start, end) in split_blocks(content) {
+            open_elements.advance(content, start);
+            let context = open_elements.context();
+            let height = block_height(&content[start..end], &context, stylesheet, section, layout);
+
+            if used > 0. && breaks_page(&content[start..end], stylesheet, &open_elements) {
+                cuts.push(start);
+                used = 0.;
+            }
+
+            if used > 0. && used + height > layout.height {
+                cuts.push(start);
+                used = 0.;
+            }
+
+            if height > layout.height {
+                let capacity = (layout.height / layout.line_height()).floor().max(1.) as usize * layout.chars_per_line();
+                let mut from = start;
+
+                while let Some(cut) = split_point(content, from, end, capacity) {
+                    cuts.push(cut);
+                    from = cut;
+                }
+                used = block_height(&content[from..end], &context, stylesheet, section, layout);
+            } else {
+                used += height;
+            }
+        }
+
+        cuts.dedup();
+        cuts.push(content.len());
+
+        let markers = print_page_markers(content);
+        let mut open_elements = OpenElements::new();
+
+        for window in cuts.windows(2) {
+            let (start, end) = (window[0], window[1]);
+            if start >= end {
+                continue;
+            }
+
+            let mut page = Page::new();
+            page.text = content[start..end].to_string();
+            page.source = section.source.clone();
+            page.start = start;
+
+            open_elements.advance(content, start);
+            page.context = open_elements.context();
+
+            if let Some((_, label)) = markers.iter().filter(|(pos, _)| *pos < start).last() {
+                print_page = label.clone();
+            } else if print_page.len() == 0 {
+                if let Some((_, label)) = markers.iter().find(|(pos, _)| *pos < end) {
+                    print_page = label.clone();
+                }
+            }
+            page.print_page = print_page.clone();
+
+            pages.push_back(page);
+        }
+    }
+
+    pages
+}
+
+/*
+This function updates the target page of every chapter after a new pagination, looking for the
+page containing the file and the element the chapter points to. Chapters whose target can't be
+found point to the page of their parent.
+*/
+pub fn locate_chapters(chapters: &mut Vector<Chapter>, pages: &Vector<Page>, parent_page: usize) {
+    for chapter in chapters.iter_mut() {
+        let fragment = Some(chapter.fragment.as_str());
+        chapter.target_page = page_of_target(pages, &chapter.source, fragment).unwrap_or(parent_page);
+        let page = chapter.target_page;
+        locate_chapters(&mut chapter.children, pages, page);
+    }
+}
+
+/*
+This function returns the index of the page containing the target of a link, given the full path
+of the file and the optional fragment identifying an element inside it.
+*/
+pub fn page_of_target(pages: &Vector<Page>, path: &str, fragment: Option<&str>) -> Option<usize> {
+    let first = pages.iter().position(|page| page.source == path)?;
+
+    if let Some(fragment) = fragment.filter(|f| f.len() > 0) {
+        let patterns = [
+            format!("id=\"{}\"", fragment),
+            format!("id='{}'", fragment),
+            format!("name=\"{}\"", fragment),
+        ];
+
+        for (i, page) in pages.iter().enumerate().skip(first) {
+            if page.source != path {
+                break;
+            }
+            if patterns.iter().any(|pattern| page.text.contains(pattern.as_str())) {
+                return Some(i);
+            }
+        }
+    }
+
+    Some(first)
+}
+
+/*
+This function returns the index of the page of the given file containing the byte at position
+offset of its content.
+*/
+pub fn page_at_offset(pages: &Vector<Page>, source: &str, offset: usize) -> Option<usize> {
+    let mut found = None;
+
+    for (i, page) in pages.iter().enumerate() {
+        if page.source == source {
+            if page.start <= offset || found.is_none() {
+                found = Some(i);
+            } else {
+                break;
+            }
+        } else if found.is_some() {
+            break;
+        }
+    }
+
+    found
+}
+
+/*
+This function divides the body of an HTML document into blocks, returning their byte ranges. A
+block ends after the closing tag of a block-level element, so that pages never start in the middle
+of a paragraph unless it is split on purpose. The part before the <body> tag is the first block.
+*/
+fn split_blocks(content: &str) -> Vec<(usize, usize)> {
+    let lower = content.to_ascii_lowercase();
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    let mut pos = match lower.find("<body") {
+        Some(body) => lower[body..].find('>').map(|end| body + end + 1).unwrap_or(content.len()),
+        None => 0,
+    };
+
+    if pos > 0 {
+        blocks.push((0, pos));
+        start = pos;
+    }
+
+    while let Some(open) = lower[pos..].find('<') {
+        let open = pos + open;
+        let close = match lower[open..].find('>') {
+            Some(close) => open + close + 1,
+            None => break,
+        };
+        let tag = &lower[open + 1..close - 1];
+        let name = tag.trim_start_matches('/').split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+
+        let ends_block = (tag.starts_with('/') && BLOCK_TAGS.contains(&name))
+            || name == "br" || name == "hr" || name == "img";
+
+        if ends_block && close > start {
+            blocks.push((start, close));
+            start = close;
+        }
+        pos = close;
+    }
+
+    if start < content.len() {
+        blocks.push((start, content.len()));
+    }
+
+    blocks
+}
+
+/*
+This function checks if the first element of a block has to start on a new page, according to the
+stylesheet of the file.
+*/
+fn breaks_page(block: &str, stylesheet: &Stylesheet, open_elements: &OpenElements) -> bool {
+    let block = block.trim_start();
+    if !block.starts_with('<') || block.starts_with("</") || block.starts_with("<!") || block.starts_with("<?") {
+        return false;
+    }
+
+    let (name, attributes, _, _) = parse_tag(block);
+    let element = Element::new(name, attributes);
+
+    stylesheet.compute(&element, &open_elements.ancestors(), &Style::default()).page_break_before
+}
+
+/*
+This function estimates the height of a block of HTML once it is displayed, given the start tags of
+the elements containing it. The block is parsed with the stylesheet of its file, as the view does
+when the page is displayed, so that the hidden elements are skipped and the character references
+count as the characters they stand for.
+*/
+fn block_height(block: &str, context: &str, stylesheet: &Stylesheet, section: &Section, layout: &PageLayout) -> f64 {
+    let blocks = parse_blocks(&(context.to_string() + block), stylesheet);
+    blocks_height(&blocks, section, layout, layout.width)
+}
+
+/*
+This function estimates the height of a list of blocks displayed in a column of the given width,
+following build_block in the view: the margins set by the stylesheet (in em, the paragraphs being
+followed by the paragraph spacing without them), the bigger font of the headings, the indentation
+of the lists and of the block quotes, the cells of the tables and the size of the images.
+*/
+fn blocks_height(blocks: &[Block], section: &Section, layout: &PageLayout, width: f64) -> f64 {
+    let size = layout.font_size;
+    let mut height = 0.;
+
+    for block in blocks {
+        let style = &block.style;
+        let (default_top, default_bottom, default_left) = match &block.kind {
+            BlockKind::Heading(..) => (0.5, 0.5, 0.),
+            BlockKind::Quote(_) => (0., 0., 2.),
+            _ => (0., layout.paragraph_spacing, 0.),
+        };
+        let top = style.margin_top.unwrap_or(default_top).max(0.) * size;
+        let bottom = style.margin_bottom.unwrap_or(default_bottom).max(0.) * size;
+        let left = style.margin_left.unwrap_or(default_left).max(0.) * size;
+        let right = style.margin_right.unwrap_or(0.).max(0.) * size;
+        let width = (width - left - right).max(size);
+
+        let content = match &block.kind {
+            BlockKind::Paragraph(spans) => text_height(spans, style, size, width, layout),
+            BlockKind::Heading(level, spans) => text_height(spans, style, size * heading_scale(*level), width, layout),
+            BlockKind::List { items, .. } => items.iter()
+                .map(|item| blocks_height(item, section, layout, width - size * 2.))
+                .sum(),
+            BlockKind::Quote(blocks) => blocks_height(blocks, section, layout, width),
+            BlockKind::Image(src) => match section.image_sizes.get(src) {
+                Some((image_width, image_height)) => {
+                    let scale = size / 40.;
+                    let image_width = *image_width as f64 * scale;
+                    let mut image_height = *image_height as f64 * scale;
+
+                    if image_width > width {
+                        image_height *= width / image_width;
+                    }
+                    image_height.min(layout.height) + 4.
+                }
+                None => 0.,
+            },
+            BlockKind::Table(rows) => {
+                let columns = rows.iter().map(|row| row.len()).max().unwrap_or(1).max(1);
+                let cell_width = (width / columns as f64 - 10.).max(size);
+
+                rows.iter()
+                    .map(|row| row.iter()
+                        .map(|cell| text_height(cell, &Style::default(), size, cell_width, layout) + 9.)
+                        .fold(0., f64::max))
+                    .sum()
+            }
+            BlockKind::Preformatted(text) => text.lines().count() as f64 * layout.line_height(),
+        };
+
+        height += top + content + bottom;
+    }
+
+    height
+}
+
+/*
+This function estimates the height of a paragraph displayed with the given font size in a column of
+the given width, counting the lines of its text: the first line is shortened by the indent set by
+the stylesheet, and every line break starts a new line.
+*/
+fn text_height(spans: &Vec<Span>, style: &Style, size: f64, width: f64, layout: &PageLayout) -> f64 {
+    let text = spans_text(spans);
+    if text.trim().len() == 0 {
+        return 0.;
+    }
+
+    let chars_per_line = chars_per_line(width, size);
+    let indent = (style.text_indent.unwrap_or(0.).max(0.) * 2.).round() as usize;
+    let lines: usize = text.split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            let chars = line.split_whitespace().collect::<Vec<&str>>().join(" ").chars().count();
+            let chars = if i == 0 { chars + indent } else { chars };
+            ((chars + chars_per_line - 1) / chars_per_line).max(1)
+        })
+        .sum();
+
+    lines as f64 * size * layout.line_height
+}
+
+/*
+This function returns how many characters of the given font size fit in a line of the given width,
+taking half of the font size as the average width of a character.
+*/
+fn chars_per_line(width: f64, size: f64) -> usize {
+    ((width / (size * 0.5)).floor() as usize).max(1)
+}
+
+/*
+This function returns how much bigger than the text a heading of the given level is displayed.
+*/
+pub fn heading_scale(level: usize) -> f64 {
+    match level {
+        1 | 2 => 1.6,
+        _ => 1.3,
+    }
+}
+
+/*
+This function finds where a block has to be split so that its first part contains at most capacity
+visible characters. The split is done on a space outside of the tags; a block without spaces (as
+CJK text or a long URL) is cut after capacity characters, outside of the tags and of the character
+references. None is returned if the rest of the block fits.
+*/
+fn split_point(content: &str, from: usize, end: usize, capacity: usize) -> Option<usize> {
+    let mut visible = 0;
+    let mut in_tag = false;
+    let mut reference = None;
+    let mut last_space = None;
+
+    for (i, c) in content[from..end].char_indices() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if in_tag => {}
+            _ => {
+                if c.is_whitespace() {
+                    last_space = Some(from + i);
+                }
+                visible += 1;
+                if visible > capacity {
+                    let cut = reference.unwrap_or(from + i);
+                    return last_space.filter(|space| *space > from)
+                        .or(Some(cut).filter(|cut| *cut > from));
+                }
+                match c {
+                    '&' => reference = Some(from + i),
+                    ';' => reference = None,
+                    _ if c.is_whitespace() => reference = None,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/*
+This function collects the page markers inserted by the publisher (the Gutenberg x-ebookmaker-pageno
+spans and the EPUB3 pagebreaks) with their position and the label of the printed page.
+*/
+fn print_page_markers(content: &str) -> Vec<(usize, String)> {
+    let mut markers = Vec::new();
+    let mut pos = 0;
+
+    while let Some(open) = content[pos..].find('<') {
+        let open = pos + open;
+        let close = match content[open..].find('>') {
+            Some(close) => open + close,
+            None => break,
+        };
+        let tag = &content[open..close];
+
+        if tag.contains("x-ebookmaker-pageno") || tag.contains("pagebreak") {
+            let inner = content[close + 1..].split('<').next().unwrap_or("");
+            let label = attribute_value(tag, "title")
+                .or(attribute_value(tag, "aria-label"))
+                .unwrap_or(inner.to_string());
+            let label = label.trim_matches(|c: char| c == '[' || c == ']' || c.is_whitespace())
+                .trim_start_matches("Pg")
+                .trim_start_matches("Page")
+                .trim()
+                .to_string();
+
+            if label.len() > 0 {
+                markers.push((open, label));
+            }
+        }
+        pos = close;
+    }
+
+    markers
+}
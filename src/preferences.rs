@@ -0,0 +1,146 @@
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use druid::{Data, Lens};
+use serde::{Deserialize, Serialize};
+use crate::data::{AppState, SIZE_FONT};
+use crate::storage::preferences_file;
+use crate::theme::{ImageFilter, Theme};
+use crate::typography::Typography;
+use crate::util::clamp;
+
+/*
+The values accepted for the font size and for the size of the window at launch, in pixels.
+*/
+pub const FONT_SIZE_RANGE: RangeInclusive<f64> = 6.0..=96.0;
+pub const WINDOW_WIDTH_RANGE: RangeInclusive<f64> = 400.0..=10000.0;
+pub const WINDOW_HEIGHT_RANGE: RangeInclusive<f64> = 300.0..=10000.0;
+
+/*
+The preferences of the user, kept between the launches of the application: the font size, the
+layout of the pages, the size of the window, the behaviour of the links and of the page turning,
+the theme and the typography. They are read from a json file in the configuration directory at
+launch and written when a window is closed, so also when the application is closed; the values out
+of range are brought back into their range, and the missing ones take their default value.
+*/
+#[derive(Clone, Data, Lens, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preferences {
+    pub font_size: f64,
+    pub double_page: bool,
+    pub display_menu: bool,
+    pub window_width: f64,
+    pub window_height: f64,
+    pub footnote_popover: bool,
+    pub show_print_pages: bool,
+    pub wheel_turning: bool,
+    pub click_turning: bool,
+    pub theme: String,
+    pub image_filter: ImageFilter,
+    pub typography: Typography,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            font_size: SIZE_FONT,
+            double_page: false,
+            display_menu: false,
+            window_width: 1100.,
+            window_height: 600.,
+            footnote_popover: true,
+            show_print_pages: false,
+            wheel_turning: true,
+            click_turning: true,
+            theme: Theme::default().name,
+            image_filter: ImageFilter::Normal,
+            typography: Typography::default(),
+        }
+    }
+}
+
+impl Preferences {
+    /*
+    This function reads the preferences, returning the default ones if the file doesn't exist or
+    isn't valid.
+    */
+    pub fn read() -> Self {
+        let path = preferences_file();
+        let mut preferences = match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str::<Self>(&text) {
+                Ok(preferences) => preferences,
+                Err(error) => {
+                    println!("The preferences {} aren't valid, using the default ones: {}", path.display(), error);
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        };
+
+        preferences.validate();
+        preferences
+    }
+
+    pub fn write(&self) -> io::Result<()> {
+        let path = preferences_file();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let text = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        fs::write(path, text)
+    }
+
+    /*
+    This function brings the values out of range back into their range.
+    */
+    pub fn validate(&mut self) {
+        self.font_size = clamp(self.font_size, &FONT_SIZE_RANGE);
+        self.window_width = clamp(self.window_width, &WINDOW_WIDTH_RANGE);
+        self.window_height = clamp(self.window_height, &WINDOW_HEIGHT_RANGE);
+        self.typography.validate();
+    }
+
+    /*
+    This function returns the preferences currently used by the application. The font size written
+    by the user is kept only if it is a valid number.
+    */
+    pub fn from_state(state: &AppState) -> Self {
+        let mut preferences = Self {
+            font_size: state.font_size,
+            double_page: state.double_page,
+            display_menu: state.display_menu,
+            window_width: state.window_size,
+            window_height: state.window_height,
+            footnote_popover: state.footnote_popover,
+            show_print_pages: state.show_print_pages,
+            wheel_turning: state.wheel_turning,
+            click_turning: state.click_turning,
+            theme: state.theme.clone(),
+            image_filter: state.image_filter,
+            typography: state.typography.clone(),
+        };
+
+        preferences.validate();
+        preferences
+    }
+
+    /*
+    This function applies the preferences to the application. The size of the window is only used
+    when the main window is created, and a theme that no longer exists is ignored.
+    */
+    pub fn apply(&self, state: &mut AppState) {
+        state.set_font_size(self.font_size);
+        state.double_page = self.double_page;
+        state.display_menu = self.display_menu;
+        state.footnote_popover = self.footnote_popover;
+        state.show_print_pages = self.show_print_pages;
+        state.wheel_turning = self.wheel_turning;
+        state.click_turning = self.click_turning;
+        if state.themes.iter().any(|theme| theme.name == self.theme) {
+            state.theme = self.theme.clone();
+        }
+        state.image_filter = self.image_filter;
+        state.typography = self.typography.clone();
+    }
+}
@@ -0,0 +1,67 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use druid::Data;
+use druid::im::Vector;
+use serde::{Deserialize, Serialize};
+use crate::storage::recent_file;
+
+/*
+The number of Ebooks kept in the list of the Ebooks opened recently.
+*/
+pub const MAX_RECENT: usize = 10;
+
+/*
+An Ebook opened recently: the path of its file and its title. available tells whether the file
+still exists; it isn't saved, but checked when the list is read and every time it is shown, so that
+the list can be built again without looking for the files.
+*/
+#[derive(Clone, Data, PartialEq, Serialize, Deserialize)]
+pub struct RecentBook {
+    pub path: String,
+    pub title: String,
+    #[serde(skip)]
+    pub available: bool,
+}
+
+/*
+This function checks which Ebooks of the list still exist.
+*/
+pub fn check_recent(books: &mut Vector<RecentBook>) {
+    for book in books.iter_mut() {
+        book.available = Path::new(&book.path).is_file();
+    }
+}
+
+/*
+This function reads the list of the Ebooks opened recently, the last one first. The list is empty
+if the file doesn't exist or can't be read.
+*/
+pub fn read_recent() -> Vector<RecentBook> {
+    let mut books: Vector<RecentBook> = fs::read_to_string(recent_file()).ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default();
+    check_recent(&mut books);
+
+    books
+}
+
+pub fn write_recent(books: &Vector<RecentBook>) -> io::Result<()> {
+    let path = recent_file();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let text = serde_json::to_string_pretty(books).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    fs::write(path, text)
+}
+
+/*
+This function moves an Ebook to the top of the list of the Ebooks opened recently, adding it if it
+isn't there, and drops the oldest ones beyond MAX_RECENT.
+*/
+pub fn add_recent(books: &mut Vector<RecentBook>, path: &str, title: &str) {
+    books.retain(|book| book.path != path);
+    books.push_front(RecentBook { path: path.to_string(), title: title.to_string(), available: true });
+    books.truncate(MAX_RECENT);
+}
@@ -0,0 +1,323 @@
+use std::ops::Range;
+use druid::{BoxConstraints, Color, Cursor, Env, Event, EventCtx, FontDescriptor, FontFamily, FontStyle, FontWeight, KeyOrValue, LayoutCtx, LifeCycle, LifeCycleCtx, MouseButton, PaintCtx, Point, Rect, RenderContext, Size, TextAlignment, UpdateCtx, Widget};
+use druid::piet::{TextLayout as _, TextStorage};
+use druid::text::{Attribute, RichText, TextLayout};
+use crate::css::{Style, TextAlign};
+use crate::data::{AppState, OPEN_LINK};
+use crate::highlight::Selection;
+use crate::markup::Span;
+use crate::theme;
+use crate::typography::{resolve_family, Alignment, Typography};
+
+/*
+The marks painted on the text of a page: the highlights and the selection of the user, as ranges of
+the characters of the visible text of the page without whitespace (the way highlights are located
+in the file), and the number of characters of the blocks already built, so that every block finds
+the part of the marks falling on its own text.
+*/
+pub struct PageMarks {
+    pub page: usize,
+    pub marks: Vec<(Range<usize>, KeyOrValue<Color>)>,
+    pub chars: usize,
+}
+
+impl PageMarks {
+    pub fn new(page: usize, marks: Vec<(Range<usize>, KeyOrValue<Color>)>) -> Self {
+        Self {
+            page,
+            marks,
+            chars: 0,
+        }
+    }
+}
+
+/*
+This widget displays a paragraph of the Ebook as rich text: every span keeps its formatting (bold,
+italic, small caps, monospace, smaller text) and the links are underlined and clickable. The
+alignment and the indentation of the first line come from the style of the block, unless the user
+forces justified or ragged text; the font family and the line height come from the typography
+chosen by the user, every line being drawn apart to space them. A click on a link
+submits the command OPEN_LINK with the file of the page, the href of the link and if it refers to
+a footnote.
+Dragging the mouse over the text selects it: when the button is released the selection is stored in
+the AppState variable selection, as a range of the characters of the page, so that it can be
+highlighted. The block only captures the mouse once it is dragged, so that a simple click on the
+text reaches the page turner. The highlights of the page falling on the paragraph are painted
+behind its text.
+*/
+pub struct RichTextBlock {
+    layout: TextLayout<RichText>,
+    size: f64,
+    family: Option<String>,
+    line_height: f64,
+    lines: Vec<(f64, f64, f64)>,
+    added_height: f64,
+    links: Vec<(Range<usize>, String, bool)>,
+    source: String,
+    page: usize,
+    first_char: usize,
+    chars: Vec<Range<usize>>,
+    marks: Vec<(Range<usize>, KeyOrValue<Color>)>,
+    anchor: Option<usize>,
+    selection: Option<Range<usize>>,
+}
+
+impl RichTextBlock {
+    pub fn new(spans: &Vec<Span>, size: f64, style: &Style, source: &str, marks: &mut PageMarks, typography: &Typography) -> Self {
+        let indent = style.text_indent.unwrap_or(0.).max(0.);
+        let (text, links) = build_rich_text(spans, size, indent);
+
+        let chars: Vec<Range<usize>> = text.as_str().char_indices()
+            .filter(|(_, c)| !c.is_whitespace())
+            .map(|(i, c)| i..i + c.len_utf8())
+            .collect();
+        let first_char = marks.chars;
+        marks.chars += chars.len();
+
+        let own_marks = marks.marks.iter()
+            .filter_map(|(range, color)| {
+                let start = range.start.max(first_char);
+                let end = range.end.min(first_char + chars.len());
+                if start < end {
+                    Some((chars[start - first_char].start..chars[end - 1 - first_char].end, color.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let mut layout = TextLayout::from_text(text);
+        layout.set_text_size(size);
+        layout.set_text_alignment(match (style.text_align, typography.alignment) {
+            (Some(TextAlign::Right), _) => TextAlignment::End,
+            (Some(TextAlign::Center), _) => TextAlignment::Center,
+            (_, Alignment::Justified) => TextAlignment::Justified,
+            (_, Alignment::Ragged) => TextAlignment::Start,
+            (Some(TextAlign::Justify), _) => TextAlignment::Justified,
+            _ => TextAlignment::Start,
+        });
+
+        Self {
+            layout,
+            size,
+            family: Some(typography.font_family.clone()),
+            line_height: typography.line_height,
+            lines: Vec::new(),
+            added_height: 0.,
+            links,
+            source: source.to_string(),
+            page: marks.page,
+            first_char,
+            chars,
+            marks: own_marks,
+            anchor: None,
+            selection: None,
+        }
+    }
+
+    /*
+    This function returns the selection of the user as characters of the page, or None if it
+    doesn't contain any visible character.
+    */
+    fn page_selection(&self, range: &Range<usize>) -> Option<Selection> {
+        let start = self.chars.iter().filter(|c| c.start < range.start).count();
+        let end = self.chars.iter().filter(|c| c.start < range.end).count();
+        if start >= end {
+            return None;
+        }
+
+        let text = self.layout.text()?.as_str()[self.chars[start].start..self.chars[end - 1].end].to_string();
+        Some(Selection {
+            page: self.page,
+            start: self.first_char + start,
+            end: self.first_char + end,
+            text,
+        })
+    }
+
+    /*
+    This function converts a point of the widget into a point of the text layout, removing the space
+    added between the lines.
+    */
+    fn layout_point(&self, point: Point) -> Point {
+        let line = self.lines.iter()
+            .find(|(top, height, shift)| point.y < top + height + shift)
+            .or(self.lines.last());
+
+        match line {
+            Some((top, height, shift)) => Point::new(point.x, (point.y - shift).max(*top).min(top + height)),
+            None => point,
+        }
+    }
+
+    /*
+    This function moves a rectangle of the text layout to its line, as it is drawn by the widget.
+    */
+    fn widget_rect(&self, rect: Rect) -> Rect {
+        let y = rect.center().y;
+        let shift = self.lines.iter()
+            .find(|(top, height, _)| y >= *top && y < top + height)
+            .map(|(_, _, shift)| *shift)
+            .unwrap_or(0.);
+
+        rect.with_origin((rect.x0, rect.y0 + shift))
+    }
+
+    fn text_position(&self, point: Point) -> usize {
+        self.layout.text_position_for_point(self.layout_point(point))
+    }
+
+    fn link_at(&self, point: Point) -> Option<(&String, bool)> {
+        if point.y > self.layout.size().height + self.added_height {
+            return None;
+        }
+        let pos = self.text_position(point);
+
+        self.links.iter()
+            .find(|(range, _, _)| range.contains(&pos))
+            .map(|(_, href, noteref)| (href, *noteref))
+    }
+}
+
+impl Widget<AppState> for RichTextBlock {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, _env: &Env) {
+        match event {
+            Event::MouseMove(mouse) => {
+                if let (true, Some(anchor)) = (mouse.buttons.has_left(), self.anchor) {
+                    ctx.set_active(true);
+                    let pos = self.text_position(mouse.pos);
+                    self.selection = Some(anchor.min(pos)..anchor.max(pos));
+                    ctx.request_paint();
+                } else if self.link_at(mouse.pos).is_some() {
+                    ctx.set_cursor(&Cursor::OpenHand);
+                } else {
+                    ctx.set_cursor(&Cursor::IBeam);
+                }
+            }
+            Event::MouseDown(mouse) if mouse.button == MouseButton::Left => {
+                if let Some((href, noteref)) = self.link_at(mouse.pos) {
+                    ctx.submit_command(OPEN_LINK.with((self.source.clone(), href.clone(), noteref)));
+                    ctx.set_handled();
+                } else {
+                    self.anchor = Some(self.text_position(mouse.pos));
+                    self.selection = None;
+                }
+            }
+            Event::MouseUp(mouse) if mouse.button == MouseButton::Left && self.anchor.is_some() => {
+                ctx.set_active(false);
+                let selection = self.selection.take().and_then(|range| self.page_selection(&range));
+                if selection.is_some() || data.selection.is_some() {
+                    data.selection = selection;
+                }
+                self.anchor = None;
+                ctx.request_paint();
+                ctx.set_handled();
+            }
+            _ => {}
+        }
+    }
+
+    fn lifecycle(&mut self, _ctx: &mut LifeCycleCtx, _event: &LifeCycle, _data: &AppState, _env: &Env) {}
+
+    fn update(&mut self, _ctx: &mut UpdateCtx, _old_data: &AppState, _data: &AppState, _env: &Env) {}
+
+    fn layout(&mut self, ctx: &mut LayoutCtx, bc: &BoxConstraints, data: &AppState, env: &Env) -> Size {
+        if let Some(name) = self.family.take() {
+            if let Some(family) = resolve_family(ctx.text(), &name, data.book.as_deref()) {
+                self.layout.set_font(FontDescriptor::new(family).with_size(self.size));
+            }
+        }
+        self.layout.set_wrap_width(bc.max().width);
+        self.layout.rebuild_if_needed(ctx.text(), env);
+
+        self.lines.clear();
+        let mut added = 0.;
+        if let Some(layout) = self.layout.layout() {
+            for i in 0..layout.line_count() {
+                if let Some(metric) = layout.line_metric(i) {
+                    let extra = self.line_height * self.size - metric.height;
+                    self.lines.push((metric.y_offset, metric.height, added + extra / 2.));
+                    added += extra;
+                }
+            }
+        }
+
+        self.added_height = added;
+
+        let size = self.layout.size();
+        bc.constrain(Size::new(bc.max().width, (size.height + added).max(0.)))
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, _data: &AppState, env: &Env) {
+        for (range, color) in self.marks.iter() {
+            let color = color.resolve(env);
+            for rect in self.layout.rects_for_range(range.clone()) {
+                ctx.fill(self.widget_rect(rect), &color);
+            }
+        }
+        if let Some(range) = &self.selection {
+            let color = env.get(theme::SELECTION);
+            for rect in self.layout.rects_for_range(range.clone()) {
+                ctx.fill(self.widget_rect(rect), &color);
+            }
+        }
+
+        let width = ctx.size().width;
+        for (top, height, shift) in self.lines.iter() {
+            ctx.with_save(|ctx| {
+                ctx.clip(Rect::new(-self.size, top + shift, width + self.size, top + height + shift));
+                self.layout.draw(ctx, Point::new(0., *shift));
+            });
+        }
+    }
+}
+
+/*
+This function builds the RichText of a list of spans, translating their style into attributes, and
+returns it with the byte ranges of the links it contains. The first line is indented with em spaces
+and the text in small caps is displayed in smaller capital letters.
+*/
+fn build_rich_text(spans: &Vec<Span>, size: f64, indent: f64) -> (RichText, Vec<(Range<usize>, String, bool)>) {
+    let mut text = "\u{2003}".repeat(indent.round() as usize);
+    let mut ranges = Vec::new();
+
+    for span in spans {
+        let start = text.len();
+        if span.style.small_caps {
+            text.push_str(&span.text.to_uppercase());
+        } else {
+            text.push_str(&span.text);
+        }
+        ranges.push(start..text.len());
+    }
+
+    let mut rich_text = RichText::new(text.as_str().into());
+    let mut links = Vec::new();
+
+    for (span, range) in spans.iter().zip(ranges) {
+        if span.style.bold {
+            rich_text.add_attribute(range.clone(), Attribute::weight(FontWeight::BOLD));
+        }
+        if span.style.italic {
+            rich_text.add_attribute(range.clone(), Attribute::style(FontStyle::Italic));
+        }
+        if span.style.underline {
+            rich_text.add_attribute(range.clone(), Attribute::underline(true));
+        }
+        if span.style.monospace {
+            rich_text.add_attribute(range.clone(), Attribute::font_family(FontFamily::MONOSPACE));
+        }
+        if span.style.small {
+            rich_text.add_attribute(range.clone(), Attribute::size(size * 0.7));
+        } else if span.style.small_caps {
+            rich_text.add_attribute(range.clone(), Attribute::size(size * 0.8));
+        }
+        if let Some(href) = &span.style.link {
+            rich_text.add_attribute(range.clone(), Attribute::text_color(theme::LINK));
+            rich_text.add_attribute(range.clone(), Attribute::underline(true));
+            links.push((range, href.clone(), span.style.noteref));
+        }
+    }
+
+    (rich_text, links)
+}
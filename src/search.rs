@@ -0,0 +1,150 @@
+use druid::{Data, Lens};
+use druid::im::Vector;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use crate::book::Book;
+use crate::data::Section;
+
+/*
+The maximum number of hits of a search, so that searching a very common word stays fast.
+*/
+pub const MAX_HITS: usize = 5000;
+
+/*
+The number of characters of context shown before and after a hit.
+*/
+const CONTEXT_LENGTH: usize = 40;
+
+/*
+The options of the search: by default the case is ignored, the text can match inside a word and the
+query is searched literally; in regex mode it is a regular expression. The library option searches
+the words of the query in all the Ebooks of the index instead of the open one, ignoring the others.
+*/
+#[derive(Clone, Data, Lens, Serialize, Deserialize)]
+pub struct SearchOptions {
+    pub match_case: bool,
+    pub whole_word: bool,
+    pub regex: bool,
+    pub library: bool,
+}
+
+impl SearchOptions {
+    pub fn new() -> Self {
+        Self {
+            match_case: false,
+            whole_word: false,
+            regex: false,
+            library: false,
+        }
+    }
+}
+
+/*
+An occurrence of the searched text: the file containing it, the byte of the file where it starts,
+the number of characters of visible text without whitespace preceding it in the file and its length
+in the same unit, and the line of text around it. The page of the hit and the number of characters
+of the page preceding it depend on the pagination, so they are computed again every time the Ebook
+is paginated.
+*/
+#[derive(Clone, Data, PartialEq)]
+pub struct SearchHit {
+    pub source: String,
+    pub offset: usize,
+    pub chars: usize,
+    pub length: usize,
+    pub context: String,
+    pub page: usize,
+    pub start: usize,
+}
+
+/*
+This function builds the regular expression searching the query with the given options. An error is
+returned if the query isn't a valid regular expression in regex mode.
+*/
+pub fn build_pattern(query: &str, options: &SearchOptions) -> Result<Regex, regex::Error> {
+    let mut pattern = if options.regex { query.to_string() } else { regex::escape(query) };
+    if options.whole_word {
+        pattern = format!(r"\b(?:{})\b", pattern);
+    }
+
+    RegexBuilder::new(&pattern)
+        .case_insensitive(!options.match_case)
+        .build()
+}
+
+/*
+This function searches the text of the sections, as it is displayed, returning the hits in reading
+order. A hit can't span two blocks, and matches made only of whitespace are ignored. The text of the
+sections is taken from the Book, which extracts it only once.
+*/
+pub fn search_sections(book: &Book, sections: &Vector<Section>, pattern: &Regex) -> Vec<SearchHit> {
+    let mut hits = Vec::new();
+
+    for section in sections.iter() {
+        let text = book.section_text(section);
+        let text = text.as_str();
+        let mut found = Vec::new();
+        let mut chars = 0;
+        let mut counted = 0;
+
+        for m in pattern.find_iter(text) {
+            let length = m.as_str().chars().filter(|c| !c.is_whitespace()).count();
+            if length == 0 {
+                continue;
+            }
+
+            chars += text[counted..m.start()].chars().filter(|c| !c.is_whitespace()).count();
+            counted = m.start();
+            found.push((chars, length, context(text, m.start(), m.end())));
+
+            if hits.len() + found.len() >= MAX_HITS {
+                break;
+            }
+        }
+
+        let counts: Vec<usize> = found.iter().map(|(chars, ..)| *chars).collect();
+        let offsets = section.text_positions(0, &counts);
+
+        for ((chars, length, context), offset) in found.into_iter().zip(offsets) {
+            hits.push(SearchHit {
+                source: section.source.clone(),
+                offset,
+                chars,
+                length,
+                context,
+                page: 0,
+                start: 0,
+            });
+        }
+
+        if hits.len() >= MAX_HITS {
+            break;
+        }
+    }
+
+    hits
+}
+
+/*
+This function returns the hit with some characters of its line before and after it.
+*/
+fn context(text: &str, start: usize, end: usize) -> String {
+    let line_start = text[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = text[end..].find('\n').map(|i| end + i).unwrap_or(text.len());
+
+    let before: Vec<char> = text[line_start..start].chars().collect();
+    let after: Vec<char> = text[end..line_end].chars().collect();
+    let mut context = String::new();
+
+    if before.len() > CONTEXT_LENGTH {
+        context.push_str("...");
+    }
+    context.extend(before[before.len().saturating_sub(CONTEXT_LENGTH)..].iter());
+    context.push_str(&text[start..end]);
+    context.extend(after.iter().take(CONTEXT_LENGTH));
+    if after.len() > CONTEXT_LENGTH {
+        context.push_str("...");
+    }
+
+    context.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
@@ -0,0 +1,133 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+This module decides where the application keeps its files. By default they are stored in the data
+directory of the platform (XDG_DATA_HOME on Linux, AppData on Windows, Application Support on
+macOS), while the files meant to be edited by the user are in its configuration directory
+(XDG_CONFIG_HOME on Linux); setting the environment variable EBOOK_READER_HOME moves both under that
+directory, which is useful to keep separate profiles or a portable installation.
+*/
+
+const APP_DIR: &str = "ebook-reader";
+const HOME_VARIABLE: &str = "EBOOK_READER_HOME";
+
+/*
+This function returns the directory containing the data saved by the application.
+*/
+pub fn data_dir() -> PathBuf {
+    match env::var_os(HOME_VARIABLE) {
+        Some(home) if home.len() > 0 => PathBuf::from(home).join("data"),
+        _ => dirs_next::data_dir()
+            .map(|dir| dir.join(APP_DIR))
+            .unwrap_or_else(|| PathBuf::from(APP_DIR).join("data")),
+    }
+}
+
+/*
+This function returns the directory containing the configuration files of the application.
+*/
+pub fn config_dir() -> PathBuf {
+    match env::var_os(HOME_VARIABLE) {
+        Some(home) if home.len() > 0 => PathBuf::from(home).join("config"),
+        _ => dirs_next::config_dir()
+            .map(|dir| dir.join(APP_DIR))
+            .unwrap_or_else(|| PathBuf::from(APP_DIR).join("config")),
+    }
+}
+
+/*
+This function returns the path of the json file binding the keyboard shortcuts to the actions.
+*/
+pub fn keymap_file() -> PathBuf {
+    config_dir().join("keymap.json")
+}
+
+/*
+This function returns the path of the json file containing the themes defined by the user.
+*/
+pub fn themes_file() -> PathBuf {
+    config_dir().join("themes.json")
+}
+
+/*
+This function returns the path of the json file containing the preferences of the user.
+*/
+pub fn preferences_file() -> PathBuf {
+    config_dir().join("preferences.json")
+}
+
+/*
+This function returns the path of the json file containing the bookmarks and the last page read of
+the Ebook with the given key (or title, for the files saved by the previous versions).
+*/
+pub fn metadata_file(name: &str) -> PathBuf {
+    data_dir().join("metadata").join(file_name(name) + ".json")
+}
+
+/*
+This function returns the path of the file containing the inverted index of the library, which maps
+every word to the files of the Ebooks containing it.
+*/
+pub fn index_file() -> PathBuf {
+    data_dir().join("index").join("terms.json")
+}
+
+/*
+This function returns the path of the file containing the text of the Ebook with the given key, as
+indexed for the search in the library.
+*/
+pub fn indexed_text_file(key: &str) -> PathBuf {
+    data_dir().join("index").join("books").join(file_name(key) + ".json")
+}
+
+/*
+This function returns the path of the json file containing the folders of the library.
+*/
+pub fn library_file() -> PathBuf {
+    data_dir().join("library.json")
+}
+
+/*
+This function returns the path of the json file containing the list of the Ebooks opened recently.
+*/
+pub fn recent_file() -> PathBuf {
+    data_dir().join("recent.json")
+}
+
+/*
+This function replaces the characters that can't be used in a file name.
+*/
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if "/\\:*?\"<>|".contains(c) || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+/*
+This function returns the path where the first versions of the application saved the metadata of an
+Ebook, so that they can still be read.
+*/
+pub fn legacy_metadata_file(title: &str) -> PathBuf {
+    PathBuf::from(format!("\\Ebook_Reader\\Metadata\\{}.json", title))
+}
+
+/*
+This function moves a file that can't be read next to it, adding the time to its name, so that it
+can be recovered by hand while the application starts again from empty data. The new path is
+returned.
+*/
+pub fn backup_file(path: &Path) -> PathBuf {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(format!(".{}.bak", time));
+    let backup = path.with_file_name(name);
+
+    if let Err(error) = fs::rename(path, &backup) {
+        println!("Error while moving {} to {}: {}", path.display(), backup.display(), error);
+    }
+
+    backup
+}
@@ -0,0 +1,180 @@
+use std::fs;
+use druid::{theme, Color, Data, Env, Key};
+use druid::im::Vector;
+use serde::{Deserialize, Serialize};
+use crate::storage::themes_file;
+
+/*
+The keys of the Env holding the colours of the current theme, used by the widgets instead of fixed
+colours: the background and the text of the page, the background of the rows of buttons and of the
+boxes displayed over the page, the borders, the entries of the menu that can be clicked (and the
+links of the page), the buttons deleting something, the secondary text and the text selected by the
+user on the page.
+*/
+pub const BACKGROUND: Key<Color> = Key::new("ebook-reader.theme.background");
+pub const TEXT: Key<Color> = Key::new("ebook-reader.theme.text");
+pub const PANEL: Key<Color> = Key::new("ebook-reader.theme.panel");
+pub const BORDER: Key<Color> = Key::new("ebook-reader.theme.border");
+pub const LINK: Key<Color> = Key::new("ebook-reader.theme.link");
+pub const DELETE: Key<Color> = Key::new("ebook-reader.theme.delete");
+pub const MUTED: Key<Color> = Key::new("ebook-reader.theme.muted");
+pub const SELECTION: Key<Color> = Key::new("ebook-reader.theme.selection");
+
+/*
+The ways the illustrations can be displayed with a dark theme, so that a white picture doesn't
+dazzle the user: unchanged, dimmed or with inverted colours.
+*/
+#[derive(Clone, Copy, Data, PartialEq, Serialize, Deserialize)]
+pub enum ImageFilter {
+    Normal,
+    Dim,
+    Invert,
+}
+
+impl ImageFilter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFilter::Normal => "normal",
+            ImageFilter::Dim => "dimmed",
+            ImageFilter::Invert => "inverted",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            ImageFilter::Normal => ImageFilter::Dim,
+            ImageFilter::Dim => ImageFilter::Invert,
+            ImageFilter::Invert => ImageFilter::Normal,
+        }
+    }
+
+    /*
+    This function applies the filter to the pixels of an image with the given number of channels,
+    leaving the alpha channel unchanged.
+    */
+    pub fn apply(&self, pixels: &mut [u8], channels: usize) {
+        let colours = if channels == 4 { 3 } else { channels };
+
+        for pixel in pixels.chunks_mut(channels) {
+            for value in pixel.iter_mut().take(colours) {
+                *value = match self {
+                    ImageFilter::Normal => *value,
+                    ImageFilter::Dim => (*value as f64 * 0.6) as u8,
+                    ImageFilter::Invert => 255 - *value,
+                };
+            }
+        }
+    }
+}
+
+/*
+A colour scheme of the application, with its colours written as hexadecimal strings ("#rrggbb").
+The dark themes allow dimming or inverting the illustrations. The themes of the user are read from
+a json file in the configuration directory containing a list of themes with the same fields; the
+missing colours are taken from the light theme, while the name is required.
+*/
+#[derive(Clone, Data, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    #[serde(default)]
+    pub name: String,
+    pub background: String,
+    pub text: String,
+    pub panel: String,
+    pub border: String,
+    pub link: String,
+    pub delete: String,
+    pub muted: String,
+    pub control: String,
+    pub selection: String,
+    pub dark: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::new("Light", ["#ffffff", "#000000", "#f5f5f5", "#a0a0a0", "#1a5fb4", "#c62828", "#707070", "#e0e0e0", "#b3d4fc"], false)
+    }
+}
+
+impl Theme {
+    fn new(name: &str, colors: [&str; 9], dark: bool) -> Self {
+        let [background, text, panel, border, link, delete, muted, control, selection] = colors.map(String::from);
+        Self { name: name.to_string(), background, text, panel, border, link, delete, muted, control, selection, dark }
+    }
+
+    /*
+    This function returns the themes available: the built-in ones followed by the ones of the user.
+    The themes of the user without a name or with an invalid colour are skipped, so that a theme
+    missing its name doesn't replace the light theme.
+    */
+    pub fn all() -> Vector<Theme> {
+        let mut themes = Vector::from(vec![
+            Theme::default(),
+            Theme::new("Dark", ["#1e1e1e", "#e0e0e0", "#2b2b2b", "#555555", "#78aeed", "#ef5350", "#9e9e9e", "#3c3c3c", "#264f78"], true),
+            Theme::new("Sepia", ["#f4ecd8", "#5b4636", "#eadfc6", "#b9a88a", "#8b4513", "#a52a2a", "#8a7560", "#e0d3b8", "#e3c98f"], false),
+            Theme::new("High contrast", ["#000000", "#ffffff", "#000000", "#ffffff", "#ffff00", "#ff5555", "#c0c0c0", "#333333", "#0050a0"], true),
+        ]);
+
+        let path = themes_file();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => return themes,
+        };
+        match serde_json::from_str::<Vec<Theme>>(&text) {
+            Ok(user) => {
+                for theme in user {
+                    if theme.name.trim().len() == 0 {
+                        println!("A theme of {} has no name and has been skipped", path.display());
+                    } else if theme.colors().iter().any(|color| color.is_none()) {
+                        println!("The theme \"{}\" contains an invalid colour and has been skipped", theme.name);
+                    } else {
+                        themes.retain(|other| other.name != theme.name);
+                        themes.push_back(theme);
+                    }
+                }
+            }
+            Err(error) => println!("The themes of {} can't be read: {}", path.display(), error),
+        }
+
+        themes
+    }
+
+    fn colors(&self) -> [Option<Color>; 9] {
+        [&self.background, &self.text, &self.panel, &self.border, &self.link, &self.delete, &self.muted, &self.control, &self.selection]
+            .map(|color| Color::from_hex_str(color).ok())
+    }
+
+    /*
+    This function sets the colours of the theme in the Env, both the keys of the application and
+    the ones used by the druid widgets (labels, buttons, text boxes, checkboxes).
+    */
+    pub fn apply(&self, env: &mut Env) {
+        let (colors, fallback) = (self.colors(), Theme::default().colors());
+        let color = |i: usize| colors[i].clone().or(fallback[i].clone()).unwrap_or(Color::BLACK);
+
+        env.set(BACKGROUND, color(0));
+        env.set(TEXT, color(1));
+        env.set(PANEL, color(2));
+        env.set(BORDER, color(3));
+        env.set(LINK, color(4));
+        env.set(DELETE, color(5));
+        env.set(MUTED, color(6));
+        env.set(SELECTION, color(8));
+
+        env.set(theme::WINDOW_BACKGROUND_COLOR, color(0));
+        env.set(theme::LABEL_COLOR, color(1));
+        env.set(theme::CURSOR_COLOR, color(1));
+        env.set(theme::FOREGROUND_LIGHT, color(1));
+        env.set(theme::FOREGROUND_DARK, color(1));
+        env.set(theme::BACKGROUND_DARK, color(2));
+        env.set(theme::BORDER_LIGHT, color(3));
+        env.set(theme::BORDER_DARK, color(3));
+        env.set(theme::PRIMARY_LIGHT, color(4));
+        env.set(theme::PRIMARY_DARK, color(4));
+        env.set(theme::PLACEHOLDER_COLOR, color(6));
+        env.set(theme::BACKGROUND_LIGHT, color(7));
+        env.set(theme::BUTTON_LIGHT, color(7));
+        env.set(theme::BUTTON_DARK, color(7));
+        env.set(theme::SELECTION_COLOR, color(8));
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs::File;
+use druid::im::Vector;
+use epub::doc::EpubDoc;
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
+use crate::data::Chapter;
+use crate::loader::{normalize_path, resolve_href};
+
+/*
+This struct represents an entry of the table of contents as it is written by the publisher, before
+its href is translated into a page of the ebook.
+*/
+struct TocEntry {
+    title: String,
+    href: String,
+    children: Vec<TocEntry>,
+}
+
+impl TocEntry {
+    fn new() -> Self {
+        Self {
+            title: String::new(),
+            href: String::new(),
+            children: Vec::new(),
+        }
+    }
+}
+
+/*
+This function reads the table of contents of the opened epub. The EPUB3 nav document is used when
+present, otherwise the toc.ncx of EPUB2 is used. It returns None if the book has no readable table
+of contents, so that the caller can fall back to the spine items.
+*/
+pub fn read_toc(doc: &mut EpubDoc<File>) -> Option<Vector<Chapter>> {
+    let opf_path = normalize_path(&doc.root_file.to_string_lossy());
+    let opf = doc.get_resource_by_path(&opf_path).ok()?;
+    let (nav, ncx) = find_toc_documents(&opf, &opf_path);
+
+    let mut entries = Vec::new();
+    let mut toc_path = String::new();
+
+    if let Some(nav) = nav {
+        if let Ok(content) = doc.get_resource_by_path(&nav) {
+            entries = parse_nav(&content);
+            toc_path = nav;
+        }
+    }
+
+    if entries.len() == 0 {
+        if let Some(ncx) = ncx {
+            if let Ok(content) = doc.get_resource_by_path(&ncx) {
+                entries = parse_ncx(&content);
+                toc_path = ncx;
+            }
+        }
+    }
+
+    if entries.len() == 0 {
+        return None;
+    }
+
+    Some(to_chapters(&entries, &toc_path))
+}
+
+/*
+This function converts the entries of the table of contents into the Chapter tree, translating every
+href into the full path of the file and the fragment the chapter points to. The target page of the
+chapters is found after the pagination.
+*/
+fn to_chapters(entries: &Vec<TocEntry>, toc_path: &str) -> Vector<Chapter> {
+    let mut chapters = Vector::new();
+
+    for entry in entries {
+        let mut chapter = Chapter::from(entry.title.clone(), 0);
+        chapter.source = resolve_href(toc_path, &entry.href);
+        chapter.fragment = entry.href.split('#').nth(1).unwrap_or("").to_string();
+        chapter.children = to_chapters(&entry.children, toc_path);
+        chapters.push_back(chapter);
+    }
+
+    chapters
+}
+
+/*
+This function creates the xml reader used for the OPF, NCX and nav documents. Unknown HTML
+entities are replaced instead of stopping the parsing.
+*/
+fn xml_reader(content: &[u8]) -> EventReader<&[u8]> {
+    let mut config = ParserConfig::new()
+        .trim_whitespace(false)
+        .cdata_to_characters(true)
+        .add_entity("nbsp", "\u{a0}");
+    config.replace_unknown_entity_references = true;
+
+    config.create_reader(content)
+}
+
+/*
+This function searches the manifest of the OPF file for the EPUB3 nav document (the item with the
+"nav" property) and for the toc.ncx, returning their full paths inside the archive.
+*/
+fn find_toc_documents(opf: &[u8], opf_path: &str) -> (Option<String>, Option<String>) {
+    let mut nav = None;
+    let mut ncx = None;
+
+    for event in xml_reader(opf) {
+        match event {
+            Ok(XmlEvent::StartElement { name, attributes, .. }) if name.local_name == "item" => {
+                let attribute = |key: &str| attributes.iter()
+                    .find(|a| a.name.local_name == key)
+                    .map(|a| a.value.clone())
+                    .unwrap_or_default();
+
+                let href = attribute("href");
+                if attribute("properties").split_whitespace().any(|p| p == "nav") {
+                    nav = Some(resolve_href(opf_path, &href));
+                } else if attribute("media-type") == "application/x-dtbncx+xml" {
+                    ncx = Some(resolve_href(opf_path, &href));
+                }
+            }
+            Err(_) => break,
+            _ => {}
+        }
+    }
+
+    (nav, ncx)
+}
+
+/*
+This function parses the <nav epub:type="toc"> element of an EPUB3 nav document. Every <li> becomes
+an entry whose title is the text of its <a> (or <span>) and whose children are the entries of the
+nested <ol>.
+*/
+fn parse_nav(content: &[u8]) -> Vec<TocEntry> {
+    let mut stack: Vec<TocEntry> = vec![TocEntry::new()];
+    let mut nav_depth = 0;
+    let mut in_toc = false;
+    let mut label_depth = 0;
+    let mut done = false;
+
+    for event in xml_reader(content) {
+        match event {
+            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+                let tag = name.local_name.as_str();
+
+                if tag == "nav" {
+                    nav_depth += 1;
+                    if !done && !in_toc && attributes.iter().any(|a| a.name.local_name == "type" && a.value.split_whitespace().any(|v| v == "toc")) {
+                        in_toc = true;
+                        nav_depth = 1;
+                    }
+                } else if in_toc {
+                    match tag {
+                        "li" => stack.push(TocEntry::new()),
+                        "a" | "span" if label_depth > 0 || (stack.len() > 1 && stack.last().unwrap().children.len() == 0) => {
+                            label_depth += 1;
+                            if let Some(href) = attributes.iter().find(|a| a.name.local_name == "href") {
+                                if stack.last().unwrap().href.len() == 0 {
+                                    stack.last_mut().unwrap().href = href.value.clone();
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Ok(XmlEvent::Characters(text)) if label_depth > 0 => {
+                stack.last_mut().unwrap().title.push_str(&text);
+            }
+            Ok(XmlEvent::EndElement { name }) => {
+                let tag = name.local_name.as_str();
+
+                if tag == "nav" && in_toc {
+                    nav_depth -= 1;
+                    if nav_depth == 0 {
+                        in_toc = false;
+                        done = true;
+                    }
+                } else if in_toc && (tag == "a" || tag == "span") && label_depth > 0 {
+                    label_depth -= 1;
+                } else if in_toc && tag == "li" && stack.len() > 1 {
+                    let mut entry = stack.pop().unwrap();
+                    entry.title = clean_title(&entry.title);
+                    stack.last_mut().unwrap().children.push(entry);
+                }
+            }
+            Err(error) => {
+                println!("Error while parsing the nav document: {}", error);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    stack.swap_remove(0).children
+}
+
+/*
+This function parses the <navMap> of an EPUB2 toc.ncx. Every nested <navPoint> becomes an entry
+with the text of its <navLabel> and the src of its <content>.
+*/
+fn parse_ncx(content: &[u8]) -> Vec<TocEntry> {
+    let mut stack: Vec<TocEntry> = vec![TocEntry::new()];
+    let mut in_label = false;
+
+    for event in xml_reader(content) {
+        match event {
+            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+                match name.local_name.as_str() {
+                    "navPoint" => stack.push(TocEntry::new()),
+                    "navLabel" if stack.len() > 1 => in_label = true,
+                    "content" if stack.len() > 1 => {
+                        if let Some(src) = attributes.iter().find(|a| a.name.local_name == "src") {
+                            stack.last_mut().unwrap().href = src.value.clone();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(XmlEvent::Characters(text)) if in_label => {
+                stack.last_mut().unwrap().title.push_str(&text);
+            }
+            Ok(XmlEvent::EndElement { name }) => {
+                match name.local_name.as_str() {
+                    "navLabel" => in_label = false,
+                    "navPoint" if stack.len() > 1 => {
+                        let mut entry = stack.pop().unwrap();
+                        entry.title = clean_title(&entry.title);
+                        stack.last_mut().unwrap().children.push(entry);
+                    }
+                    _ => {}
+                }
+            }
+            Err(error) => {
+                println!("Error while parsing the toc.ncx: {}", error);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    stack.swap_remove(0).children
+}
+
+/*
+This function collapses the whitespace of a title read from the table of contents.
+*/
+fn clean_title(title: &str) -> String {
+    title.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
@@ -0,0 +1,128 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use druid::{Data, FontFamily, Lens};
+use druid::piet::{PietText, Text};
+use serde::{Deserialize, Serialize};
+use crate::book::Book;
+use crate::util::clamp;
+
+/*
+The generic font families offered before the fonts embedded in the Ebook.
+*/
+pub const GENERIC_FAMILIES: [&str; 3] = ["Serif", "Sans-serif", "Monospace"];
+
+/*
+The values accepted for the line height (in multiples of the font size), for the space after the
+paragraphs (in em) and for the margins on the sides of the page (in pixels).
+*/
+pub const LINE_HEIGHT_RANGE: RangeInclusive<f64> = 1.0..=2.5;
+pub const PARAGRAPH_SPACING_RANGE: RangeInclusive<f64> = 0.0..=3.0;
+pub const MARGIN_RANGE: RangeInclusive<f64> = 0.0..=300.0;
+
+/*
+The alignment of the text of the paragraphs: the one chosen by the stylesheets of the Ebook, or
+always justified or ragged. Centered and right-aligned text keeps its alignment.
+*/
+#[derive(Clone, Copy, Data, PartialEq, Serialize, Deserialize)]
+pub enum Alignment {
+    Ebook,
+    Justified,
+    Ragged,
+}
+
+/*
+The typography chosen by the user. An empty font family keeps the fonts of the Ebook; otherwise it
+is a generic family, a font embedded in the Ebook or the name of a font installed on the system.
+The settings are kept for the next launches with the other preferences.
+*/
+#[derive(Clone, Data, Lens, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Typography {
+    pub font_family: String,
+    pub line_height: f64,
+    pub paragraph_spacing: f64,
+    pub margin: f64,
+    pub alignment: Alignment,
+}
+
+impl Default for Typography {
+    fn default() -> Self {
+        Self {
+            font_family: String::new(),
+            line_height: 1.2,
+            paragraph_spacing: 0.5,
+            margin: 50.,
+            alignment: Alignment::Ebook,
+        }
+    }
+}
+
+impl Typography {
+    /*
+    This function brings the values out of range back into their range.
+    */
+    pub fn validate(&mut self) {
+        self.line_height = clamp(self.line_height, &LINE_HEIGHT_RANGE);
+        self.paragraph_spacing = clamp(self.paragraph_spacing, &PARAGRAPH_SPACING_RANGE);
+        self.margin = clamp(self.margin, &MARGIN_RANGE);
+    }
+
+    /*
+    This function returns the font family following the current one in the list made of the fonts
+    of the Ebook (an empty name), the generic families and the fonts embedded in the Ebook.
+    */
+    pub fn next_family(&self, embedded: &[String]) -> String {
+        let mut families = vec![String::new()];
+        families.extend(GENERIC_FAMILIES.iter().map(|family| family.to_string()));
+        families.extend(embedded.iter().cloned());
+
+        let current = families.iter().position(|family| *family == self.font_family);
+        current.map(|i| families[(i + 1) % families.len()].clone()).unwrap_or_default()
+    }
+}
+
+/*
+The embedded fonts loaded by the text system, by key of the Ebook and family name. They are only
+used by the thread building the pages and forgotten when the Ebook is closed.
+*/
+thread_local! {
+    static LOADED_FONTS: RefCell<HashMap<(String, String), Option<FontFamily>>> = RefCell::new(HashMap::new());
+}
+
+/*
+This function returns the font family with the given name: a generic family, a font embedded in the
+Ebook (read from the archive and loaded the first time it is used) or an installed font. The
+embedded fonts can't be loaded on every platform; in that case an installed font with the same name
+is used, if any.
+*/
+pub fn resolve_family(text: &mut PietText, name: &str, book: Option<&Book>) -> Option<FontFamily> {
+    match name.trim() {
+        "" => None,
+        "Serif" => Some(FontFamily::SERIF),
+        "Sans-serif" => Some(FontFamily::SANS_SERIF),
+        "Monospace" => Some(FontFamily::MONOSPACE),
+        name => {
+            let embedded = book.and_then(|book| {
+                book.fonts.iter().find(|(family, _)| family == name)?;
+                let key = (book.key.clone(), name.to_string());
+
+                LOADED_FONTS.with(|loaded| {
+                    loaded.borrow_mut()
+                        .entry(key)
+                        .or_insert_with(|| book.font_bytes(name).and_then(|bytes| text.load_font(&bytes).ok()))
+                        .clone()
+                })
+            });
+
+            embedded.or_else(|| text.font_family(name))
+        }
+    }
+}
+
+/*
+This function forgets the embedded fonts loaded for the Ebook that has been closed.
+*/
+pub fn forget_fonts() {
+    LOADED_FONTS.with(|loaded| loaded.borrow_mut().clear());
+}
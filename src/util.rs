@@ -0,0 +1,9 @@
+use std::ops::RangeInclusive;
+
+/*
+This function brings a value back into a range, using the start of the range for a value that isn't
+a number.
+*/
+pub fn clamp(value: f64, range: &RangeInclusive<f64>) -> f64 {
+    if value.is_nan() { *range.start() } else { value.max(*range.start()).min(*range.end()) }
+}
@@ -1,18 +1,36 @@
 #![allow(non_snake_case)]
 
-use druid::{widget::{Flex}, Widget, WidgetExt, Color, UnitPoint, FileDialogOptions, FileSpec, ImageBuf, KeyOrValue, TextAlignment};
+use druid::{widget::{Flex}, Widget, WidgetExt, LensExt, Color, UnitPoint, FileDialogOptions, FileSpec, ImageBuf, KeyOrValue, TextAlignment, FontDescriptor, FontFamily, FontWeight};
 use druid::piet::ImageFormat;
 use crate::data::*;
-use druid::widget::{TextBox, Button, Scroll, SizedBox, Image, FillStrat, Label, CrossAxisAlignment, LineBreaking, Padding, Click, ControllerHost};
-use voca_rs::strip::strip_tags;
+use druid::widget::{TextBox, Button, Scroll, SizedBox, Image, FillStrat, Label, CrossAxisAlignment, LineBreaking, Padding, Click, ControllerHost, Either, EnvScope, ProgressBar, Radio, Checkbox, Stepper};
+use crate::book::DecodedPage;
+use crate::markup::{Block, BlockKind};
+use crate::css::Style;
+use crate::highlight::HighlightColor;
+use crate::keymap::{KeyMap, ShortcutController};
+use crate::library::{filter_books, LibraryBook, LibraryLayout, LibrarySort, COVER_HEIGHT};
+use crate::page_turner::PageTurner;
+use crate::rich_text::{PageMarks, RichTextBlock};
+use crate::search::SearchOptions;
+use crate::theme::{self, ImageFilter};
+use crate::preferences::{Preferences, FONT_SIZE_RANGE};
+use crate::typography::{Alignment, Typography, LINE_HEIGHT_RANGE, MARGIN_RANGE, PARAGRAPH_SPACING_RANGE};
+use crate::paginator::heading_scale;
 use voca_rs::Voca;
 
+const LISTED_HITS: usize = 200;
+
+const CARD_WIDTH: f64 = 160.;
+
+const LIST_COVER_WIDTH: f64 = 60.;
+
 //Creating the layout for defining a new bookmark
 
 fn bookmark_row() -> impl Widget<AppState> {
     let mut r = Flex::row();
 
-    r.add_child(Label::new("Create a new Bookmark").with_text_color(KeyOrValue::Concrete(Color::BLACK)).padding(5.0));
+    r.add_child(Label::new("Create a new Bookmark").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
     r.add_child(TextBox::new().with_placeholder("Title")
         .padding(5.0)
         .lens(AppState::string_bookmark));
@@ -22,12 +40,181 @@ fn bookmark_row() -> impl Widget<AppState> {
 
 
     r.expand_width()
-        .background(Color::WHITE)
-        .border(Color::GRAY, 0.5)
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5)
+}
+
+/*
+Creating the layout for highlighting the text selected by the user: the colour of the highlight, a
+note attached to it and the buttons saving or discarding it. It is displayed only while some text
+is selected.
+*/
+fn highlight_row() -> impl Widget<AppState> {
+    let mut r = Flex::row();
+
+    r.add_child(Label::new("Highlight the selection").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    for color in HighlightColor::ALL {
+        r.add_child(Radio::new(color.name(), color)
+            .lens(AppState::highlight_color)
+            .padding(5.0)
+            .background(color.color()));
+    }
+    r.add_flex_child(TextBox::new().with_placeholder("Note")
+        .expand_width()
+        .padding(5.0)
+        .lens(AppState::string_note), 1.);
+
+    r.add_child(Button::new("Apply").padding(5.0).on_click(AppState::click_confirm_highlight_button));
+    r.add_child(Button::new("Deny").padding(5.0).on_click(AppState::click_reject_highlight_button));
+
+    let row = r.expand_width()
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5);
+
+    Either::new(|data: &AppState, _env| data.selection.is_some(), row, SizedBox::empty())
 }
 
 /*
-Creating the layout for the functions: Open, Edit, Scan, Help and the page navigation section;
+Creating the layout of the search bar: the text to search, the options of the search (case, whole
+words and regular expressions), the button starting the search and the buttons moving between its
+hits, with the number of the current hit. It is displayed when the user presses Find.
+*/
+fn search_row() -> impl Widget<AppState> {
+    let mut r = Flex::row();
+
+    r.add_child(Label::new("Search").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    r.add_flex_child(TextBox::new().with_placeholder("Text to search")
+        .expand_width()
+        .padding(5.0)
+        .lens(AppState::search_query), 1.);
+    r.add_child(Checkbox::new("Match case").lens(AppState::search_options.then(SearchOptions::match_case)).padding(5.0));
+    r.add_child(Checkbox::new("Whole word").lens(AppState::search_options.then(SearchOptions::whole_word)).padding(5.0));
+    r.add_child(Checkbox::new("Regex").lens(AppState::search_options.then(SearchOptions::regex)).padding(5.0));
+    r.add_child(Checkbox::new("Whole library").lens(AppState::search_options.then(SearchOptions::library)).padding(5.0));
+
+    r.add_child(Button::new("Search").padding(5.0).on_click(AppState::click_search_button));
+    r.add_child(Button::new("Previous").padding(5.0).on_click(AppState::click_previous_hit_button));
+    r.add_child(Button::new("Next").padding(5.0).on_click(AppState::click_next_hit_button));
+    r.add_child(Label::new(|data: &AppState, _env: &_| {
+        let partial = if data.search_partial { " (searching the part of the Ebook loaded so far)" } else { "" };
+        if data.search_hits.len() > 0 {
+            format!("{} of {}{}", data.search_current + 1, data.search_hits.len(), partial)
+        } else {
+            String::from(partial.trim())
+        }
+    }).with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+
+    let row = r.expand_width()
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5);
+
+    Either::new(|data: &AppState, _env| data.display_search, row, SizedBox::empty())
+}
+
+/*
+Creating the layout for browsing the library: the filter matching the title, the author or the
+language of the Ebooks, their order, the choice between a grid and a list and the buttons adding a
+folder and scanning the folders again.
+It is displayed only while the library is shown.
+*/
+fn library_row() -> impl Widget<AppState> {
+    let mut r = Flex::row();
+
+    r.add_child(Label::new("Filter").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    r.add_flex_child(TextBox::new().with_placeholder("Title, author or language")
+        .expand_width()
+        .padding(5.0)
+        .lens(AppState::library_filter), 1.);
+    r.add_child(Label::new("Sort by").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    r.add_child(Radio::new("Title", LibrarySort::Title).lens(AppState::library_sort).padding(5.0));
+    r.add_child(Radio::new("Author", LibrarySort::Author).lens(AppState::library_sort).padding(5.0));
+    r.add_child(Radio::new("Progress", LibrarySort::Progress).lens(AppState::library_sort).padding(5.0));
+    r.add_child(Label::new("Show as").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    r.add_child(Radio::new("Grid", LibraryLayout::Grid).lens(AppState::library_layout).padding(5.0));
+    r.add_child(Radio::new("List", LibraryLayout::List).lens(AppState::library_layout).padding(5.0));
+
+    r.add_child(Button::new("Add folder").padding(5.0).on_click(AppState::click_add_folder_button));
+    r.add_child(Button::new("Rescan").padding(5.0).on_click(AppState::click_rescan_button));
+
+    let row = r.expand_width()
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5);
+
+    Either::new(|data: &AppState, _env| data.display_library, row, SizedBox::empty())
+}
+
+/*
+Creating the layout of the typography settings: the font family (Font switches between the fonts of
+the Ebook, the generic families and the fonts embedded in the Ebook, while the TextBox accepts the
+name of any installed font), the line height, the space after the paragraphs, the margins of the
+page and the alignment of the text. It is displayed when the user presses Typography.
+*/
+fn typography_row() -> impl Widget<AppState> {
+    let mut r = Flex::row();
+
+    r.add_child(Button::dynamic(|data: &AppState, _env| {
+        let family = &data.typography.font_family;
+        format!("Font: {}", if family.len() > 0 { family.as_str() } else { "Ebook" })
+    }).padding(5.0).on_click(AppState::click_font_family_button));
+    r.add_child(TextBox::new().with_placeholder("Installed font")
+        .fix_width(120.)
+        .padding(5.0)
+        .lens(AppState::typography.then(Typography::font_family)));
+
+    r.add_child(Label::new(|data: &AppState, _env: &_| format!("Line height {:.1}", data.typography.line_height))
+        .with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    r.add_child(Stepper::new()
+        .with_range(*LINE_HEIGHT_RANGE.start(), *LINE_HEIGHT_RANGE.end())
+        .with_step(0.1)
+        .lens(AppState::typography.then(Typography::line_height)));
+
+    r.add_child(Label::new(|data: &AppState, _env: &_| format!("Paragraph spacing {:.2}", data.typography.paragraph_spacing))
+        .with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    r.add_child(Stepper::new()
+        .with_range(*PARAGRAPH_SPACING_RANGE.start(), *PARAGRAPH_SPACING_RANGE.end())
+        .with_step(0.25)
+        .lens(AppState::typography.then(Typography::paragraph_spacing)));
+
+    r.add_child(Label::new(|data: &AppState, _env: &_| format!("Margins {}", data.typography.margin))
+        .with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    r.add_child(Stepper::new()
+        .with_range(*MARGIN_RANGE.start(), *MARGIN_RANGE.end())
+        .with_step(10.)
+        .lens(AppState::typography.then(Typography::margin)));
+
+    r.add_child(Radio::new("Ebook", Alignment::Ebook).lens(AppState::typography.then(Typography::alignment)).padding(5.0));
+    r.add_child(Radio::new("Justified", Alignment::Justified).lens(AppState::typography.then(Typography::alignment)).padding(5.0));
+    r.add_child(Radio::new("Ragged", Alignment::Ragged).lens(AppState::typography.then(Typography::alignment)).padding(5.0));
+
+    let row = r.expand_width()
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5);
+
+    Either::new(|data: &AppState, _env| data.display_typography && !data.distraction_free, row, SizedBox::empty())
+}
+
+/*
+Creating the layout displayed while an Ebook is loading: a progress bar following the AppState
+variable loading_progress and the Cancel button stopping the loading. It is hidden otherwise.
+*/
+fn loading_row() -> impl Widget<AppState> {
+    let mut r = Flex::row();
+
+    r.add_child(Label::new("Loading the Ebook...").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0));
+    r.add_flex_child(ProgressBar::new().lens(AppState::loading_progress).expand_width().padding(5.0), 1.);
+    r.add_child(Button::new("Cancel").padding(5.0).on_click(AppState::click_cancel_loading_button));
+
+    let row = r.expand_width()
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5);
+
+    Either::new(|data: &AppState, _env| data.loading, row, SizedBox::empty())
+}
+
+/*
+Creating the layout for the functions: Open, Recent, Library, Edit, Scan, Export, Find, Preferences,
+Help, Back and the page navigation section;
+Back returns to the page the last followed link was clicked from;
 Open function is managed through FileDialogOptions;
 TextBox in the navigation section depends on the state of the AppState's variable edit_current_page
 and is done through the method lens.
@@ -57,9 +244,15 @@ fn option_row() -> impl Widget<AppState> {
     });
     let edit_button = Button::new("Edit").padding(5.0).on_click(AppState::click_edit_button);
     let scan_button = Button::new("Scan").padding(5.0).on_click(AppState::click_scan_button);
+    let export_button = Button::new("Export").padding(5.0).on_click(AppState::click_export_button);
+    let find_button = Button::new("Find").padding(5.0).on_click(AppState::click_find_button);
+    let recent_button = Button::new("Recent").padding(5.0).on_click(AppState::click_recent_button);
+    let library_button = Button::new("Library").padding(5.0).on_click(AppState::click_library_button);
 
 
+    let preferences_button = Button::new("Preferences").padding(5.0).on_click(AppState::click_preferences_button);
     let help_button = Button::new("Help").padding(5.0).on_click(AppState::click_help_button);
+    let back_button = Button::new("Back").padding(5.0).on_click(AppState::click_back_button);
     let previous_button = Button::new("Previous Page").padding(5.0).on_click(AppState::click_previous_button);
     let change_page = TextBox::new()
         .with_placeholder("50")
@@ -68,12 +261,18 @@ fn option_row() -> impl Widget<AppState> {
 
     let r1 = Flex::row()
         .with_child(open_button)
+        .with_child(recent_button)
+        .with_child(library_button)
         .with_child(edit_button)
         .with_child(scan_button)
+        .with_child(export_button)
+        .with_child(find_button)
+        .with_child(preferences_button)
         .with_child(help_button)
         .align_left();
 
     let r2 = Flex::row()
+        .with_child(back_button)
         .with_child(previous_button)
         .with_child(change_page)
         .with_child(next_button)
@@ -84,37 +283,65 @@ fn option_row() -> impl Widget<AppState> {
         .with_flex_child(r1, 1.0)
         .with_flex_child(r2, 1.0)
         .expand_width()
-        .background(Color::WHITE)
-        .border(Color::GRAY, 0.5)
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5)
 }
 
 /*
-Creating the layout for the functions: Menu, Single Page, Double Page and the font size section;
+Creating the layout for the functions: Menu, Print Pages, Single Page, Double Page and the font size
+section;
 The function Menu updates the state of the AppState's variable display_menu in order to edit the app
 main section adding a new column;
-TextBox in the font size section depends on the state of the AppState's variable font_size and is
-done through the method lens.
+The function Typography shows the row of the typography settings;
+The function Print Pages shows the number of the printed page next to the number of the page;
+The function Footnote Popover chooses if the links to the footnotes show the note over the page;
+Wheel turns pages and Click zones choose if scrolling past the end of a page and clicking on the
+sides of the reading area turn the pages;
+The function Theme switches to the next theme, and Images (shown with the dark themes) chooses if the
+illustrations are dimmed or inverted;
+The font size section shows the AppState's variable font_size, changed by the Stepper (done through
+the method lens) inside the sizes allowed by the preferences.
 */
 fn settings_row() -> impl Widget<AppState> {
     let display_menu_button = Button::new("Menu").padding(5.0).on_click(AppState::click_display_menu_button);
+    let typography_button = Button::new("Typography").padding(5.0).on_click(AppState::click_typography_button);
     let single_page_button = Button::new("Single Page").padding(5.0).on_click(AppState::click_single_page_button);
     let double_page_button = Button::new("Double Page").padding(5.0).on_click(AppState::click_double_page_button);
-    let plus_button = Button::new("+").padding(5.0).on_click(AppState::click_plus_button);
-    let min_button = Button::new("-").padding(5.0).on_click(AppState::click_min_button);
-
-
-    let edit_size_text = TextBox::new()
-        .with_placeholder("50")
+    let print_pages_button = Button::new("Print Pages").padding(5.0).on_click(AppState::click_print_pages_button);
+    let footnote_button = Button::new("Footnote Popover").padding(5.0).on_click(AppState::click_footnote_popover_button);
+    let theme_button = Button::dynamic(|data: &AppState, _env| format!("Theme: {}", data.theme))
+        .padding(5.0).on_click(AppState::click_theme_button);
+    let image_filter_button = Button::dynamic(|data: &AppState, _env| format!("Images: {}", data.image_filter.name()))
+        .padding(5.0).on_click(AppState::click_image_filter_button);
+    let image_filter = Either::new(|data: &AppState, _env| data.current_theme().dark, image_filter_button, SizedBox::empty());
+
+
+    let wheel_checkbox = Checkbox::new("Wheel turns pages").lens(AppState::wheel_turning).padding(5.0);
+    let click_checkbox = Checkbox::new("Click zones").lens(AppState::click_turning).padding(5.0);
+
+    let size_label = Label::new(|data: &AppState, _env: &_| format!("Font size {}", data.font_size))
+        .with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0);
+    let size_stepper = Stepper::new()
+        .with_range(*FONT_SIZE_RANGE.start(), *FONT_SIZE_RANGE.end())
+        .with_step(1.)
         .lens(AppState::font_size);
 
 
-    let r1 = Flex::row().with_child(display_menu_button).align_left();
+    let r1 = Flex::row()
+        .with_child(display_menu_button)
+        .with_child(typography_button)
+        .with_child(print_pages_button)
+        .with_child(footnote_button)
+        .with_child(wheel_checkbox)
+        .with_child(click_checkbox)
+        .with_child(theme_button)
+        .with_child(image_filter)
+        .align_left();
     let r2 = Flex::row()
         .with_child(single_page_button)
         .with_child(double_page_button)
-        .with_child(min_button)
-        .with_child(edit_size_text)
-        .with_child(plus_button)
+        .with_child(size_label)
+        .with_child(size_stepper)
         .align_right();
 
     Flex::row()
@@ -122,8 +349,16 @@ fn settings_row() -> impl Widget<AppState> {
         .with_flex_child(r2, 1.0)
         .align_vertical(UnitPoint::BOTTOM)
         .expand_width()
-        .background(Color::WHITE)
-        .border(Color::GRAY, 0.5)
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5)
+}
+
+/*
+This function sets the colours of the theme chosen by the user in the Env of a window, painting its
+background with them.
+*/
+fn themed<W: Widget<AppState> + 'static>(widget: W) -> impl Widget<AppState> {
+    EnvScope::new(|env, data: &AppState| data.current_theme().apply(env), widget.background(theme::BACKGROUND))
 }
 
 /*
@@ -132,11 +367,16 @@ to create the entire User Interface.
 */
 pub fn build_ui() -> impl Widget<AppState> {
     let mut c = Flex::column();
-    c.add_child(option_row());
-    c.add_child(bookmark_row());
-    c.add_flex_child(Rebuilder::new(), 1.0);
-    c.add_child(settings_row());
-    return c;
+    c.add_child(Either::new(|data: &AppState, _env| data.distraction_free, SizedBox::empty(), option_row()));
+    c.add_child(library_row());
+    c.add_child(search_row());
+    c.add_child(Either::new(|data: &AppState, _env| data.distraction_free, SizedBox::empty(), bookmark_row()));
+    c.add_child(highlight_row());
+    c.add_child(loading_row());
+    c.add_flex_child(Rebuilder::new().controller(ShortcutController::new(KeyMap::load())), 1.0);
+    c.add_child(typography_row());
+    c.add_child(Either::new(|data: &AppState, _env| data.distraction_free, SizedBox::empty(), settings_row()));
+    return themed(c);
 }
 
 /*
@@ -150,7 +390,79 @@ pub fn build_ui_edit_mode() -> impl Widget<AppState> {
 
     c.add_flex_child(Scroll::new(TextBox::multiline().lens(AppState::current_page_text).expand_width()).vertical(), 1.);
 
-    return c;
+    return themed(c);
+}
+
+/*
+This function builds the window of the preferences, editing a copy of them: the font size, the
+layout of the pages, the links, the page turning, the theme and the typography. Apply uses and saves
+them, Reset brings back the default ones and Close closes the window without applying them.
+*/
+pub fn build_preferences_window() -> impl Widget<AppState> {
+    let mut c = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+
+    c.add_child(Flex::row()
+        .with_child(Label::new(|data: &AppState, _env: &_| format!("Font size {}", data.preferences.font_size))
+            .with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0))
+        .with_child(Stepper::new()
+            .with_range(*FONT_SIZE_RANGE.start(), *FONT_SIZE_RANGE.end())
+            .with_step(1.)
+            .lens(AppState::preferences.then(Preferences::font_size))));
+    c.add_child(Checkbox::new("Double page").lens(AppState::preferences.then(Preferences::double_page)).padding(5.0));
+    c.add_child(Checkbox::new("Show the menu").lens(AppState::preferences.then(Preferences::display_menu)).padding(5.0));
+    c.add_child(Checkbox::new("Footnote popover").lens(AppState::preferences.then(Preferences::footnote_popover)).padding(5.0));
+    c.add_child(Checkbox::new("Print pages").lens(AppState::preferences.then(Preferences::show_print_pages)).padding(5.0));
+    c.add_child(Checkbox::new("Wheel turns pages").lens(AppState::preferences.then(Preferences::wheel_turning)).padding(5.0));
+    c.add_child(Checkbox::new("Click zones").lens(AppState::preferences.then(Preferences::click_turning)).padding(5.0));
+    c.add_child(Button::dynamic(|data: &AppState, _env| format!("Theme: {}", data.preferences.theme))
+        .padding(5.0).on_click(AppState::click_preferences_theme_button));
+    c.add_child(Flex::row()
+        .with_child(Label::new("Images with a dark theme").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0))
+        .with_child(Radio::new("normal", ImageFilter::Normal).lens(AppState::preferences.then(Preferences::image_filter)).padding(5.0))
+        .with_child(Radio::new("dimmed", ImageFilter::Dim).lens(AppState::preferences.then(Preferences::image_filter)).padding(5.0))
+        .with_child(Radio::new("inverted", ImageFilter::Invert).lens(AppState::preferences.then(Preferences::image_filter)).padding(5.0)));
+
+    c.add_child(Label::new("Typography").with_text_color(KeyOrValue::Key(theme::TEXT)).padding((5., 10., 5., 5.)));
+    c.add_child(Flex::row()
+        .with_child(Label::new("Font").with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0))
+        .with_child(TextBox::new().with_placeholder("Fonts of the Ebook")
+            .fix_width(160.)
+            .padding(5.0)
+            .lens(AppState::preferences.then(Preferences::typography).then(Typography::font_family))));
+    c.add_child(Flex::row()
+        .with_child(Label::new(|data: &AppState, _env: &_| format!("Line height {:.1}", data.preferences.typography.line_height))
+            .with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0))
+        .with_child(Stepper::new()
+            .with_range(*LINE_HEIGHT_RANGE.start(), *LINE_HEIGHT_RANGE.end())
+            .with_step(0.1)
+            .lens(AppState::preferences.then(Preferences::typography).then(Typography::line_height)))
+        .with_child(Label::new(|data: &AppState, _env: &_| format!("Paragraph spacing {:.2}", data.preferences.typography.paragraph_spacing))
+            .with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0))
+        .with_child(Stepper::new()
+            .with_range(*PARAGRAPH_SPACING_RANGE.start(), *PARAGRAPH_SPACING_RANGE.end())
+            .with_step(0.25)
+            .lens(AppState::preferences.then(Preferences::typography).then(Typography::paragraph_spacing))));
+    c.add_child(Flex::row()
+        .with_child(Label::new(|data: &AppState, _env: &_| format!("Margins {}", data.preferences.typography.margin))
+            .with_text_color(KeyOrValue::Key(theme::TEXT)).padding(5.0))
+        .with_child(Stepper::new()
+            .with_range(*MARGIN_RANGE.start(), *MARGIN_RANGE.end())
+            .with_step(10.)
+            .lens(AppState::preferences.then(Preferences::typography).then(Typography::margin)))
+        .with_child(Radio::new("Ebook", Alignment::Ebook).lens(AppState::preferences.then(Preferences::typography).then(Typography::alignment)).padding(5.0))
+        .with_child(Radio::new("Justified", Alignment::Justified).lens(AppState::preferences.then(Preferences::typography).then(Typography::alignment)).padding(5.0))
+        .with_child(Radio::new("Ragged", Alignment::Ragged).lens(AppState::preferences.then(Preferences::typography).then(Typography::alignment)).padding(5.0)));
+
+    c.add_flex_spacer(1.);
+    c.add_child(Flex::row()
+        .with_child(Button::new("Apply").padding(5.0).on_click(AppState::click_apply_preferences_button))
+        .with_child(Button::new("Reset").padding(5.0).on_click(AppState::click_reset_preferences_button))
+        .with_child(Button::new("Close").padding(5.0).on_click(|ctx, _, _| {
+            ctx.submit_command(druid::commands::CLOSE_WINDOW);
+        }))
+        .align_right());
+
+    themed(c.padding(10.).expand())
 }
 
 /*
@@ -190,8 +502,8 @@ fn option_row_edit_mode() -> impl Widget<AppState> {
     Flex::row()
         .with_flex_child(r1, 1.0)
         .expand_width()
-        .background(Color::WHITE)
-        .border(Color::GRAY, 0.5)
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5)
 }
 
 /*
@@ -220,612 +532,101 @@ the user how to start using the app.
 */
 pub fn build_widget(state: &AppState) -> Box<dyn Widget<AppState>> {
     let mut c = Flex::column();
-    let mut i = 0 as usize;
-    let mut pixels_vec = Vec::new();
-    let mut image_buf;
     let scroll;
     let mut c2 = Flex::column();
 
+    if state.display_recent {
+        c.add_child(recent_popover(state));
+    }
+
+    if state.display_library {
+        return library_view(state, c);
+    }
+
+    if state.library_hits.len() > 0 {
+        c.add_child(library_results(state));
+    }
+
     if state.ebook.len() <= 0 {
-        c.add_child(Label::new("\n\n\n\n\n\n\n\n\t\t\t\t\t\t\t\t\tWelcome to Ebook Reader!\n\t\t\t\t\t\t\tPress the Open button to start reading an Ebook\n\t\t\t\t\t\t\tUse Help button to open the application guide")
+        c.add_child(Label::new("\n\n\n\n\n\n\n\n\t\t\t\t\t\t\t\t\tWelcome to Ebook Reader!\n\t\t\t\t\t\t\tPress the Open button to start reading an Ebook\n\t\t\t\t\t\t\tor the Library button to browse the Ebooks of your folders\n\t\t\t\t\t\t\tUse Help button to open the application guide")
             .with_text_size(KeyOrValue::Concrete(20.)));
+        if let Some(resume) = resume_button(state) {
+            c.add_child(resume.padding((0., 20.)));
+        }
     } else if state.ebook.len() > 0
-        && state.font_size != "0"
         && state.edit_current_page.len() > 0
         && check_valid_number(state.clone().edit_current_page) != "Not valid"
         && state.edit_current_page._is_numeric() {
-        if state.current_page != 0 {
-            let mut str_page_number = String::new();
-            str_page_number.push_str((state.current_page).to_string().as_str());
-            str_page_number.push_str("\n\n");
-
-            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                let rl_page = Label::new(str_page_number)
-                    .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                    .with_text_alignment(TextAlignment::Center)
-                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                c.add_child(rl_page);
-            } else {
-                let rl_page = Label::new(str_page_number)
-                    .with_text_size(KeyOrValue::Concrete(1.))
-                    .with_text_alignment(TextAlignment::Center)
-                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                c.add_child(rl_page);
-            }
-        }
-
-
-        let init = state.ebook[state.current_page].text.find("<body");
-        let end_body = state.ebook[state.current_page].text.find("</html>");
-
-        if end_body.is_some() && init.is_some() {
-            if end_body.unwrap() < init.unwrap() {
-                for element in state.ebook[state.current_page].text[..end_body.unwrap()].split("\n") {
-                    if element.contains("img") {
-                        for pixel in state.ebook[state.current_page].images[i].image.clone() {
-                            pixels_vec.push(pixel);
-                        }
-                        match pixels_vec.len() / (state.ebook[state.current_page].images[i].width * state.ebook[state.current_page].images[i].height) {
-                            1 => {
-                                image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Grayscale, state.ebook[state.current_page]
-                                    .images[i].width, state.ebook[state.current_page].images[i].height);
-                            }
-                            3 => {
-                                image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Rgb, state.ebook[state.current_page]
-                                    .images[i].width, state.ebook[state.current_page].images[i].height);
-                            }
-                            4 => {
-                                image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::RgbaPremul, state.ebook[state.current_page]
-                                    .images[i].width, state.ebook[state.current_page].images[i].height);
-                            }
-                            _ => { panic!("Unable to process the image") }
-                        }
-
-
-                        let img = Image::new(image_buf.clone()).fill_mode(FillStrat::Fill);
-
-                        if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                            let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.),
-                                                                    image_buf.height().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.));
-
-                            let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-                            c.add_child(container);
-                        } else {
-                            let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (1. / 40.),
-                                                                    image_buf.height().clone() as f64 * (1. / 40.));
-
-                            let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-                            c.add_child(container);
-                        }
-
-
-                        i += 1;
-                        pixels_vec.clear();
-                    } else {
-                        let mut _string;
-
-                        let mut appStr = element.to_string();
-
-                        if appStr.len() >= 1 {
-                            if appStr.chars().last().unwrap() == '<' {
-                                appStr.replace_range(appStr.len() - 1.., "");
-                            }
-                        }
-
-                        _string = strip_tags(appStr.as_str());
-
-                        if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                            let rl = Label::new(_string.clone())
-                                .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                                .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-                            c.add_child(rl);
-                        } else {
-                            let rl = Label::new(_string.clone())
-                                .with_text_size(KeyOrValue::Concrete(1.))
-                                .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-                            c.add_child(rl);
-                        }
-                    }
-                }
-            }
-        }
-
-        if init.is_some() {
-            for element in state.ebook[state.current_page].text[init.unwrap()..].split("\n") {
-                if element.contains("img") {
-                    for pixel in state.ebook[state.current_page].images[i].image.clone() {
-                        pixels_vec.push(pixel);
-                    }
-                    match pixels_vec.len() / (state.ebook[state.current_page].images[i].width * state.ebook[state.current_page].images[i].height) {
-                        1 => {
-                            image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Grayscale, state.ebook[state.current_page]
-                                .images[i].width, state.ebook[state.current_page].images[i].height);
-                        }
-                        3 => {
-                            image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Rgb, state.ebook[state.current_page]
-                                .images[i].width, state.ebook[state.current_page].images[i].height);
-                        }
-                        4 => {
-                            image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::RgbaPremul, state.ebook[state.current_page]
-                                .images[i].width, state.ebook[state.current_page].images[i].height);
-                        }
-                        _ => { panic!("Unable to process the image") }
-                    }
-
-
-                    let img = Image::new(image_buf.clone()).fill_mode(FillStrat::Fill);
-
-                    if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                        let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.),
-                                                                image_buf.height().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.));
-
-                        let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-
-                        c.add_child(container);
-                    } else {
-                        let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (1. / 40.),
-                                                                image_buf.height().clone() as f64 * (1. / 40.));
-
-                        let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-
-                        c.add_child(container);
-                    }
-
-
-                    i += 1;
-                    pixels_vec.clear();
-                } else {
-                    let mut _string;
-
-                    let mut appStr = element.to_string();
-
-                    if appStr.len() >= 1 {
-                        if appStr.chars().last().unwrap() == '<' {
-                            appStr.replace_range(appStr.len() - 1.., "");
-                        }
-                    }
-
-                    _string = strip_tags(appStr.as_str());
-
-                    if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                        let rl = Label::new(_string.clone())
-                            .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                            .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                        c.add_child(rl);
-                    } else {
-                        let rl = Label::new(_string.clone())
-                            .with_text_size(KeyOrValue::Concrete(1.))
-                            .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                        c.add_child(rl);
-                    }
-                }
-            }
-        } else {
-            for element in state.ebook[state.current_page].text.split("\n") {
-                if element.contains("img") {
-                    for pixel in state.ebook[state.current_page].images[i].image.clone() {
-                        pixels_vec.push(pixel);
-                    }
-                    match pixels_vec.len() / (state.ebook[state.current_page].images[i].width * state.ebook[state.current_page].images[i].height) {
-                        1 => {
-                            image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Grayscale, state.ebook[state.current_page]
-                                .images[i].width, state.ebook[state.current_page].images[i].height);
-                        }
-                        3 => {
-                            image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Rgb, state.ebook[state.current_page]
-                                .images[i].width, state.ebook[state.current_page].images[i].height);
-                        }
-                        4 => {
-                            image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::RgbaPremul, state.ebook[state.current_page]
-                                .images[i].width, state.ebook[state.current_page].images[i].height);
-                        }
-                        _ => { panic!("Unable to process the image") }
-                    }
-
-
-                    let img = Image::new(image_buf.clone()).fill_mode(FillStrat::Fill);
-
-                    if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                        let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.),
-                                                                image_buf.height().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.));
-
-                        let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-
-                        c.add_child(container);
-                    } else {
-                        let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (1. / 40.),
-                                                                image_buf.height().clone() as f64 * (1. / 40.));
-
-                        let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-
-                        c.add_child(container);
-                    }
-
-
-                    i += 1;
-                    pixels_vec.clear();
-                } else {
-                    let mut _string;
-
-                    let mut appStr = element.to_string();
-
-                    if appStr.len() >= 1 {
-                        if appStr.chars().last().unwrap() == '<' {
-                            appStr.replace_range(appStr.len() - 1.., "");
-                        }
-                    }
-
-                    _string = strip_tags(appStr.as_str());
-
-                    if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                        let rl = Label::new(_string.clone())
-                            .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                            .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                        c.add_child(rl);
-                    } else {
-                        let rl = Label::new(_string.clone())
-                            .with_text_size(KeyOrValue::Concrete(1.))
-                            .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                        c.add_child(rl);
-                    }
-                }
-            }
+        if state.footnote.len() > 0 {
+            c.add_child(footnote_popover(state));
         }
 
+        build_page(state, state.current_page, &mut c);
 
         if state.double_page {
             if state.current_page + 1 < state.ebook.len() {
-                let mut str_page = String::new();
-                str_page.push_str((state.current_page + 1).to_string().as_str());
-                str_page.push_str("\n\n");
-                if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                    let rl_page = Label::new(str_page)
-                        .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                        .with_text_alignment(TextAlignment::Center)
-                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                    c2.add_child(rl_page);
-                } else {
-                    let rl_page = Label::new(str_page)
-                        .with_text_size(KeyOrValue::Concrete(1.))
-                        .with_text_alignment(TextAlignment::Center)
-                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                    c2.add_child(rl_page);
-                }
-
-                let init_double = state.ebook[state.current_page + 1].text.find("<body");
-                let end_body_double = state.ebook[state.current_page + 1].text.find("</html>");
-                i = 0;
-                if init_double.is_some() && end_body_double.is_some() {
-                    if end_body_double.unwrap() < init_double.unwrap() {
-                        for element in state.ebook[state.current_page + 1].text[..end_body_double.unwrap()].split("\n") {
-                            if element.contains("img") {
-                                for pixel in state.ebook[state.current_page + 1].images[i].image.clone() {
-                                    pixels_vec.push(pixel);
-                                }
-                                match pixels_vec.len() / (state.ebook[state.current_page + 1].images[i].width * state.ebook[state.current_page + 1].images[i].height) {
-                                    1 => {
-                                        image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Grayscale, state.ebook[state.current_page + 1]
-                                            .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                    }
-                                    3 => {
-                                        image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Rgb, state.ebook[state.current_page + 1]
-                                            .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                    }
-                                    4 => {
-                                        image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::RgbaPremul, state.ebook[state.current_page + 1]
-                                            .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                    }
-                                    _ => { panic!("Unable to process the image") }
-                                }
-
-
-                                let img = Image::new(image_buf.clone()).fill_mode(FillStrat::Fill);
-
-                                if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                                    let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.),
-                                                                            image_buf.height().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.));
-
-                                    let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-                                    c2.add_child(container);
-                                } else {
-                                    let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (1. / 40.),
-                                                                            image_buf.height().clone() as f64 * (1. / 40.));
-
-                                    let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-                                    c2.add_child(container);
-                                }
-
-
-                                i += 1;
-                                pixels_vec.clear();
-                            } else {
-                                let mut _string;
-
-                                let mut appStr = element.to_string();
-
-                                if appStr.len() >= 1 {
-                                    if appStr.chars().last().unwrap() == '<' {
-                                        appStr.replace_range(appStr.len() - 1.., "");
-                                    }
-                                }
-
-                                _string = strip_tags(appStr.as_str());
-
-                                if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                                    let rl = Label::new(_string.clone())
-                                        .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-                                    c2.add_child(rl);
-                                } else {
-                                    let rl = Label::new(_string.clone())
-                                        .with_text_size(KeyOrValue::Concrete(1.))
-                                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-                                    c2.add_child(rl);
-                                }
-                            }
-                        }
-                    }
-                }
-                if init_double.is_some() {
-                    for element in state.ebook[state.current_page + 1].text[init_double.unwrap()..].split("\n") {
-                        if element.contains("img") {
-                            for pixel in state.ebook[state.current_page + 1].images[i].image.clone() {
-                                pixels_vec.push(pixel);
-                            }
-                            match pixels_vec.len() / (state.ebook[state.current_page + 1].images[i].width * state.ebook[state.current_page + 1].images[i].height) {
-                                1 => {
-                                    image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Grayscale, state.ebook[state.current_page + 1]
-                                        .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                }
-                                3 => {
-                                    image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Rgb, state.ebook[state.current_page + 1]
-                                        .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                }
-                                4 => {
-                                    image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::RgbaPremul, state.ebook[state.current_page + 1]
-                                        .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                }
-                                _ => { panic!("Unable to process the image") }
-                            }
-
-
-                            let img = Image::new(image_buf.clone()).fill_mode(FillStrat::Fill);
-
-                            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                                let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.),
-                                                                        image_buf.height().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.));
-
-                                let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-
-                                c2.add_child(container);
-                            } else {
-                                let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (1. / 40.),
-                                                                        image_buf.height().clone() as f64 * (1. / 40.));
-
-                                let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-
-                                c2.add_child(container);
-                            }
-
-
-                            i += 1;
-                            pixels_vec.clear();
-                        } else {
-                            let mut _string;
-
-                            let mut appStr = element.to_string();
-
-                            if appStr.len() >= 1 {
-                                if appStr.chars().last().unwrap() == '<' {
-                                    appStr.replace_range(appStr.len() - 1.., "");
-                                }
-                            }
-
-                            _string = strip_tags(appStr.as_str());
-
-                            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                                let rl = Label::new(_string.clone())
-                                    .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                                c2.add_child(rl);
-                            } else {
-                                let rl = Label::new(_string.clone())
-                                    .with_text_size(KeyOrValue::Concrete(1.))
-                                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                                c2.add_child(rl);
-                            }
-                        }
-                    }
-                } else {
-                    for element in state.ebook[state.current_page + 1].text.split("\n") {
-                        if element.contains("img") {
-                            for pixel in state.ebook[state.current_page + 1].images[i].image.clone() {
-                                pixels_vec.push(pixel);
-                            }
-                            match pixels_vec.len() / (state.ebook[state.current_page + 1].images[i].width * state.ebook[state.current_page + 1].images[i].height) {
-                                1 => {
-                                    image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Grayscale, state.ebook[state.current_page + 1]
-                                        .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                }
-                                3 => {
-                                    image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::Rgb, state.ebook[state.current_page + 1]
-                                        .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                }
-                                4 => {
-                                    image_buf = ImageBuf::from_raw(pixels_vec.clone(), ImageFormat::RgbaPremul, state.ebook[state.current_page + 1]
-                                        .images[i].width, state.ebook[state.current_page + 1].images[i].height);
-                                }
-                                _ => { panic!("Unable to process the image") }
-                            }
-
-
-                            let img = Image::new(image_buf.clone()).fill_mode(FillStrat::Fill);
-
-                            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                                let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.),
-                                                                        image_buf.height().clone() as f64 * (state.font_size.clone().parse::<f64>().unwrap() / 40.));
-
-                                let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-
-                                c2.add_child(container);
-                            } else {
-                                let sized = SizedBox::new(img).fix_size(image_buf.width().clone() as f64 * (1. / 40.),
-                                                                        image_buf.height().clone() as f64 * (1. / 40.));
-
-                                let container = sized.border(Color::grey(0.6), 2.0).center().boxed();
-
-                                c2.add_child(container);
-                            }
-
-
-                            i += 1;
-                            pixels_vec.clear();
-                        } else {
-                            let mut _string;
-
-                            let mut appStr = element.to_string();
-
-                            if appStr.len() >= 1 {
-                                if appStr.chars().last().unwrap() == '<' {
-                                    appStr.replace_range(appStr.len() - 1.., "");
-                                }
-                            }
-
-                            _string = strip_tags(appStr.as_str());
-
-                            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                                let rl = Label::new(_string.clone())
-                                    .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                                c2.add_child(rl);
-                            } else {
-                                let rl = Label::new(_string.clone())
-                                    .with_text_size(KeyOrValue::Concrete(1.))
-                                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size);
-
-                                c2.add_child(rl);
-                            }
-                        }
-                    }
-                }
+                build_page(state, state.current_page + 1, &mut c2);
             }
         }
     }
 
     if state.double_page {
         let mut c3 = Flex::row();
-        if state.display_menu && state.font_size != "0" {
+        if state.display_menu {
             let mut c4 = Flex::column();
+            search_menu(state, &mut c4);
 
-            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                c4.add_child(Padding::new((0., 10.), Label::new("BOOKMARKS")
-                    .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-            } else {
-                c4.add_child(Padding::new((0., 10.), Label::new("BOOKMARKS")
-                    .with_text_size(KeyOrValue::Concrete(1.))
-                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-            }
+            c4.add_child(Padding::new((0., 10.), Label::new("BOOKMARKS")
+                .with_text_size(KeyOrValue::Concrete(state.font_size))
+                .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
 
             if state.saves.bookmarks.len() > 0 {
                 for bookmark in state.saves.bookmarks.clone() {
                     let mut ro = Flex::row();
-
-                    if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                        let ch = ControllerHost::new(Label::new(bookmark.0.clone() + " - pag. " + (bookmark.1.clone()).to_string().as_str())
-                                                         .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                                                         .with_text_color(KeyOrValue::Concrete(Color::LIME))
-                                                         .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
-                            ctx.submit_command(GO_TO_POS.with(bookmark.1.clone()));
-                        }));
-
-                        ro.add_flex_child(ch, 1.0);
-                    } else {
-                        let ch = ControllerHost::new(Label::new(bookmark.0.clone() + " - pag. " + (bookmark.1.clone()).to_string().as_str())
-                                                         .with_text_size(KeyOrValue::Concrete(1.))
-                                                         .with_text_color(KeyOrValue::Concrete(Color::LIME))
-                                                         .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
-                            ctx.submit_command(GO_TO_POS.with(bookmark.1.clone()));
-                        }));
-
-                        ro.add_flex_child(ch, 1.0);
-                    }
-
-
-                    if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                        let x_button = Label::new("x")
-                            .with_text_color(KeyOrValue::Concrete(Color::RED))
-                            .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                            .on_click(move |ctx, _, _| {
-                                ctx.submit_command(DELETE_BOOKMARK.with(bookmark.clone()));
-                            });
-
-                        ro.add_flex_child(x_button, 0.6);
-                        c4.add_child(ro);
-                    } else {
-                        let x_button = Label::new("x")
-                            .with_text_color(KeyOrValue::Concrete(Color::RED))
-                            .with_text_size(KeyOrValue::Concrete(1.))
-                            .on_click(move |ctx, _, _| {
-                                ctx.submit_command(DELETE_BOOKMARK.with(bookmark.clone()));
-                            });
-
-                        ro.add_flex_child(x_button, 0.6);
-                        c4.add_child(ro);
-                    }
+                    let page = state.page_of_locator(&bookmark.1)
+                        .map(|page| page.to_string())
+                        .unwrap_or(String::from("..."));
+                    let locator = bookmark.1.clone();
+
+                    let ch = ControllerHost::new(Label::new(bookmark.0.clone() + " - pag. " + page.as_str())
+                        .with_text_size(KeyOrValue::Concrete(state.font_size))
+                        .with_text_color(KeyOrValue::Key(theme::LINK))
+                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
+                        ctx.submit_command(GO_TO_LOCATOR.with(locator.clone()));
+                    }));
+
+                    ro.add_flex_child(ch, 1.0);
+
+                    let x_button = Label::new("x")
+                        .with_text_color(KeyOrValue::Key(theme::DELETE))
+                        .with_text_size(KeyOrValue::Concrete(state.font_size))
+                        .on_click(move |ctx, _, _| {
+                            ctx.submit_command(DELETE_BOOKMARK.with(bookmark.clone()));
+                        });
+
+                    ro.add_flex_child(x_button, 0.6);
+                    c4.add_child(ro);
                 }
             } else {
-                if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                    c4.add_child(Padding::new((0., 10.), Label::new("No bookmarks available")
-                        .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-                } else {
-                    c4.add_child(Padding::new((0., 10.), Label::new("No bookmarks available")
-                        .with_text_size(KeyOrValue::Concrete(1.))
-                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-                }
-            }
-
-            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                c4.add_child(Padding::new((0., 20.), Label::new("CHAPTERS")
-                    .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-            } else {
-                c4.add_child(Padding::new((0., 20.), Label::new("CHAPTERS")
-                    .with_text_size(KeyOrValue::Concrete(1.))
+                c4.add_child(Padding::new((0., 10.), Label::new("No bookmarks available")
+                    .with_text_size(KeyOrValue::Concrete(state.font_size))
                     .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
             }
 
+            highlights_menu(state, &mut c4);
 
-            for chapter in state.chapters.clone() {
-                if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                    c4.add_child(ControllerHost::new(Label::new(chapter.title.clone())
-                                                         .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                                                         .with_text_color(KeyOrValue::Concrete(Color::LIME))
-                                                         .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
-                        ctx.submit_command(GO_TO_POS.with(chapter.target_page.clone()));
-                    })));
-                    c4.add_child(Label::new("\n"));
-                } else {
-                    c4.add_child(ControllerHost::new(Label::new(chapter.title.clone())
-                                                         .with_text_size(KeyOrValue::Concrete(1.))
-                                                         .with_text_color(KeyOrValue::Concrete(Color::LIME))
-                                                         .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
-                        ctx.submit_command(GO_TO_POS.with(chapter.target_page.clone()));
-                    })));
-                    c4.add_child(Label::new("\n"));
-                }
+            c4.add_child(Padding::new((0., 20.), Label::new("CHAPTERS")
+                .with_text_size(KeyOrValue::Concrete(state.font_size))
+                .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
+
+            for (depth, chapter) in flatten_chapters(&state.chapters) {
+                c4.add_child(Padding::new((depth as f64 * 20., 0., 0., 0.), ControllerHost::new(Label::new(chapter.title.clone())
+                    .with_text_size(KeyOrValue::Concrete(state.font_size))
+                    .with_text_color(KeyOrValue::Key(theme::LINK))
+                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size - depth as f64 * 20.), Click::new(move |ctx, _, _| {
+                    ctx.submit_command(GO_TO_POS.with(chapter.target_page.clone()));
+                }))));
+                c4.add_child(Label::new("\n"));
             }
             c3.add_flex_child(c4, 0.2);
             let padd = Padding::new((30., 0.), c.cross_axis_alignment(CrossAxisAlignment::Start));
@@ -839,109 +640,62 @@ pub fn build_widget(state: &AppState) -> Box<dyn Widget<AppState>> {
         scroll = Scroll::new(c3.cross_axis_alignment(CrossAxisAlignment::Start)).vertical();
     } else {
         let mut c3 = Flex::row();
-        if state.display_menu && state.font_size != "0" {
+        if state.display_menu {
             let mut c4 = Flex::column();
+            search_menu(state, &mut c4);
 
-            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                c4.add_child(Padding::new((0., 10.), Label::new("BOOKMARKS")
-                    .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-            } else {
-                c4.add_child(Padding::new((0., 10.), Label::new("BOOKMARKS")
-                    .with_text_size(KeyOrValue::Concrete(1.))
-                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-            }
+            c4.add_child(Padding::new((0., 10.), Label::new("BOOKMARKS")
+                .with_text_size(KeyOrValue::Concrete(state.font_size))
+                .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
 
             if state.saves.bookmarks.len() > 0 {
                 for bookmark in state.saves.bookmarks.clone() {
                     let mut ro = Flex::row();
-
-                    if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                        let ch = ControllerHost::new(Label::new(bookmark.0.clone() + " - pag. " + (bookmark.1.clone()).to_string().as_str())
-                                                         .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                                                         .with_text_color(KeyOrValue::Concrete(Color::LIME))
-                                                         .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
-                            ctx.submit_command(GO_TO_POS.with(bookmark.1.clone()));
-                        }));
-
-                        ro.add_flex_child(ch, 1.0);
-                    } else {
-                        let ch = ControllerHost::new(Label::new(bookmark.0.clone() + " - pag. " + (bookmark.1.clone() + 1).to_string().as_str())
-                                                         .with_text_size(KeyOrValue::Concrete(1.))
-                                                         .with_text_color(KeyOrValue::Concrete(Color::LIME))
-                                                         .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
-                            ctx.submit_command(GO_TO_POS.with(bookmark.1.clone()));
-                        }));
-
-                        ro.add_flex_child(ch, 1.0);
-                    }
-
-
-                    if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                        let x_button = Label::new("x")
-                            .with_text_color(KeyOrValue::Concrete(Color::RED))
-                            .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                            .on_click(move |ctx, _, _| {
-                                ctx.submit_command(DELETE_BOOKMARK.with(bookmark.clone()));
-                            });
-
-                        ro.add_flex_child(x_button, 0.5);
-                        c4.add_child(ro);
-                    } else {
-                        let x_button = Label::new("x")
-                            .with_text_color(KeyOrValue::Concrete(Color::RED))
-                            .with_text_size(KeyOrValue::Concrete(1.))
-                            .on_click(move |ctx, _, _| {
-                                ctx.submit_command(DELETE_BOOKMARK.with(bookmark.clone()));
-                            });
-
-                        ro.add_flex_child(x_button, 0.5);
-                        c4.add_child(ro);
-                    }
+                    let page = state.page_of_locator(&bookmark.1)
+                        .map(|page| page.to_string())
+                        .unwrap_or(String::from("..."));
+                    let locator = bookmark.1.clone();
+
+                    let ch = ControllerHost::new(Label::new(bookmark.0.clone() + " - pag. " + page.as_str())
+                        .with_text_size(KeyOrValue::Concrete(state.font_size))
+                        .with_text_color(KeyOrValue::Key(theme::LINK))
+                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
+                        ctx.submit_command(GO_TO_LOCATOR.with(locator.clone()));
+                    }));
+
+                    ro.add_flex_child(ch, 1.0);
+
+                    let x_button = Label::new("x")
+                        .with_text_color(KeyOrValue::Key(theme::DELETE))
+                        .with_text_size(KeyOrValue::Concrete(state.font_size))
+                        .on_click(move |ctx, _, _| {
+                            ctx.submit_command(DELETE_BOOKMARK.with(bookmark.clone()));
+                        });
+
+                    ro.add_flex_child(x_button, 0.5);
+                    c4.add_child(ro);
                 }
-            } else {
-                if state.font_size.len() > 0 && state.font_size.clone().parse::<f64>().is_ok() {
-                    c4.add_child(Padding::new((0., 10.), Label::new("No bookmarks available")
-                        .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-                } else {
-                    c4.add_child(Padding::new((0., 10.), Label::new("No bookmarks available")
-                        .with_text_size(KeyOrValue::Concrete(1.))
-                        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
-                }
-            }
-
-            if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                c4.add_child(Padding::new((0., 20.), Label::new("CHAPTERS")
-                    .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
             } else {
                 c4.add_child(Padding::new((0., 10.), Label::new("No bookmarks available")
-                    .with_text_size(KeyOrValue::Concrete(1.))
+                    .with_text_size(KeyOrValue::Concrete(state.font_size))
                     .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
             }
 
+            highlights_menu(state, &mut c4);
 
-            for chapter in state.chapters.clone() {
-                if state.font_size.len() > 0 && check_valid_number(state.font_size.clone()) == "Ok" {
-                    c4.add_child(ControllerHost::new(Label::new(chapter.title.clone())
-                                                         .with_text_size(KeyOrValue::Concrete(state.font_size.clone().parse::<f64>().unwrap()))
-                                                         .with_text_color(KeyOrValue::Concrete(Color::LIME))
-                                                         .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
-                        ctx.submit_command(GO_TO_POS.with(chapter.target_page.clone()));
-                    })));
-
-                    c4.add_child(Label::new("\n"));
-                } else {
-                    c4.add_child(ControllerHost::new(Label::new(chapter.title.clone())
-                                                         .with_text_size(KeyOrValue::Concrete(1.))
-                                                         .with_text_color(KeyOrValue::Concrete(Color::LIME))
-                                                         .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size), Click::new(move |ctx, _, _| {
-                        ctx.submit_command(GO_TO_POS.with(chapter.target_page.clone()));
-                    })));
-
-                    c4.add_child(Label::new("\n"));
-                }
+            c4.add_child(Padding::new((0., 20.), Label::new("CHAPTERS")
+                .with_text_size(KeyOrValue::Concrete(state.font_size))
+                .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
+
+            for (depth, chapter) in flatten_chapters(&state.chapters) {
+                c4.add_child(Padding::new((depth as f64 * 20., 0., 0., 0.), ControllerHost::new(Label::new(chapter.title.clone())
+                    .with_text_size(KeyOrValue::Concrete(state.font_size))
+                    .with_text_color(KeyOrValue::Key(theme::LINK))
+                    .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size - depth as f64 * 20.), Click::new(move |ctx, _, _| {
+                    ctx.submit_command(GO_TO_POS.with(chapter.target_page.clone()));
+                }))));
+
+                c4.add_child(Label::new("\n"));
             }
             c3.add_flex_child(c4, 0.2);
             let padd = Padding::new((20., 0.), c.cross_axis_alignment(CrossAxisAlignment::Start));
@@ -953,7 +707,504 @@ pub fn build_widget(state: &AppState) -> Box<dyn Widget<AppState>> {
         scroll = Scroll::new(c3.cross_axis_alignment(CrossAxisAlignment::Start)).vertical();
     }
 
-
-    let padding = Padding::new((50.0, 10.), scroll);
+    let padding = Padding::new((state.typography.margin, PAGE_PADDING), PageTurner::new(scroll));
     SizedBox::new(padding).expand_height().boxed()
 }
+
+/*
+This function adds to the menu the hits of the last search, if any: every hit shows its page and the
+text around it, the current one in bold, and a click on it moves to its page. Only the hits around
+the current one are listed, since the menu is built again at every change.
+*/
+fn search_menu(state: &AppState, c4: &mut Flex<AppState>) {
+    if state.search_hits.len() == 0 {
+        return;
+    }
+    let size = state.font_size;
+
+    c4.add_child(Padding::new((0., 10.), Label::new(format!("SEARCH RESULTS ({})", state.search_hits.len()))
+        .with_text_size(KeyOrValue::Concrete(size))
+        .with_line_break_mode(LineBreaking::WordWrap)));
+
+    let first = state.search_current.saturating_sub(LISTED_HITS / 2);
+    let last = (first + LISTED_HITS).min(state.search_hits.len());
+    for (i, hit) in state.search_hits.iter().enumerate().take(last).skip(first) {
+        let mut font = FontDescriptor::default().with_size(size * 0.8);
+        if i == state.search_current {
+            font = font.with_weight(FontWeight::BOLD);
+        }
+
+        c4.add_child(ControllerHost::new(Label::new(format!("pag. {} - {}", hit.page, hit.context))
+                                             .with_font(font)
+                                             .with_text_color(KeyOrValue::Key(theme::LINK))
+                                             .with_line_break_mode(LineBreaking::WordWrap), Click::new(move |ctx, _, _| {
+            ctx.submit_command(GO_TO_HIT.with(i));
+        })).padding((0., 0., 0., 5.)));
+    }
+}
+
+/*
+This function adds to the menu the list of the highlights of the Ebook: every highlight shows the
+beginning of its text on its colour and its note, a click on it moves to its page and the "x"
+deletes it.
+*/
+fn highlights_menu(state: &AppState, c4: &mut Flex<AppState>) {
+    let size = state.font_size;
+
+    c4.add_child(Padding::new((0., 20.), Label::new("HIGHLIGHTS")
+        .with_text_size(KeyOrValue::Concrete(size))
+        .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
+
+    if state.saves.highlights.len() == 0 {
+        c4.add_child(Padding::new((0., 10.), Label::new("No highlights available")
+            .with_text_size(KeyOrValue::Concrete(size))
+            .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.window_size)));
+        return;
+    }
+
+    for highlight in state.saves.highlights.clone() {
+        let mut text: String = highlight.text.chars().take(80).collect();
+        if text.len() < highlight.text.len() {
+            text.push_str("...");
+        }
+        let page = state.page_of_locator(&highlight.position)
+            .map(|page| page.to_string())
+            .unwrap_or(String::from("..."));
+        let position = highlight.position.clone();
+
+        let mut entry = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+        entry.add_child(ControllerHost::new(Label::new(text + " - pag. " + page.as_str())
+                                                .with_text_size(KeyOrValue::Concrete(size))
+                                                .with_text_color(KeyOrValue::Concrete(Color::BLACK))
+                                                .with_line_break_mode(LineBreaking::WordWrap)
+                                                .background(highlight.color.color()), Click::new(move |ctx, _, _| {
+            ctx.submit_command(GO_TO_LOCATOR.with(position.clone()));
+        })));
+        if highlight.note.len() > 0 {
+            entry.add_child(Label::new(highlight.note.clone())
+                .with_text_size(KeyOrValue::Concrete(size * 0.8))
+                .with_line_break_mode(LineBreaking::WordWrap));
+        }
+
+        let mut ro = Flex::row().cross_axis_alignment(CrossAxisAlignment::Start);
+        ro.add_flex_child(entry, 1.0);
+        ro.add_flex_child(Label::new("x")
+            .with_text_color(KeyOrValue::Key(theme::DELETE))
+            .with_text_size(KeyOrValue::Concrete(size))
+            .on_click(move |ctx, _, _| {
+                ctx.submit_command(DELETE_HIGHLIGHT.with(highlight.clone()));
+            }), 0.6);
+        c4.add_child(ro.padding((0., 0., 0., 5.)));
+    }
+}
+
+/*
+This function adds a page of the Ebook to the given column: a Row indicating the number of the page
+(and of the printed page, if requested) followed by the blocks of the page, decoded by the Book
+of the Ebook (which parses its HTML and applies the stylesheets of its file). The highlights of the
+page and the text selected by the user are painted on its blocks.
+*/
+fn build_page(state: &AppState, index: usize, column: &mut Flex<AppState>) {
+    let page = &state.ebook[index];
+    let size = state.font_size;
+    let width = state.text_width();
+
+    if index != 0 {
+        let mut str_page_number = String::new();
+        str_page_number.push_str(index.to_string().as_str());
+        if state.show_print_pages && page.print_page.len() > 0 {
+            str_page_number.push_str(" (print page ");
+            str_page_number.push_str(page.print_page.as_str());
+            str_page_number.push_str(")");
+        }
+        str_page_number.push_str("\n\n");
+
+        let rl_page = Label::new(str_page_number)
+            .with_text_size(KeyOrValue::Concrete(size))
+            .with_text_alignment(TextAlignment::Center)
+            .with_line_break_mode(LineBreaking::WordWrap).fix_width(width);
+
+        column.add_child(rl_page);
+    }
+
+    if let Some(decoded) = state.decoded_page(index) {
+        let mut marks = PageMarks::new(index, state.page_marks(index));
+        for block in decoded.blocks.iter() {
+            column.add_child(build_block(block, page, &decoded, state, size, width, &mut marks));
+        }
+    }
+}
+
+/*
+This function builds the box displaying the footnote the user clicked on, over the page, with a
+button to close it.
+*/
+fn footnote_popover(state: &AppState) -> impl Widget<AppState> {
+    let size = state.font_size;
+
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::End)
+        .with_child(Label::new(state.footnote.clone())
+            .with_text_size(KeyOrValue::Concrete(size * 0.9))
+            .with_text_color(KeyOrValue::Key(theme::TEXT))
+            .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.text_width() - 20.))
+        .with_child(Button::new("Close").padding(5.0).on_click(AppState::click_close_footnote_button))
+        .padding(10.)
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5)
+        .padding((0., 0., 0., size))
+}
+
+/*
+This function builds the box listing the results of the last search in the library, over the page:
+every result shows its Ebook, its chapter and the text around the words found, and a click on it
+opens the Ebook at that position.
+*/
+fn library_results(state: &AppState) -> impl Widget<AppState> {
+    let size = state.font_size;
+    let mut c = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+
+    c.add_child(Label::new(format!("RESULTS IN THE LIBRARY ({})", state.library_hits.len()))
+        .with_text_size(KeyOrValue::Concrete(size))
+        .with_text_color(KeyOrValue::Key(theme::TEXT)));
+
+    for hit in state.library_hits.iter() {
+        let title = if hit.chapter.len() > 0 { format!("{} - {}", hit.title, hit.chapter) } else { hit.title.clone() };
+        let clicked = hit.clone();
+
+        c.add_child(ControllerHost::new(Flex::column()
+                                            .cross_axis_alignment(CrossAxisAlignment::Start)
+                                            .with_child(Label::new(title)
+                                                .with_font(FontDescriptor::default().with_size(size * 0.8).with_weight(FontWeight::BOLD))
+                                                .with_text_color(KeyOrValue::Key(theme::TEXT))
+                                                .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.text_width() - 20.))
+                                            .with_child(Label::new(hit.snippet.clone())
+                                                .with_text_size(KeyOrValue::Concrete(size * 0.8))
+                                                .with_text_color(KeyOrValue::Key(theme::MUTED))
+                                                .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.text_width() - 20.)), Click::new(move |ctx, _, _| {
+            ctx.submit_command(OPEN_LIBRARY_HIT.with(clicked.clone()));
+        })).padding((0., 5.)));
+    }
+
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::End)
+        .with_child(Scroll::new(c).vertical().fix_height(state.window_height / 3.))
+        .with_child(Button::new("Close").padding(5.0).on_click(AppState::click_close_library_button))
+        .padding(10.)
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5)
+        .padding((0., 0., 0., size))
+}
+
+/*
+This function builds the box listing the Ebooks opened recently, over the page: a click on one opens
+it at the last position read. The files that don't exist anymore are greyed out.
+*/
+fn recent_popover(state: &AppState) -> impl Widget<AppState> {
+    let mut c = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+
+    c.add_child(Label::new("RECENT EBOOKS").with_text_color(KeyOrValue::Key(theme::TEXT)));
+    if state.recent_books.len() == 0 {
+        c.add_child(Label::new("No Ebooks opened yet").with_text_color(KeyOrValue::Key(theme::MUTED)));
+    }
+
+    for book in state.recent_books.iter() {
+        let color = if book.available { theme::LINK } else { theme::MUTED };
+        let label = Label::new(format!("{} - {}", book.title, book.path))
+            .with_text_color(KeyOrValue::Key(color))
+            .with_line_break_mode(LineBreaking::WordWrap).fix_width(state.text_width() - 20.);
+
+        if book.available {
+            let path = book.path.clone();
+            c.add_child(ControllerHost::new(label, Click::new(move |ctx, _, _| {
+                ctx.submit_command(OPEN_PATH.with(path.clone()));
+            })).padding((0., 5.)));
+        } else {
+            c.add_child(label.padding((0., 5.)));
+        }
+    }
+
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::End)
+        .with_child(c)
+        .with_child(Flex::row()
+            .with_child(Button::new("Clear").padding(5.0).on_click(AppState::click_clear_recent_button))
+            .with_child(Button::new("Close").padding(5.0).on_click(AppState::click_recent_button)))
+        .padding(10.)
+        .background(theme::PANEL)
+        .border(theme::BORDER, 0.5)
+        .padding((0., 0., 0., 10.))
+}
+
+/*
+This function builds the button opening again the last Ebook read, if its file still exists.
+*/
+fn resume_button(state: &AppState) -> Option<impl Widget<AppState>> {
+    let book = state.recent_books.front().filter(|book| book.available)?;
+
+    Some(Button::new(format!("Continue reading {}", book.title)).on_click(AppState::click_resume_button))
+}
+
+/*
+This function builds the library: the folders scanned, with a button removing each of them, and a
+grid of the Ebooks found matching the filter, in the chosen order. A click on an Ebook opens it.
+*/
+fn library_view(state: &AppState, mut c: Flex<AppState>) -> Box<dyn Widget<AppState>> {
+    c.set_cross_axis_alignment(CrossAxisAlignment::Start);
+
+    if let Some(resume) = resume_button(state) {
+        c.add_child(resume.padding((0., 0., 0., 10.)));
+    }
+
+    if state.library_folders.len() == 0 {
+        c.add_child(Label::new("\n\nThe library is empty.\nPress Add folder to choose the folders containing your Ebooks.")
+            .with_text_size(KeyOrValue::Concrete(20.)));
+    }
+
+    for folder in state.library_folders.iter() {
+        let removed = folder.clone();
+        c.add_child(Flex::row()
+            .with_child(Label::new(folder.clone()).with_text_color(KeyOrValue::Key(theme::MUTED)))
+            .with_child(Button::new("x").on_click(move |ctx, _, _| {
+                ctx.submit_command(REMOVE_LIBRARY_FOLDER.with(removed.clone()));
+            }).padding((5., 0.))));
+    }
+
+    let books = filter_books(&state.library_books, &state.library_filter, state.library_sort);
+    let status = if state.library_scanning {
+        String::from("Scanning the folders...")
+    } else {
+        format!("{} of {} Ebooks", books.len(), state.library_books.len())
+    };
+    c.add_child(Label::new(status).with_text_color(KeyOrValue::Key(theme::MUTED)).padding((0., 10.)));
+
+    match state.library_layout {
+        LibraryLayout::Grid => {
+            let per_row = ((state.window_size - 100.) / CARD_WIDTH).floor().max(1.) as usize;
+            for row in books.chunks(per_row) {
+                let mut r = Flex::row().cross_axis_alignment(CrossAxisAlignment::Start);
+                for book in row {
+                    r.add_child(library_card(book, state.current_image_filter()));
+                }
+                c.add_child(r.padding((0., 0., 0., 10.)));
+            }
+        }
+        LibraryLayout::List => {
+            for book in books.iter() {
+                c.add_child(library_line(book, state.current_image_filter(), state.window_size - 100.));
+            }
+        }
+    }
+
+    Padding::new((50.0, 10.), Scroll::new(c).vertical()).expand_width().boxed()
+}
+
+/*
+This function builds the card of an Ebook of the library: its cover (or an empty box when it has
+none), its title, author and language and the percentage read.
+*/
+fn library_card(book: &LibraryBook, filter: ImageFilter) -> impl Widget<AppState> {
+    let cover: Box<dyn Widget<AppState>> = match &book.cover {
+        Some(cover) => build_image(cover, 40., CARD_WIDTH - 20., filter),
+        None => SizedBox::empty()
+            .fix_size((CARD_WIDTH - 20.) * 2. / 3., COVER_HEIGHT as f64)
+            .background(theme::BORDER)
+            .center()
+            .boxed(),
+    };
+    let progress = match book.progress {
+        Some(progress) => format!("{:.0}% read", progress * 100.),
+        None => String::from("Not started"),
+    };
+    let details = if book.language.len() > 0 { format!("{}\n{}", book.author, book.language) } else { book.author.clone() };
+    let path = book.path.clone();
+
+    let card = Flex::column()
+        .with_child(cover)
+        .with_child(Label::new(book.title.clone())
+            .with_font(FontDescriptor::default().with_size(14.).with_weight(FontWeight::BOLD))
+            .with_text_color(KeyOrValue::Key(theme::TEXT))
+            .with_text_alignment(TextAlignment::Center)
+            .with_line_break_mode(LineBreaking::WordWrap).fix_width(CARD_WIDTH - 20.))
+        .with_child(Label::new(details)
+            .with_text_size(KeyOrValue::Concrete(12.))
+            .with_text_color(KeyOrValue::Key(theme::MUTED))
+            .with_text_alignment(TextAlignment::Center)
+            .with_line_break_mode(LineBreaking::WordWrap).fix_width(CARD_WIDTH - 20.))
+        .with_child(Label::new(progress)
+            .with_text_size(KeyOrValue::Concrete(12.))
+            .with_text_color(KeyOrValue::Key(theme::LINK)))
+        .fix_width(CARD_WIDTH)
+        .padding(5.);
+
+    ControllerHost::new(card, Click::new(move |ctx, _, _| {
+        ctx.submit_command(OPEN_PATH.with(path.clone()));
+    }))
+}
+
+/*
+This function builds the line of an Ebook of the library shown as a list: a small cover, its title,
+author and language and the percentage read.
+*/
+fn library_line(book: &LibraryBook, filter: ImageFilter, width: f64) -> impl Widget<AppState> {
+    let cover: Box<dyn Widget<AppState>> = match &book.cover {
+        Some(cover) => build_image(cover, 40. / 3., LIST_COVER_WIDTH, filter),
+        None => SizedBox::empty()
+            .fix_size(LIST_COVER_WIDTH * 2. / 3., COVER_HEIGHT as f64 / 3.)
+            .background(theme::BORDER)
+            .center()
+            .boxed(),
+    };
+    let progress = match book.progress {
+        Some(progress) => format!("{:.0}% read", progress * 100.),
+        None => String::from("Not started"),
+    };
+    let details = if book.language.len() > 0 { format!("{} - {}", book.author, book.language) } else { book.author.clone() };
+    let path = book.path.clone();
+    let text_width = (width - LIST_COVER_WIDTH - 20.).max(CARD_WIDTH);
+
+    let line = Flex::row()
+        .cross_axis_alignment(CrossAxisAlignment::Center)
+        .with_child(cover.fix_width(LIST_COVER_WIDTH))
+        .with_child(Flex::column()
+            .cross_axis_alignment(CrossAxisAlignment::Start)
+            .with_child(Label::new(book.title.clone())
+                .with_font(FontDescriptor::default().with_size(14.).with_weight(FontWeight::BOLD))
+                .with_text_color(KeyOrValue::Key(theme::TEXT))
+                .with_line_break_mode(LineBreaking::WordWrap).fix_width(text_width))
+            .with_child(Label::new(details)
+                .with_text_size(KeyOrValue::Concrete(12.))
+                .with_text_color(KeyOrValue::Key(theme::MUTED))
+                .with_line_break_mode(LineBreaking::WordWrap).fix_width(text_width))
+            .with_child(Label::new(progress)
+                .with_text_size(KeyOrValue::Concrete(12.))
+                .with_text_color(KeyOrValue::Key(theme::LINK)))
+            .padding((10., 0.)))
+        .padding(5.);
+
+    ControllerHost::new(line, Click::new(move |ctx, _, _| {
+        ctx.submit_command(OPEN_PATH.with(path.clone()));
+    }))
+}
+
+/*
+This function builds the widget displaying a block of a page. Paragraphs and headings are rich text
+keeping the formatting of the Ebook, with a size depending on the font size (headings are bigger),
+lists and block quotes are indented columns containing their own blocks, tables are rows of bordered
+cells and preformatted text uses a monospace font without wrapping the lines. The margins set by the
+stylesheet are expressed in em, so they follow the font size too; without them the paragraphs are
+followed by the space chosen in the typography settings.
+*/
+fn build_block(block: &Block, page: &Page, decoded: &DecodedPage, state: &AppState, size: f64, width: f64, marks: &mut PageMarks) -> Box<dyn Widget<AppState>> {
+    let style = &block.style;
+    let (default_top, default_bottom, default_left) = match &block.kind {
+        BlockKind::Heading(..) => (0.5, 0.5, 0.),
+        BlockKind::Quote(_) => (0., 0., 2.),
+        _ => (0., state.typography.paragraph_spacing, 0.),
+    };
+    let top = style.margin_top.unwrap_or(default_top).max(0.) * size;
+    let bottom = style.margin_bottom.unwrap_or(default_bottom).max(0.) * size;
+    let left = style.margin_left.unwrap_or(default_left).max(0.) * size;
+    let right = style.margin_right.unwrap_or(0.).max(0.) * size;
+    let width = (width - left - right).max(size);
+
+    let content: Box<dyn Widget<AppState>> = match &block.kind {
+        BlockKind::Paragraph(spans) => {
+            RichTextBlock::new(spans, size, style, &page.source, marks, &state.typography)
+                .fix_width(width)
+                .boxed()
+        }
+        BlockKind::Heading(level, spans) => {
+            RichTextBlock::new(spans, size * heading_scale(*level), style, &page.source, marks, &state.typography)
+                .fix_width(width)
+                .boxed()
+        }
+        BlockKind::List { ordered, start, items } => {
+            let mut list = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+
+            for (n, item) in items.iter().enumerate() {
+                let marker = if *ordered { format!("{}.", start + n) } else { String::from("•") };
+                let mut content = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+                for block in item {
+                    content.add_child(build_block(block, page, decoded, state, size, width - size * 2., marks));
+                }
+
+                list.add_child(Flex::row()
+                    .cross_axis_alignment(CrossAxisAlignment::Start)
+                    .with_child(Label::new(marker).with_text_size(KeyOrValue::Concrete(size)).fix_width(size * 2.))
+                    .with_child(content));
+            }
+            list.boxed()
+        }
+        BlockKind::Quote(blocks) => {
+            let mut quote = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+            for block in blocks {
+                quote.add_child(build_block(block, page, decoded, state, size, width, marks));
+            }
+            quote.boxed()
+        }
+        BlockKind::Image(src) => {
+            match decoded.images.get(src) {
+                Some(image) => build_image(image, size, width, state.current_image_filter()),
+                None => SizedBox::empty().boxed(),
+            }
+        }
+        BlockKind::Table(rows) => {
+            let columns = rows.iter().map(|row| row.len()).max().unwrap_or(1).max(1);
+            let cell_width = width / columns as f64 - 10.;
+            let mut table = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+
+            for row in rows {
+                let mut r = Flex::row().cross_axis_alignment(CrossAxisAlignment::Start);
+                for cell in row {
+                    r.add_child(RichTextBlock::new(cell, size, &Style::default(), &page.source, marks, &state.typography)
+                        .fix_width(cell_width)
+                        .padding(4.)
+                        .border(theme::BORDER, 0.5));
+                }
+                table.add_child(r);
+            }
+            table.boxed()
+        }
+        BlockKind::Preformatted(text) => {
+            marks.chars += text.chars().filter(|c| !c.is_whitespace()).count();
+            Label::new(text.clone())
+                .with_font(FontDescriptor::new(FontFamily::MONOSPACE).with_size(size))
+                .with_line_break_mode(LineBreaking::Clip).fix_width(width)
+                .boxed()
+        }
+    };
+
+    Padding::new((left, top, right, bottom), content).boxed()
+}
+
+/*
+This function builds the widget displaying an image of the Ebook. The size of the image follows the
+font size, but it never exceeds the width of the page. The filter dims or inverts the image when a
+dark theme is used.
+*/
+fn build_image(image: &ImageOfPage, size: f64, width: f64, filter: ImageFilter) -> Box<dyn Widget<AppState>> {
+    let mut pixels: Vec<u8> = image.image.iter().cloned().collect();
+    let channels = pixels.len() / (image.width * image.height).max(1);
+    let format = match channels {
+        1 => ImageFormat::Grayscale,
+        3 => ImageFormat::Rgb,
+        4 => ImageFormat::RgbaSeparate,
+        _ => {
+            println!("Unable to process the image");
+            return SizedBox::empty().boxed();
+        }
+    };
+    filter.apply(&mut pixels, channels);
+    let image_buf = ImageBuf::from_raw(pixels, format, image.width, image.height);
+
+    let mut scale = size / 40.;
+    if image.width as f64 * scale > width {
+        scale = width / image.width as f64;
+    }
+
+    let img = Image::new(image_buf).fill_mode(FillStrat::Fill);
+    let sized = SizedBox::new(img).fix_size(image.width as f64 * scale, image.height as f64 * scale);
+
+    sized.border(theme::BORDER, 2.0).center().boxed()
+}